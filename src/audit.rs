@@ -0,0 +1,164 @@
+use std::{io::ErrorKind, net::SocketAddr};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::server_settings::AuditSettings;
+
+/// An append-only JSON lines log of every request, rotated by size.
+pub struct AuditLog {
+    path: Utf8PathBuf,
+    max_size: Option<u64>,
+    max_files: usize,
+    file: Mutex<AuditFile>,
+}
+
+struct AuditFile {
+    file: fs::File,
+    size: u64,
+}
+
+/// A request that has started and is waiting for its outcome.
+pub struct AuditEntry {
+    peer: SocketAddr,
+    user: Option<String>,
+    operation: &'static str,
+    path: Option<String>,
+    started: Instant,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    peer: String,
+    user: Option<&'a str>,
+    operation: &'a str,
+    path: Option<&'a str>,
+    bytes: Option<u64>,
+    duration_ms: u64,
+    result: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        peer: SocketAddr,
+        user: Option<&str>,
+        operation: &'static str,
+        path: Option<&str>,
+    ) -> Self {
+        Self {
+            peer,
+            user: user.map(Into::into),
+            operation,
+            path: path.map(Into::into),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl AuditLog {
+    pub async fn open(path: &Utf8Path, settings: &AuditSettings) -> Result<Self> {
+        let file = open_file(path).await?;
+        let size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+
+        Ok(Self {
+            path: path.to_owned(),
+            max_size: settings.max_size.map(|size| size.0),
+            max_files: settings.max_files,
+            file: Mutex::new(AuditFile { file, size }),
+        })
+    }
+
+    /// Appends the outcome of `entry`. Failures are logged, never returned, so
+    /// a broken audit log doesn't fail requests.
+    pub async fn record(&self, entry: AuditEntry, bytes: Option<u64>, error: Option<&str>) {
+        let record = AuditRecord {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            peer: entry.peer.to_string(),
+            user: entry.user.as_deref(),
+            operation: entry.operation,
+            path: entry.path.as_deref(),
+            bytes,
+            duration_ms: entry.started.elapsed().as_millis() as u64,
+            result: if error.is_some() { "error" } else { "ok" },
+            error,
+        };
+
+        if let Err(e) = self.append(&record).await {
+            log::error!("Failed to write audit log `{}`: {:#}", self.path, e);
+        }
+    }
+
+    async fn append(&self, record: &AuditRecord<'_>) -> Result<()> {
+        let mut line = serde_json::to_vec(record).context("Failed to serialize audit record")?;
+        line.push(b'\n');
+
+        let mut current = self.file.lock().await;
+        if let Some(max_size) = self.max_size
+            && current.size > 0
+            && current.size + line.len() as u64 > max_size
+        {
+            self.rotate().await?;
+            *current = AuditFile {
+                file: open_file(&self.path).await?,
+                size: 0,
+            };
+        }
+
+        current.file.write_all(&line).await?;
+        current.file.flush().await?;
+        current.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `audit.log` to `audit.log.1`, `audit.log.1` to `audit.log.2` and
+    /// so on, dropping the oldest file beyond `max_files`.
+    async fn rotate(&self) -> Result<()> {
+        let rotated = |index: usize| Utf8PathBuf::from(format!("{}.{}", self.path, index));
+
+        match fs::remove_file(rotated(self.max_files)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Failed to remove oldest audit log"),
+        }
+
+        for index in (1..self.max_files).rev() {
+            match fs::rename(rotated(index), rotated(index + 1)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context("Failed to rotate audit log"),
+            }
+        }
+
+        fs::rename(&self.path, rotated(1))
+            .await
+            .context("Failed to rotate audit log")
+    }
+}
+
+async fn open_file(path: &Utf8Path) -> Result<fs::File> {
+    if let Some(parent) = path.parent()
+        && !parent.as_str().is_empty()
+    {
+        fs::create_dir_all(parent)
+            .await
+            .context(format!("Failed to create `{}`", parent))?;
+    }
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .context(format!("Failed to open audit log `{}`", path))
+}
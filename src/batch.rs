@@ -0,0 +1,403 @@
+//! Runs the uploads, downloads and removals listed in a manifest, one per
+//! line:
+//!
+//! ```text
+//! # Nightly backup
+//! u build/app.tar releases/app.tar -f
+//! d reports/q3.pdf
+//! rm -r scratch
+//! ```
+
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use humansize::{BINARY, format_size};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use lud::{Client, settings::Server};
+use tabwriter::TabWriter;
+use tokio::{fs, sync::Semaphore, task::JoinSet};
+
+use crate::commands;
+
+const JOB_STYLE: &str =
+    "{prefix:.bold} [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})";
+const OVERALL_STYLE: &str =
+    "{spinner:.green} [{elapsed_precise}] [{wide_bar:.green}] {pos}/{len} jobs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Upload,
+    Download,
+    Remove,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Upload => "upload",
+            Operation::Download => "download",
+            Operation::Remove => "remove",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Job {
+    operation: Operation,
+    /// The local file for uploads, the remote path otherwise.
+    source: Utf8PathBuf,
+    /// Where to upload or download to, defaulting to the source's file name.
+    destination: Option<Utf8PathBuf>,
+    force: bool,
+    recursive: bool,
+}
+
+enum Outcome {
+    Done(Option<u64>),
+    Failed(anyhow::Error),
+    /// Not started, because an earlier job failed or the batch was aborted.
+    Skipped,
+}
+
+pub async fn batch(
+    manifest: &Path,
+    concurrency: usize,
+    continue_on_error: bool,
+    server: &Server,
+) -> Result<()> {
+    let jobs = read_manifest(manifest).await?;
+    if jobs.is_empty() {
+        log::info!("Nothing to do, the manifest lists no jobs");
+        return Ok(());
+    }
+
+    let abort = commands::abort_on_ctrl_c();
+    let client = Client::connect(server).await?.with_abort(abort.clone());
+
+    let progress = MultiProgress::new();
+    let overall = progress.add(ProgressBar::new(jobs.len() as u64));
+    overall.set_style(
+        ProgressStyle::with_template(OVERALL_STYLE)
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut tasks = JoinSet::new();
+    for (index, job) in jobs.iter().cloned().enumerate() {
+        let permit = semaphore.clone().acquire_owned().await?;
+        if stop.load(Ordering::Acquire) || abort.is_cancelled() {
+            break;
+        }
+
+        let client = client.clone();
+        let progress = progress.clone();
+        let overall = overall.clone();
+        let stop = stop.clone();
+        tasks.spawn(async move {
+            let bar = progress.insert_before(&overall, ProgressBar::new(0));
+            bar.set_style(
+                ProgressStyle::with_template(JOB_STYLE)
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            bar.set_prefix(job.source.to_string());
+
+            let result = run_job(&client, &job, &bar).await;
+            bar.finish_and_clear();
+            progress.remove(&bar);
+            drop(permit);
+
+            if let Err(e) = &result {
+                let _ = progress.println(format!(
+                    "Failed to {} `{}`: {:#}",
+                    job.operation.name(),
+                    job.source,
+                    e
+                ));
+                if !continue_on_error {
+                    stop.store(true, Ordering::Release);
+                }
+            }
+            overall.inc(1);
+            (index, result)
+        });
+    }
+
+    let mut outcomes: Vec<Outcome> = jobs.iter().map(|_| Outcome::Skipped).collect();
+    while let Some(joined) = tasks.join_next().await {
+        let (index, result) = joined.context("Batch job panicked")?;
+        outcomes[index] = match result {
+            Ok(size) => Outcome::Done(size),
+            Err(e) => Outcome::Failed(e),
+        };
+    }
+    overall.finish_and_clear();
+
+    print_summary(&jobs, &outcomes);
+
+    if abort.is_cancelled() {
+        return Err(lud::Error::Aborted.into());
+    }
+    let failed = outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome, Outcome::Failed(_)))
+        .count();
+    let skipped = outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome, Outcome::Skipped))
+        .count();
+    match (failed, skipped) {
+        (0, _) => {
+            log::info!("Completed {} jobs", jobs.len());
+            Ok(())
+        }
+        (_, 0) => Err(anyhow!("{} of {} jobs failed", failed, jobs.len())),
+        _ => Err(anyhow!(
+            "{} of {} jobs failed, skipped the remaining {} (use --continue-on-error to run them)",
+            failed,
+            jobs.len(),
+            skipped
+        )),
+    }
+}
+
+/// Runs one job, returning the bytes transferred.
+async fn run_job(client: &Client, job: &Job, bar: &ProgressBar) -> Result<Option<u64>> {
+    match job.operation {
+        Operation::Upload => {
+            let remote_path = match &job.destination {
+                Some(path) => path.clone(),
+                None => file_name(&job.source)?,
+            };
+            let size = client
+                .upload_file(&job.source, &remote_path, job.force, |sent, total| {
+                    bar.set_length(total);
+                    bar.set_position(sent);
+                })
+                .await?;
+            Ok(Some(size))
+        }
+        Operation::Download => {
+            let local_path = match &job.destination {
+                Some(path) => path.clone(),
+                None => file_name(&job.source)?,
+            };
+            if !job.force && fs::try_exists(&local_path).await.unwrap_or(false) {
+                return Err(commands::already_exists(&local_path));
+            }
+
+            let download = client.download(&job.source).await?;
+            bar.set_length(download.size);
+            let size = download
+                .save(&local_path, |received, _| bar.set_position(received))
+                .await?;
+            Ok(Some(size))
+        }
+        Operation::Remove => {
+            client.remove(&job.source, job.force, job.recursive).await?;
+            Ok(None)
+        }
+    }
+}
+
+fn file_name(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    path.file_name()
+        .map(Into::into)
+        .ok_or_else(|| anyhow!("`{}` has no file name, give a destination", path))
+}
+
+/// Reads the manifest at `path`, or standard input for `-`, checking every
+/// line before anything runs.
+async fn read_manifest(path: &Path) -> Result<Vec<Job>> {
+    let text = if path == Path::new("-") {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .context("Failed to read manifest from standard input")?;
+        text
+    } else {
+        fs::read_to_string(path)
+            .await
+            .context(format!("Failed to read manifest `{}`", path.display()))?
+    };
+
+    let mut jobs = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let job = split_words(line)
+            .and_then(|words| parse_job(&words))
+            .context(format!("Invalid manifest line {}", index + 1))?;
+        jobs.extend(job);
+    }
+    Ok(jobs)
+}
+
+/// Parses the words of one manifest line, which may be blank.
+fn parse_job(words: &[String]) -> Result<Option<Job>> {
+    let Some((operation, arguments)) = words.split_first() else {
+        return Ok(None);
+    };
+    let operation = match operation.as_str() {
+        "u" | "upload" => Operation::Upload,
+        "d" | "download" => Operation::Download,
+        "rm" | "remove" => Operation::Remove,
+        other => bail!("Unknown operation `{}` (expected u, d or rm)", other),
+    };
+
+    let mut force = false;
+    let mut recursive = false;
+    let mut paths = Vec::new();
+    for argument in arguments {
+        match argument.as_str() {
+            "-f" | "--force" => force = true,
+            "-r" | "--recursive" if operation == Operation::Remove => recursive = true,
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                bail!("Unknown flag `{}` for {}", flag, operation.name())
+            }
+            path => paths.push(Utf8PathBuf::from(path)),
+        }
+    }
+
+    let max_paths = if operation == Operation::Remove { 1 } else { 2 };
+    if paths.is_empty() {
+        bail!("Missing path to {}", operation.name());
+    }
+    if paths.len() > max_paths {
+        bail!("Too many paths for {}", operation.name());
+    }
+
+    let mut paths = paths.into_iter();
+    Ok(Some(Job {
+        operation,
+        source: paths.next().unwrap(),
+        destination: paths.next(),
+        force,
+        recursive,
+    }))
+}
+
+/// Splits a line into words on whitespace, keeping quoted words together
+/// and dropping everything after a `#`.
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_default().push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_default();
+            }
+            (None, '#') if word.is_none() => break,
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("Unterminated quote");
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+fn print_summary(jobs: &[Job], outcomes: &[Outcome]) {
+    let mut tw = TabWriter::new(io::stdout()).padding(1).minwidth(16);
+    let is_tty = atty::is(atty::Stream::Stdout);
+
+    if is_tty {
+        writeln!(tw, "\x1b[1mOperation\tPath\tResult\x1b[0m").unwrap();
+    } else {
+        writeln!(tw, "Operation\tPath\tResult").unwrap();
+    }
+
+    for (job, outcome) in jobs.iter().zip(outcomes) {
+        let path = match &job.destination {
+            Some(destination) => format!("{} -> {}", job.source, destination),
+            None => job.source.to_string(),
+        };
+        let result = match outcome {
+            Outcome::Done(Some(size)) => format!("ok ({})", format_size(*size, BINARY)),
+            Outcome::Done(None) => "ok".to_string(),
+            Outcome::Failed(e) => format!("failed: {:#}", e),
+            Outcome::Skipped => "skipped".to_string(),
+        };
+        writeln!(tw, "{}\t{}\t{}", job.operation.name(), path, result).unwrap();
+    }
+
+    tw.flush().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Option<Job>> {
+        parse_job(&split_words(line)?)
+    }
+
+    fn error(line: &str) -> String {
+        parse(line).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn splits_quoted_words_and_drops_comments() {
+        assert_eq!(
+            split_words(r#"u "my file.txt" 'to dir/it''s' # copy it"#).unwrap(),
+            ["u", "my file.txt", "to dir/its"]
+        );
+        assert_eq!(split_words("d a#b").unwrap(), ["d", "a#b"]);
+        assert_eq!(split_words(r#"rm "" "#).unwrap(), ["rm", ""]);
+        assert!(split_words("  # only a comment").unwrap().is_empty());
+        assert_eq!(
+            split_words(r#"u "unterminated"#).unwrap_err().to_string(),
+            "Unterminated quote"
+        );
+    }
+
+    #[test]
+    fn parses_jobs() {
+        let job = parse(r#"upload -f "my file.txt" backups/ # nightly"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(job.operation, Operation::Upload);
+        assert_eq!(job.source, "my file.txt");
+        assert_eq!(job.destination.as_deref(), Some(Utf8Path::new("backups/")));
+        assert!(job.force && !job.recursive);
+
+        let job = parse("rm -r -f old").unwrap().unwrap();
+        assert_eq!(job.operation, Operation::Remove);
+        assert_eq!(job.destination, None);
+        assert!(job.force && job.recursive);
+
+        assert!(parse("   ").unwrap().is_none());
+        assert!(parse("# comment").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_jobs() {
+        assert_eq!(
+            error("cp a b"),
+            "Unknown operation `cp` (expected u, d or rm)"
+        );
+        assert_eq!(
+            error("d --resume a"),
+            "Unknown flag `--resume` for download"
+        );
+        assert_eq!(error("u -r dir"), "Unknown flag `-r` for upload");
+        assert_eq!(error("d -f"), "Missing path to download");
+        assert_eq!(error("d a b c"), "Too many paths for download");
+        assert_eq!(error("rm a b"), "Too many paths for remove");
+    }
+}
@@ -0,0 +1,919 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow};
+use camino::{Utf8Path, Utf8PathBuf};
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEvent},
+    execute,
+    terminal::{
+        Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode,
+        enable_raw_mode,
+    },
+};
+use humansize::{BINARY, format_size};
+use tokio::{runtime::Handle, sync::mpsc};
+use tui::{
+    Frame, Terminal,
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Span, Spans},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+};
+use walkdir::WalkDir;
+
+use lud::{Client, server::File, settings::Server};
+
+const HELP: &str = "Tab: Switch  Space: Mark  Enter: Open  Bksp: Up  u: Upload  d: Download  x: Delete  /: Filter  f: Overwrite  c: Clear  r: Refresh  q: Quit";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Local,
+    Remote,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+enum Mode {
+    Normal,
+    Filter,
+    Confirm(Vec<Utf8PathBuf>),
+}
+
+#[derive(Clone)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+}
+
+struct Pane {
+    side: Side,
+    cwd: Utf8PathBuf,
+    entries: Vec<Entry>,
+    marked: HashSet<String>,
+    filter: String,
+    state: ListState,
+}
+
+impl Pane {
+    fn new(side: Side, cwd: Utf8PathBuf) -> Self {
+        Self {
+            side,
+            cwd,
+            entries: Vec::new(),
+            marked: HashSet::new(),
+            filter: String::new(),
+            state: ListState::default(),
+        }
+    }
+
+    fn visible(&self) -> Vec<&Entry> {
+        let filter = self.filter.to_lowercase();
+        self.entries
+            .iter()
+            .filter(|entry| filter.is_empty() || entry.name.to_lowercase().contains(&filter))
+            .collect()
+    }
+
+    fn highlighted(&self) -> Option<Entry> {
+        let visible = self.visible();
+        self.state
+            .selected()
+            .and_then(|i| visible.get(i).map(|entry| (*entry).clone()))
+    }
+
+    fn set_entries(&mut self, entries: Vec<Entry>) {
+        self.entries = entries;
+        self.marked
+            .retain(|name| self.entries.iter().any(|entry| &entry.name == name));
+        self.clamp_selection();
+    }
+
+    fn clamp_selection(&mut self) {
+        let len = self.visible().len();
+        let selected = match self.state.selected() {
+            _ if len == 0 => None,
+            Some(i) if i >= len => Some(len - 1),
+            Some(i) => Some(i),
+            None => Some(0),
+        };
+        self.state.select(selected);
+    }
+
+    fn next(&mut self) {
+        let len = self.visible().len();
+        if len == 0 {
+            return;
+        }
+        let i = match self.state.selected() {
+            Some(i) if i + 1 < len => i + 1,
+            _ => 0,
+        };
+        self.state.select(Some(i));
+    }
+
+    fn previous(&mut self) {
+        let len = self.visible().len();
+        if len == 0 {
+            return;
+        }
+        let i = match self.state.selected() {
+            Some(0) | None => len - 1,
+            Some(i) => i - 1,
+        };
+        self.state.select(Some(i));
+    }
+
+    fn toggle_mark(&mut self) {
+        if let Some(entry) = self.highlighted()
+            && !self.marked.remove(&entry.name)
+        {
+            self.marked.insert(entry.name);
+        }
+        self.next();
+    }
+
+    /// Marked entries, or the highlighted one when nothing is marked.
+    fn targets(&self) -> Vec<Entry> {
+        if self.marked.is_empty() {
+            return self.highlighted().into_iter().collect();
+        }
+        self.entries
+            .iter()
+            .filter(|entry| self.marked.contains(&entry.name))
+            .cloned()
+            .collect()
+    }
+
+    fn enter(&mut self, name: &str) {
+        self.cwd.push(name);
+        self.filter.clear();
+        self.marked.clear();
+        self.state.select(Some(0));
+    }
+
+    /// Moves to the parent directory, returning the name of the one we left.
+    fn leave(&mut self) -> Option<String> {
+        let name = self.cwd.file_name().map(str::to_string)?;
+        self.cwd.pop();
+        self.filter.clear();
+        self.marked.clear();
+        self.state.select(Some(0));
+        Some(name)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum TransferKind {
+    Upload,
+    Download,
+}
+
+enum TransferStatus {
+    Queued,
+    Active,
+    Done,
+    Failed(String),
+}
+
+impl TransferStatus {
+    fn is_pending(&self) -> bool {
+        matches!(self, TransferStatus::Queued | TransferStatus::Active)
+    }
+}
+
+/// A queued transfer. Workers find it by `id`, which stays the same when
+/// finished transfers before it are cleared.
+struct Transfer {
+    id: usize,
+    kind: TransferKind,
+    source: Utf8PathBuf,
+    target: Utf8PathBuf,
+    force: bool,
+    transferred: u64,
+    total: u64,
+    status: TransferStatus,
+}
+
+type Queue = Arc<Mutex<Vec<Transfer>>>;
+
+struct App {
+    client: Client,
+    handle: Handle,
+    local: Pane,
+    remote: Pane,
+    active: Side,
+    mode: Mode,
+    force: bool,
+    message: Option<String>,
+    queue: Queue,
+    jobs: mpsc::UnboundedSender<usize>,
+    next_id: usize,
+    finished: Arc<AtomicUsize>,
+    seen_finished: usize,
+}
+
+impl App {
+    fn pane(&self) -> &Pane {
+        match self.active {
+            Side::Local => &self.local,
+            Side::Remote => &self.remote,
+        }
+    }
+
+    fn pane_mut(&mut self) -> &mut Pane {
+        match self.active {
+            Side::Local => &mut self.local,
+            Side::Remote => &mut self.remote,
+        }
+    }
+
+    fn refresh(&mut self, side: Side) {
+        let result = match side {
+            Side::Local => {
+                read_local(&self.local.cwd).map(|entries| self.local.set_entries(entries))
+            }
+            Side::Remote => self
+                .handle
+                .block_on(read_remote(&self.client, &self.remote.cwd))
+                .map(|entries| self.remote.set_entries(entries)),
+        };
+        if let Err(e) = result {
+            self.message = Some(format!("{:#}", e));
+        }
+    }
+
+    fn open(&mut self) {
+        let Some(entry) = self.pane().highlighted() else {
+            return;
+        };
+        if entry.is_dir {
+            self.pane_mut().enter(&entry.name);
+            self.refresh(self.active);
+        }
+    }
+
+    fn up(&mut self) {
+        let Some(previous) = self.pane_mut().leave() else {
+            return;
+        };
+        self.refresh(self.active);
+
+        // Keep the directory we came from highlighted.
+        let pane = self.pane_mut();
+        if let Some(i) = pane
+            .visible()
+            .iter()
+            .position(|entry| entry.name == previous)
+        {
+            pane.state.select(Some(i));
+        }
+    }
+
+    fn enqueue(&mut self, kind: TransferKind, source: Utf8PathBuf, target: Utf8PathBuf) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.lock().unwrap().push(Transfer {
+            id,
+            kind,
+            source,
+            target,
+            force: self.force,
+            transferred: 0,
+            total: 0,
+            status: TransferStatus::Queued,
+        });
+        let _ = self.jobs.send(id);
+    }
+
+    fn upload(&mut self) {
+        if self.active != Side::Local {
+            self.message = Some("Select local files to upload".into());
+            return;
+        }
+
+        let mut count = 0;
+        for entry in self.local.targets() {
+            let source = self.local.cwd.join(&entry.name);
+            if !entry.is_dir {
+                self.enqueue(
+                    TransferKind::Upload,
+                    source,
+                    self.remote.cwd.join(&entry.name),
+                );
+                count += 1;
+                continue;
+            }
+
+            for file in WalkDir::new(&source).into_iter().filter_map(Result::ok) {
+                if file.file_type().is_dir() {
+                    continue;
+                }
+                let Some(path) = Utf8Path::from_path(file.path()) else {
+                    continue;
+                };
+                let Ok(relative) = path.strip_prefix(&self.local.cwd) else {
+                    continue;
+                };
+                let target = self.remote.cwd.join(relative);
+                self.enqueue(TransferKind::Upload, path.to_owned(), target);
+                count += 1;
+            }
+        }
+
+        self.local.marked.clear();
+        self.message = Some(format!("Queued {} upload(s)", count));
+    }
+
+    fn download(&mut self) {
+        if self.active != Side::Remote {
+            self.message = Some("Select remote files to download".into());
+            return;
+        }
+
+        let mut count = 0;
+        for entry in self.remote.targets() {
+            let source = self.remote.cwd.join(&entry.name);
+            if !entry.is_dir {
+                self.enqueue(
+                    TransferKind::Download,
+                    source,
+                    self.local.cwd.join(&entry.name),
+                );
+                count += 1;
+                continue;
+            }
+
+            let files = match self.handle.block_on(list_remote(&self.client, &source)) {
+                Ok(files) => files,
+                Err(e) => {
+                    self.message = Some(format!("{:#}", e));
+                    return;
+                }
+            };
+            for file in files {
+                let path = Utf8PathBuf::from(file.path);
+                let Ok(relative) = path.strip_prefix(&self.remote.cwd) else {
+                    continue;
+                };
+                let target = self.local.cwd.join(relative);
+                self.enqueue(TransferKind::Download, path, target);
+                count += 1;
+            }
+        }
+
+        self.remote.marked.clear();
+        self.message = Some(format!("Queued {} download(s)", count));
+    }
+
+    fn confirm_delete(&mut self) {
+        let pane = self.pane();
+        let targets: Vec<Utf8PathBuf> = pane
+            .targets()
+            .iter()
+            .map(|entry| pane.cwd.join(&entry.name))
+            .collect();
+        if !targets.is_empty() {
+            self.mode = Mode::Confirm(targets);
+        }
+    }
+
+    fn delete(&mut self, targets: Vec<Utf8PathBuf>) {
+        let side = self.active;
+        let result = match side {
+            Side::Local => targets.iter().try_for_each(|path| {
+                let result = if path.is_dir() {
+                    fs::remove_dir_all(path)
+                } else {
+                    fs::remove_file(path)
+                };
+                result.context(format!("Failed to delete `{}`", path))
+            }),
+            Side::Remote => self.handle.block_on(async {
+                for path in &targets {
+                    self.client
+                        .remove(path, false, true)
+                        .await
+                        .context(format!("Failed to delete `{}`", path))?;
+                }
+                Ok(())
+            }),
+        };
+
+        self.message = Some(match result {
+            Ok(()) => format!("Deleted {} item(s)", targets.len()),
+            Err(e) => format!("{:#}", e),
+        });
+        self.pane_mut().marked.clear();
+        self.refresh(side);
+    }
+
+    /// Drops finished transfers from the queue, keeping pending ones.
+    fn clear_finished(&mut self) {
+        let mut queue = self.queue.lock().unwrap();
+        let before = queue.len();
+        queue.retain(|t| t.status.is_pending());
+        self.message = Some(format!("Cleared {} transfer(s)", before - queue.len()));
+    }
+
+    /// Reloads both panes once the queue has drained after transfers
+    /// completed, rather than after each one.
+    fn poll_transfers(&mut self) {
+        let finished = self.finished.load(Ordering::Acquire);
+        if finished == self.seen_finished {
+            return;
+        }
+        let pending = self
+            .queue
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|t| t.status.is_pending());
+        if !pending {
+            self.seen_finished = finished;
+            self.refresh(Side::Local);
+            self.refresh(Side::Remote);
+        }
+    }
+
+    /// Returns `false` once the user asked to quit.
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        match self.mode.clone() {
+            Mode::Filter => match key.code {
+                KeyCode::Esc => {
+                    let pane = self.pane_mut();
+                    pane.filter.clear();
+                    pane.clamp_selection();
+                    self.mode = Mode::Normal;
+                }
+                KeyCode::Enter => self.mode = Mode::Normal,
+                KeyCode::Backspace => {
+                    let pane = self.pane_mut();
+                    pane.filter.pop();
+                    pane.state.select(Some(0));
+                    pane.clamp_selection();
+                }
+                KeyCode::Char(c) => {
+                    let pane = self.pane_mut();
+                    pane.filter.push(c);
+                    pane.state.select(Some(0));
+                    pane.clamp_selection();
+                }
+                _ => {}
+            },
+
+            Mode::Confirm(targets) => {
+                self.mode = Mode::Normal;
+                if let KeyCode::Char('y') | KeyCode::Char('Y') = key.code {
+                    self.delete(targets);
+                } else {
+                    self.message = Some("Deletion cancelled".into());
+                }
+            }
+
+            Mode::Normal => {
+                self.message = None;
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return false,
+                    KeyCode::Tab | KeyCode::BackTab => {
+                        self.active = match self.active {
+                            Side::Local => Side::Remote,
+                            Side::Remote => Side::Local,
+                        };
+                    }
+                    KeyCode::Up | KeyCode::Char('k') => self.pane_mut().previous(),
+                    KeyCode::Down | KeyCode::Char('j') => self.pane_mut().next(),
+                    KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => self.open(),
+                    KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => self.up(),
+                    KeyCode::Char(' ') => self.pane_mut().toggle_mark(),
+                    KeyCode::Char('u') => self.upload(),
+                    KeyCode::Char('d') => self.download(),
+                    KeyCode::Char('x') | KeyCode::Delete => self.confirm_delete(),
+                    KeyCode::Char('/') => self.mode = Mode::Filter,
+                    KeyCode::Char('f') => {
+                        self.force = !self.force;
+                        self.message = Some(format!(
+                            "Overwrite existing files: {}",
+                            if self.force { "on" } else { "off" }
+                        ));
+                    }
+                    KeyCode::Char('c') => self.clear_finished(),
+                    KeyCode::Char('r') => {
+                        self.refresh(Side::Local);
+                        self.refresh(Side::Remote);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        true
+    }
+}
+
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), LeaveAlternateScreen, crossterm::cursor::Show);
+    }
+}
+
+pub async fn browse(path: Option<Utf8PathBuf>, server: Server) -> Result<()> {
+    let handle = Handle::current();
+    tokio::task::spawn_blocking(move || run(handle, path, server))
+        .await
+        .context("Browser task panicked")?
+}
+
+fn run(handle: Handle, path: Option<Utf8PathBuf>, server: Server) -> Result<()> {
+    let local_cwd = Utf8PathBuf::try_from(std::env::current_dir()?)
+        .context("Current directory is not valid UTF-8")?;
+    let remote_cwd = path.unwrap_or_default();
+
+    // Fail before touching the terminal if the server is unreachable. Listings
+    // and the transfer queue share this client, so browsing continues while a
+    // transfer runs.
+    let client = handle.block_on(Client::connect(&server))?;
+    let remote_entries = handle.block_on(read_remote(&client, &remote_cwd))?;
+
+    let queue: Queue = Arc::default();
+    let finished = Arc::new(AtomicUsize::new(0));
+    let (jobs, rx) = mpsc::unbounded_channel();
+    handle.spawn(run_queue(
+        client.clone(),
+        queue.clone(),
+        finished.clone(),
+        rx,
+    ));
+
+    let mut app = App {
+        client,
+        handle,
+        local: Pane::new(Side::Local, local_cwd),
+        remote: Pane::new(Side::Remote, remote_cwd),
+        active: Side::Local,
+        mode: Mode::Normal,
+        force: false,
+        message: None,
+        queue,
+        jobs,
+        next_id: 0,
+        finished,
+        seen_finished: 0,
+    };
+    app.refresh(Side::Local);
+    app.remote.set_entries(remote_entries);
+
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let _guard = TerminalGuard;
+    enable_raw_mode()?;
+    execute!(stdout, Clear(ClearType::All))?;
+
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+
+    loop {
+        app.poll_transfers();
+        terminal.draw(|f| draw(f, &mut app))?;
+
+        if event::poll(Duration::from_millis(100))?
+            && let Event::Key(key) = event::read()?
+            && !app.handle_key(key)
+        {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(5),
+            Constraint::Length(8),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .split(f.size());
+
+    let panes = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(rows[0]);
+
+    let active = app.active;
+    draw_pane(f, &mut app.local, panes[0], active == Side::Local);
+    draw_pane(f, &mut app.remote, panes[1], active == Side::Remote);
+    draw_queue(f, &app.queue, rows[1]);
+
+    let status = match &app.mode {
+        Mode::Filter => format!("/{}", app.pane().filter),
+        Mode::Confirm(targets) => format!("Delete {} item(s)? (y/n)", targets.len()),
+        Mode::Normal => match &app.message {
+            Some(message) => message.clone(),
+            None if !app.pane().filter.is_empty() => format!("Filter: {}", app.pane().filter),
+            None => String::new(),
+        },
+    };
+    f.render_widget(Paragraph::new(status), rows[2]);
+    if app.mode == Mode::Filter {
+        f.set_cursor(rows[2].x + 1 + app.pane().filter.len() as u16, rows[2].y);
+    }
+
+    let overwrite = if app.force { "  [overwrite]" } else { "" };
+    f.render_widget(
+        Paragraph::new(Spans::from(vec![
+            Span::styled(HELP, Style::default().fg(Color::DarkGray)),
+            Span::styled(overwrite, Style::default().fg(Color::Yellow)),
+        ])),
+        rows[3],
+    );
+}
+
+fn draw_pane<B: Backend>(f: &mut Frame<B>, pane: &mut Pane, area: Rect, active: bool) {
+    let title = match pane.side {
+        Side::Local => format!(" Local: {} ", pane.cwd),
+        Side::Remote => format!(" Remote: /{} ", pane.cwd),
+    };
+    let border_style = if active {
+        Style::default().fg(Color::Cyan)
+    } else {
+        Style::default()
+    };
+
+    let name_width = (area.width as usize).saturating_sub(20);
+    let items: Vec<ListItem> = pane
+        .visible()
+        .into_iter()
+        .map(|entry| {
+            let marked = pane.marked.contains(&entry.name);
+            let mark = if marked { "* " } else { "  " };
+            let name = if entry.is_dir {
+                format!("{}/", entry.name)
+            } else {
+                entry.name.clone()
+            };
+            let size = if entry.is_dir {
+                String::new()
+            } else {
+                format_size(entry.size, BINARY)
+            };
+
+            let mut style = Style::default();
+            if entry.is_dir {
+                style = style.fg(Color::Blue).add_modifier(Modifier::BOLD);
+            }
+            if marked {
+                style = style.fg(Color::Yellow);
+            }
+
+            ListItem::new(Span::styled(
+                format!("{}{:<name_width$} {:>10}", mark, name, size),
+                style,
+            ))
+        })
+        .collect();
+
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(border_style)
+                .title(title),
+        )
+        .highlight_symbol(">> ")
+        .highlight_style(Style::default().bg(Color::DarkGray).fg(Color::White));
+
+    f.render_stateful_widget(list, area, &mut pane.state);
+}
+
+fn draw_queue<B: Backend>(f: &mut Frame<B>, queue: &Queue, area: Rect) {
+    let queue = queue.lock().unwrap();
+
+    let active = queue.iter().filter(|t| t.status.is_pending()).count();
+    let failed = queue
+        .iter()
+        .filter(|t| matches!(t.status, TransferStatus::Failed(_)))
+        .count();
+    let title = format!(
+        " Transfers ({} pending, {} failed, {} total) ",
+        active,
+        failed,
+        queue.len()
+    );
+
+    // Show the running transfer and its neighbours rather than the oldest ones.
+    let height = area.height.saturating_sub(2) as usize;
+    let focus = queue
+        .iter()
+        .position(|t| t.status.is_pending())
+        .unwrap_or(queue.len());
+    let skip = focus
+        .saturating_sub(height / 2)
+        .min(queue.len().saturating_sub(height));
+
+    let items: Vec<ListItem> = queue
+        .iter()
+        .skip(skip)
+        .take(height)
+        .map(|t| {
+            let arrow = match t.kind {
+                TransferKind::Upload => "↑",
+                TransferKind::Download => "↓",
+            };
+            let percent = (t.transferred * 100)
+                .checked_div(t.total)
+                .unwrap_or(0)
+                .min(100) as usize;
+            let filled = percent / 5;
+            let bar = format!("[{}{}]", "#".repeat(filled), "-".repeat(20 - filled));
+
+            let (status, style) = match &t.status {
+                TransferStatus::Queued => ("queued".to_string(), Style::default()),
+                TransferStatus::Active => (
+                    format!(
+                        "{}/{}",
+                        format_size(t.transferred, BINARY),
+                        format_size(t.total, BINARY)
+                    ),
+                    Style::default().fg(Color::Cyan),
+                ),
+                TransferStatus::Done => ("done".to_string(), Style::default().fg(Color::Green)),
+                TransferStatus::Failed(e) => {
+                    (format!("failed: {}", e), Style::default().fg(Color::Red))
+                }
+            };
+
+            ListItem::new(Span::styled(
+                format!(
+                    "{} {} {:>3}% {} -> {}  {}",
+                    arrow, bar, percent, t.source, t.target, status
+                ),
+                style,
+            ))
+        })
+        .collect();
+
+    f.render_widget(
+        List::new(items).block(Block::default().borders(Borders::ALL).title(title)),
+        area,
+    );
+}
+
+fn read_local(cwd: &Utf8Path) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    for entry in fs::read_dir(cwd).context(format!("Failed to read directory `{}`", cwd))? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        // Follow symlinks so linked directories can be entered.
+        let Ok(metadata) = fs::metadata(entry.path()) else {
+            continue;
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+        });
+    }
+
+    sort_entries(&mut entries);
+    Ok(entries)
+}
+
+async fn list_remote(client: &Client, path: &Utf8Path) -> Result<Vec<File>> {
+    let path = if path.as_str().is_empty() {
+        Utf8Path::new("./")
+    } else {
+        path
+    };
+    Ok(client.list(path).await?)
+}
+
+/// Lists the direct children of `cwd` on the server, empty directories
+/// included. Matching files alone first tells the directories apart.
+async fn read_remote(client: &Client, cwd: &Utf8Path) -> Result<Vec<Entry>> {
+    let pattern = match cwd.as_str() {
+        "" => "*".to_string(),
+        cwd => format!("{}/*", globset::escape(cwd)),
+    };
+    let files: HashSet<String> = client
+        .glob(&pattern, false)
+        .await?
+        .into_iter()
+        .map(|file| file.path)
+        .collect();
+
+    let mut entries: Vec<Entry> = client
+        .glob(&pattern, true)
+        .await?
+        .into_iter()
+        .filter_map(|file| {
+            let name = Utf8Path::new(&file.path).file_name()?.to_string();
+            Some(Entry {
+                name,
+                is_dir: !files.contains(&file.path),
+                size: file.size,
+            })
+        })
+        .collect();
+    sort_entries(&mut entries);
+    Ok(entries)
+}
+
+fn sort_entries(entries: &mut [Entry]) {
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+}
+
+async fn run_queue(
+    client: Client,
+    queue: Queue,
+    finished: Arc<AtomicUsize>,
+    mut jobs: mpsc::UnboundedReceiver<usize>,
+) {
+    while let Some(id) = jobs.recv().await {
+        let (kind, source, target, force) = {
+            let mut queue = queue.lock().unwrap();
+            let Some(transfer) = queue.iter_mut().find(|t| t.id == id) else {
+                continue;
+            };
+            transfer.status = TransferStatus::Active;
+            (
+                transfer.kind,
+                transfer.source.clone(),
+                transfer.target.clone(),
+                transfer.force,
+            )
+        };
+
+        let progress = |transferred, total| {
+            if let Some(transfer) = queue.lock().unwrap().iter_mut().find(|t| t.id == id) {
+                transfer.transferred = transferred;
+                transfer.total = total;
+            }
+        };
+
+        let result = match kind {
+            TransferKind::Upload => upload(&client, &source, &target, force, progress).await,
+            TransferKind::Download => download(&client, &source, &target, force, progress).await,
+        };
+
+        if let Some(transfer) = queue.lock().unwrap().iter_mut().find(|t| t.id == id) {
+            transfer.status = match result {
+                Ok(()) => TransferStatus::Done,
+                Err(e) => TransferStatus::Failed(format!("{:#}", e)),
+            };
+        }
+        finished.fetch_add(1, Ordering::Release);
+    }
+}
+
+async fn upload<F>(
+    client: &Client,
+    source: &Utf8Path,
+    target: &Utf8Path,
+    force: bool,
+    progress: F,
+) -> Result<()>
+where
+    F: FnMut(u64, u64),
+{
+    client.upload_file(source, target, force, progress).await?;
+    Ok(())
+}
+
+async fn download<F>(
+    client: &Client,
+    source: &Utf8Path,
+    target: &Utf8Path,
+    force: bool,
+    progress: F,
+) -> Result<()>
+where
+    F: FnMut(u64, u64),
+{
+    if !force && target.exists() {
+        return Err(anyhow!("File already exists"));
+    }
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .context("Failed to create directories")?;
+    }
+
+    client.download_file(source, target, progress).await?;
+    Ok(())
+}
@@ -1,11 +1,48 @@
+use std::{ops::RangeInclusive, path::PathBuf, time::Duration};
+
 use camino::Utf8PathBuf;
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand};
+
+use lud::{server::FileKind, utils};
 
 #[derive(Parser)]
 #[command(name = env!("CARGO_PKG_NAME"))]
 #[command(version = env!("CARGO_PKG_VERSION"))]
 #[command(about = env!("CARGO_PKG_DESCRIPTION"))]
 pub struct Cli {
+    #[clap(
+        long,
+        global = true,
+        env = "LUD_SERVER",
+        help = "Server name or address to use"
+    )]
+    pub server: Option<String>,
+
+    #[clap(
+        long,
+        global = true,
+        env = "LUD_RETRIES",
+        help = "Retry requests failing on a network error this many times"
+    )]
+    pub retries: Option<u32>,
+
+    #[clap(
+        long,
+        global = true,
+        value_parser = parse_duration,
+        env = "LUD_RETRY_DELAY",
+        help = "Delay before the first retry, doubled for each one after it (e.g. 1s)"
+    )]
+    pub retry_delay: Option<Duration>,
+
+    #[clap(
+        long,
+        short = 'c',
+        env = "LUD_CONFIG",
+        help = "Config file loaded on top of the system, user and project files"
+    )]
+    pub config: Option<PathBuf>,
+
     #[command(subcommand)]
     pub cmd: Command,
 }
@@ -14,26 +51,61 @@ pub struct Cli {
 pub enum Command {
     #[clap(visible_alias = "d", about = "Download a file")]
     Download {
-        #[clap(required = true, help = "Remote path to file")]
-        input: Utf8PathBuf,
+        #[clap(
+            required_unless_present = "token",
+            conflicts_with = "token",
+            help = "Remote path to file, or a glob such as 'logs/*.gz'"
+        )]
+        input: Option<Utf8PathBuf>,
 
-        #[clap(long, short = 'o', help = "Local output of downloaded file")]
+        #[clap(
+            long,
+            short = 'o',
+            help = "Local output of downloaded file, or directory for a glob"
+        )]
         output: Option<Utf8PathBuf>,
 
         #[clap(long, short = 'f', help = "Overwriting existing local file")]
         force: bool,
+
+        #[clap(
+            long,
+            value_parser = parse_rate,
+            help = "Limit the transfer rate in bytes per second (e.g. 10M)"
+        )]
+        limit_rate: Option<u64>,
+
+        #[clap(long, help = "Download the file shared with this token")]
+        token: Option<String>,
+
+        #[clap(long, help = "Keep what was downloaded if the transfer fails")]
+        keep_partial: bool,
     },
 
     #[clap(visible_alias = "u", about = "Upload a file")]
     Upload {
-        #[clap(required = true, help = "Local path to file")]
+        #[clap(
+            required = true,
+            help = "Local path to file, or a glob such as 'build/*.tar.gz'"
+        )]
         input: Utf8PathBuf,
 
-        #[clap(long, short = 'o', help = "Remote output of uploaded file")]
+        #[clap(
+            long,
+            short = 'o',
+            help = "Remote output of uploaded file, or directory for a glob"
+        )]
         output: Option<Utf8PathBuf>,
 
         #[clap(long, short = 'f', help = "Overwriting existing remote file")]
         force: bool,
+
+        #[clap(
+            long,
+            value_parser = parse_rate,
+            help = "Limit the transfer rate in bytes per second (e.g. 10M)"
+        )]
+        limit_rate: Option<u64>,
     },
 
     #[clap(visible_alias = "ls", about = "List files")]
@@ -42,28 +114,78 @@ pub enum Command {
         path: Option<Utf8PathBuf>,
     },
 
+    #[clap(about = "Search for files on the server")]
+    Find {
+        #[clap(help = "Remote directory to search [default: the storage root]")]
+        path: Option<Utf8PathBuf>,
+
+        #[clap(long, help = "Only names matching this glob (e.g. '*.log')")]
+        name: Option<String>,
+
+        #[clap(
+            long,
+            value_parser = parse_size_filter,
+            allow_hyphen_values = true,
+            help = "Only files over (+N), under (-N) or of exactly N bytes (e.g. +100M)"
+        )]
+        size: Vec<RangeInclusive<u64>>,
+
+        #[clap(
+            long,
+            value_parser = parse_newer,
+            help = "Only entries modified within this long (e.g. 2d)"
+        )]
+        newer: Option<Duration>,
+
+        #[clap(
+            long = "type",
+            value_parser = parse_file_kind,
+            help = "Only files (f) or directories (d)"
+        )]
+        kind: Option<FileKind>,
+    },
+
     #[clap(visible_alias = "ln", about = "Start a server")]
     Listen {
         #[clap(
             long,
             short = 'a',
-            help = "Listening address",
-            default_value = "127.0.0.1:4899"
+            help = "Listening address [default: 127.0.0.1:4899]"
         )]
-        addr: String,
+        addr: Option<String>,
 
         #[clap(
             long,
             short = 'o',
-            help = "Output path for uploaded files",
-            default_value = "./storage"
+            help = "Output path for uploaded files [default: ./storage]"
+        )]
+        output: Option<Utf8PathBuf>,
+
+        #[clap(long, short = 'c', help = "Server config file")]
+        config: Option<PathBuf>,
+
+        #[clap(long, help = "Serve Prometheus metrics on this address (host:port)")]
+        metrics: Option<String>,
+
+        #[clap(
+            long,
+            help = "Serve the storage root over HTTP on this address (host:port)"
+        )]
+        http: Option<String>,
+
+        #[clap(
+            long,
+            help = "Serve the storage root over WebDAV on this address (host:port)"
         )]
-        output: Utf8PathBuf,
+        webdav: Option<String>,
+
+        #[clap(long, help = "Let clients on the local network discover this server")]
+        announce: bool,
     },
 
     #[clap(visible_alias = "rm", about = "Delete a file or directory")]
     Remove {
-        #[clap(required = true, help = "Remote path to delete")]
+        #[clap(required = true, help = "Remote path or glob to delete")]
         path: Utf8PathBuf,
 
         #[clap(
@@ -79,8 +201,253 @@ pub enum Command {
             help = "Remove directories and their contents recursively"
         )]
         recursive: bool,
+
+        #[clap(
+            long,
+            short = 'n',
+            help = "List the paths that would be removed without removing them"
+        )]
+        dry_run: bool,
     },
 
     #[clap(visible_alias = "p", about = "Ping a server")]
     Ping,
+
+    #[clap(about = "Create a link others can download a file with")]
+    Share {
+        #[clap(required = true, help = "Remote path to file")]
+        path: Utf8PathBuf,
+
+        #[clap(
+            long,
+            value_parser = parse_duration,
+            help = "Invalidate the token after this long (e.g. 24h)"
+        )]
+        expires: Option<Duration>,
+
+        #[clap(long, help = "Invalidate the token after this many downloads")]
+        max_downloads: Option<u32>,
+    },
+
+    #[clap(about = "Send a file straight to another machine")]
+    Send {
+        #[clap(required = true, help = "Local path to file")]
+        file: Utf8PathBuf,
+
+        #[clap(long, env = "LUD_RELAY", help = "Relay used to pair with the receiver")]
+        relay: Option<String>,
+    },
+
+    #[clap(about = "Receive a file sent with `lud send`")]
+    Receive {
+        #[clap(required = true, help = "Code printed by the sender")]
+        code: String,
+
+        #[clap(long, short = 'o', help = "Local output of received file")]
+        output: Option<Utf8PathBuf>,
+
+        #[clap(long, short = 'f', help = "Overwriting existing local file")]
+        force: bool,
+
+        #[clap(long, env = "LUD_RELAY", help = "Relay used to pair with the sender")]
+        relay: Option<String>,
+    },
+
+    #[clap(about = "Start a relay pairing senders and receivers")]
+    Relay {
+        #[clap(long, short = 'a', help = "Listening address [default: 0.0.0.0:4900]")]
+        addr: Option<String>,
+    },
+
+    #[clap(about = "Find servers on the local network")]
+    Discover {
+        #[clap(
+            long,
+            value_parser = parse_duration,
+            default_value = "2s",
+            help = "How long to wait for answers"
+        )]
+        timeout: Duration,
+
+        #[clap(long, short = 's', help = "Add the servers found to the config file")]
+        save: bool,
+    },
+
+    #[clap(about = "Show free space, storage usage and quotas")]
+    Df {
+        #[clap(long, help = "Print JSON instead of a table")]
+        json: bool,
+    },
+
+    // `-h` means human-readable as in du(1), so help is `--help` only.
+    #[clap(
+        about = "Show the total size of each directory",
+        disable_help_flag = true
+    )]
+    Du {
+        #[clap(help = "Remote path [default: the storage root]")]
+        path: Option<Utf8PathBuf>,
+
+        #[clap(long, short = 'h', help = "Print sizes like 1.5 MiB instead of bytes")]
+        human_readable: bool,
+
+        #[clap(long, help = "Only show directories this many levels below the path")]
+        max_depth: Option<u32>,
+
+        #[clap(long, help = "Print JSON instead of a table")]
+        json: bool,
+
+        #[clap(long, action = ArgAction::Help, help = "Print help")]
+        help: Option<bool>,
+    },
+
+    #[clap(about = "Manage configured servers")]
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCommand,
+    },
+
+    #[clap(about = "Run the uploads, downloads and removals listed in a manifest")]
+    Batch {
+        #[clap(
+            required_unless_present = "from_file",
+            conflicts_with = "from_file",
+            help = "Manifest with one `u`, `d` or `rm` job per line, or - for stdin"
+        )]
+        manifest: Option<PathBuf>,
+
+        #[clap(long, help = "Read the manifest from this file")]
+        from_file: Option<PathBuf>,
+
+        #[clap(
+            long,
+            short = 'j',
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u32).range(1..),
+            help = "Number of jobs to run at once"
+        )]
+        concurrency: u32,
+
+        #[clap(long, help = "Keep running jobs after one fails")]
+        continue_on_error: bool,
+    },
+
+    #[clap(visible_alias = "b", about = "Browse local and remote files")]
+    Browse {
+        #[clap(help = "Remote directory to start in")]
+        path: Option<Utf8PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    #[clap(about = "Create a config file")]
+    Init,
+
+    #[clap(about = "Add a server")]
+    Add {
+        #[clap(required = true, help = "Server name")]
+        name: String,
+
+        #[clap(required = true, help = "Server address (host:port)")]
+        addr: String,
+
+        #[clap(long, short = 'd', help = "Use this server by default")]
+        default: bool,
+    },
+
+    #[clap(visible_alias = "rm", about = "Remove a server")]
+    Remove {
+        #[clap(required = true, help = "Server name")]
+        name: String,
+    },
+
+    #[clap(about = "Use a server by default")]
+    SetDefault {
+        #[clap(required = true, help = "Server name")]
+        name: String,
+    },
+
+    #[clap(visible_alias = "ls", about = "List servers")]
+    List,
+
+    #[clap(about = "Print the config file path")]
+    Path,
+}
+
+fn parse_rate(input: &str) -> Result<u64, String> {
+    match utils::parse_size(input) {
+        Ok(0) => Err("rate must be greater than zero".to_string()),
+        Ok(rate) => Ok(rate),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_duration(input: &str) -> Result<Duration, String> {
+    utils::parse_duration(input).map_err(|e| e.to_string())
+}
+
+/// Parses the age for `find --newer`, which the server compares in whole
+/// seconds.
+fn parse_newer(input: &str) -> Result<Duration, String> {
+    match parse_duration(input)? {
+        newer if newer < Duration::from_secs(1) => Err("must be at least one second".to_string()),
+        newer => Ok(newer),
+    }
+}
+
+/// Parses a size as `find -size` takes it: `+N` for more than N bytes, `-N`
+/// for less and a bare `N` for exactly N.
+fn parse_size_filter(input: &str) -> Result<RangeInclusive<u64>, String> {
+    let (sign, size) = match input.split_at_checked(1) {
+        Some((sign @ ("+" | "-"), size)) => (sign, size),
+        _ => ("", input),
+    };
+    let size = utils::parse_size(size).map_err(|e| e.to_string())?;
+    match sign {
+        "+" if size == u64::MAX => Err("no file is larger than that".to_string()),
+        "+" => Ok(size + 1..=u64::MAX),
+        "-" if size == 0 => Err("no file is smaller than zero bytes".to_string()),
+        "-" => Ok(0..=size - 1),
+        _ => Ok(size..=size),
+    }
+}
+
+fn parse_file_kind(input: &str) -> Result<FileKind, String> {
+    match input {
+        "f" | "file" => Ok(FileKind::File),
+        "d" | "dir" | "directory" => Ok(FileKind::Directory),
+        _ => Err("expected f (file) or d (directory)".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_size_filters() {
+        assert_eq!(parse_size_filter("100"), Ok(100..=100));
+        assert_eq!(parse_size_filter("+1K"), Ok(1025..=u64::MAX));
+        assert_eq!(parse_size_filter("-1K"), Ok(0..=1023));
+        assert_eq!(parse_size_filter("0"), Ok(0..=0));
+        // Over zero bytes means any non-empty file.
+        assert_eq!(parse_size_filter("+0"), Ok(1..=u64::MAX));
+    }
+
+    #[test]
+    fn rejects_size_filters_nothing_can_match() {
+        assert!(parse_size_filter("-0").is_err());
+        assert!(parse_size_filter(&format!("+{}", u64::MAX)).is_err());
+        assert!(parse_size_filter("+").is_err());
+        assert!(parse_size_filter("big").is_err());
+    }
+
+    #[test]
+    fn rejects_newer_below_one_second() {
+        assert!(parse_newer("500ms").is_err());
+        assert!(parse_newer("0").is_err());
+        assert_eq!(parse_newer("1s"), Ok(Duration::from_secs(1)));
+        assert_eq!(parse_newer("2d"), Ok(Duration::from_secs(2 * 86400)));
+    }
 }
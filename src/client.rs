@@ -0,0 +1,774 @@
+use std::{io, os::unix::fs::MetadataExt, sync::Arc, time::Duration};
+
+use camino::Utf8Path;
+use rand::Rng;
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+    time::{self, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    error::{Error, Result},
+    mux::Session,
+    rate_limit::Throttle,
+    server::{Connection, File, FindQuery, Packet, Space, Usage},
+    settings::Server,
+    tls, utils,
+};
+
+/// Talks to a lud server. Requests share one connection and can run at the
+/// same time, from clones of the client too. Servers that can't multiplex
+/// requests get a connection for each instead.
+#[derive(Clone)]
+pub struct Client {
+    server: Server,
+    rate_limit: Option<u64>,
+    /// Cancelled to abort the transfers in progress.
+    abort: CancellationToken,
+    retries: u32,
+    retry_delay: Duration,
+    /// The shared connection, or `None` when the server can't multiplex.
+    session: Arc<Mutex<Option<Session>>>,
+}
+
+/// A download the server has accepted, waiting to be written somewhere.
+pub struct Download {
+    /// Name of the file on the server.
+    pub name: String,
+    pub size: u64,
+    /// Unix permissions of the file on the server.
+    pub mode: u32,
+    /// Modification time of the file on the server, so a resumed download
+    /// can tell it was replaced.
+    modified: u64,
+    conn: Connection,
+    throttle: Throttle,
+    abort: CancellationToken,
+    keep_partial: bool,
+    /// The client and remote path to resume with. Shared downloads start
+    /// from the beginning, so they can't resume.
+    resume: Option<(Client, String)>,
+}
+
+impl Client {
+    /// Connects to `server`, negotiating TLS and authenticating as configured.
+    /// Network errors are retried as often as the server's settings allow.
+    pub async fn connect(server: &Server) -> Result<Self> {
+        let client = Self {
+            server: server.clone(),
+            rate_limit: None,
+            abort: CancellationToken::new(),
+            retries: server.retries.unwrap_or(0),
+            retry_delay: server
+                .retry_delay
+                .map_or(DEFAULT_RETRY_DELAY, |delay| delay.0),
+            session: Arc::new(Mutex::new(None)),
+        };
+
+        let mut attempt = 0;
+        let session = loop {
+            match open_session(server).await {
+                Ok(session) => break session,
+                Err(e) => client.backoff(e, &mut attempt).await?,
+            }
+        };
+        *client.session.lock().await = session;
+        Ok(client)
+    }
+
+    /// Limits transfers to `rate` bytes per second.
+    pub fn with_rate_limit(mut self, rate: Option<u64>) -> Self {
+        self.rate_limit = rate;
+        self
+    }
+
+    /// Aborts transfers once `abort` is cancelled. They fail with
+    /// [`Error::Aborted`], and the server discards partial uploads.
+    pub fn with_abort(mut self, abort: CancellationToken) -> Self {
+        self.abort = abort;
+        self
+    }
+
+    /// Retries requests failing on a network error up to `retries` times,
+    /// waiting `delay` before the first retry and twice as long before each
+    /// one after it.
+    pub fn with_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    /// The server this client talks to.
+    pub fn server(&self) -> &Server {
+        &self.server
+    }
+
+    /// Checks the server is responding, returning the round trip time.
+    pub async fn ping(&self) -> Result<Duration> {
+        self.idempotent(|| async {
+            let start = Instant::now();
+            let mut conn = self.send_request(&Packet::Ping).await?;
+            expect_ok(&mut conn).await?;
+            let elapsed = start.elapsed();
+            conn.finish().await;
+            Ok(elapsed)
+        })
+        .await
+    }
+
+    /// Lists the files below `path`, recursively.
+    pub async fn list(&self, path: &Utf8Path) -> Result<Vec<File>> {
+        self.idempotent(|| async {
+            let mut conn = self
+                .send_request(&Packet::List(path.to_string(), Vec::new()))
+                .await?;
+            let files = match conn.read_packet().await? {
+                Packet::List(_, files) => files,
+                other => return Err(response_error(other)),
+            };
+            conn.finish().await;
+            Ok(files)
+        })
+        .await
+    }
+
+    /// Expands the glob `pattern` on the server into the paths matching it,
+    /// including directories when `directories` is set.
+    pub async fn glob(&self, pattern: &str, directories: bool) -> Result<Vec<File>> {
+        self.idempotent(|| async {
+            let mut conn = self
+                .send_request(&Packet::Glob(pattern.to_string(), directories, Vec::new()))
+                .await?;
+            let files = match conn.read_packet().await? {
+                Packet::Glob(_, _, files) => files,
+                other => return Err(response_error(other)),
+            };
+            conn.finish().await;
+            Ok(files)
+        })
+        .await
+    }
+
+    /// Searches the server for entries matching `query`, calling `on_found`
+    /// with each batch as the server streams them in. An error from
+    /// `on_found` cancels the search.
+    pub async fn find(
+        &self,
+        query: &FindQuery,
+        mut on_found: impl FnMut(Vec<File>) -> io::Result<()>,
+    ) -> Result<()> {
+        // Retrying after results arrived would report them twice, so only
+        // sending the request is retried.
+        let mut conn = self.request(&Packet::Find(query.clone())).await?;
+        loop {
+            match conn.read_packet().await? {
+                Packet::Found(files) => on_found(files)
+                    .map_err(|e| Error::Io("Failed to handle search results".to_string(), e))?,
+                Packet::Ok => break,
+                other => return Err(response_error(other)),
+            }
+        }
+        conn.finish().await;
+        Ok(())
+    }
+
+    /// Removes `path`. Directories need `recursive`, and `force` ignores
+    /// paths that don't exist.
+    pub async fn remove(&self, path: &Utf8Path, force: bool, recursive: bool) -> Result<()> {
+        let mut conn = self
+            .request(&Packet::Remove(path.to_string(), force, recursive))
+            .await?;
+        expect_ok(&mut conn).await?;
+        conn.finish().await;
+        Ok(())
+    }
+
+    /// Storage used and the quotas that apply to this client.
+    pub async fn usage(&self) -> Result<Vec<Usage>> {
+        self.idempotent(|| async {
+            let mut conn = self.send_request(&Packet::Usage(Vec::new())).await?;
+            let usage = match conn.read_packet().await? {
+                Packet::Usage(usage) => usage,
+                other => return Err(response_error(other)),
+            };
+            conn.finish().await;
+            Ok(usage)
+        })
+        .await
+    }
+
+    /// The space on the server's storage volume.
+    pub async fn space(&self) -> Result<Space> {
+        self.idempotent(|| async {
+            let mut conn = self.send_request(&Packet::Space(None)).await?;
+            let space = match conn.read_packet().await? {
+                Packet::Space(Some(space)) => space,
+                other => return Err(response_error(other)),
+            };
+            conn.finish().await;
+            Ok(space)
+        })
+        .await
+    }
+
+    /// The total size of `path` and every directory below it, down to
+    /// `max_depth` levels when set.
+    pub async fn disk_usage(&self, path: &Utf8Path, max_depth: Option<u32>) -> Result<Vec<File>> {
+        self.idempotent(|| async {
+            let mut conn = self
+                .send_request(&Packet::DiskUsage(path.to_string(), max_depth, Vec::new()))
+                .await?;
+            let files = match conn.read_packet().await? {
+                Packet::DiskUsage(_, _, files) => files,
+                other => return Err(response_error(other)),
+            };
+            conn.finish().await;
+            Ok(files)
+        })
+        .await
+    }
+
+    /// Issues a token anyone can download `path` with, valid for `expires_in`
+    /// and `max_downloads` when set. Also returns the base URL of the
+    /// server's HTTP gateway, if it runs one.
+    pub async fn share(
+        &self,
+        path: &Utf8Path,
+        expires_in: Option<Duration>,
+        max_downloads: Option<u32>,
+    ) -> Result<(String, Option<String>)> {
+        let expires_in = expires_in.map(|expires_in| expires_in.as_secs());
+        let mut conn = self
+            .request(&Packet::Share(path.to_string(), expires_in, max_downloads))
+            .await?;
+        let token = match conn.read_packet().await? {
+            Packet::ShareToken(token) => token,
+            other => return Err(response_error(other)),
+        };
+        let gateway = match conn.read_packet().await? {
+            Packet::HttpGateway(gateway) => Some(gateway),
+            Packet::Ok => None,
+            other => return Err(response_error(other)),
+        };
+        conn.finish().await;
+        Ok((token, gateway))
+    }
+
+    /// Starts downloading `remote_path`. Should the connection break during
+    /// the transfer, the download resumes where it stopped.
+    pub async fn download(&self, remote_path: &Utf8Path) -> Result<Download> {
+        let path = remote_path.to_string();
+        let request = Packet::DownloadStart(path.clone(), 0, 0, 0);
+        let (conn, name, size, mode, modified) =
+            self.idempotent(|| self.start_download(&request)).await?;
+        Ok(Download {
+            name,
+            size,
+            mode,
+            modified,
+            conn,
+            throttle: self.throttle(),
+            abort: self.abort.clone(),
+            keep_partial: false,
+            resume: Some((self.clone(), path)),
+        })
+    }
+
+    /// Starts downloading the file shared with `token`.
+    pub async fn download_shared(&self, token: &str) -> Result<Download> {
+        // The download only counts once the server sent all of it, so
+        // starting over is safe.
+        let request = Packet::SharedDownload(token.to_string());
+        let (conn, name, size, mode, modified) =
+            self.idempotent(|| self.start_download(&request)).await?;
+        Ok(Download {
+            name,
+            size,
+            mode,
+            modified,
+            conn,
+            throttle: self.throttle(),
+            abort: self.abort.clone(),
+            keep_partial: false,
+            resume: None,
+        })
+    }
+
+    /// Downloads `remote_path` into `writer`, calling `on_progress(received,
+    /// total)` after every chunk. Returns the file size.
+    pub async fn download_to_writer<W, F>(
+        &self,
+        remote_path: &Utf8Path,
+        writer: &mut W,
+        on_progress: F,
+    ) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+        F: FnMut(u64, u64),
+    {
+        self.download(remote_path)
+            .await?
+            .write_to(writer, on_progress)
+            .await
+    }
+
+    /// Downloads `remote_path` to the local file `local_path`.
+    pub async fn download_file<F>(
+        &self,
+        remote_path: &Utf8Path,
+        local_path: &Utf8Path,
+        on_progress: F,
+    ) -> Result<u64>
+    where
+        F: FnMut(u64, u64),
+    {
+        self.download(remote_path)
+            .await?
+            .save(local_path, on_progress)
+            .await
+    }
+
+    /// Uploads `size` bytes from `reader` to `remote_path` with permissions
+    /// `mode`, calling `on_progress(sent, total)` after every chunk. Existing
+    /// files are only replaced with `force`.
+    pub async fn upload_from_reader<R, F>(
+        &self,
+        reader: &mut R,
+        remote_path: &Utf8Path,
+        size: u64,
+        mode: u32,
+        force: bool,
+        mut on_progress: F,
+    ) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(u64, u64),
+    {
+        let conn = self
+            .request(&Packet::UploadStart(
+                remote_path.to_string(),
+                size,
+                mode,
+                force,
+            ))
+            .await?;
+        self.send_upload(conn, reader, size, &mut false, &mut on_progress)
+            .await
+    }
+
+    /// Uploads the local file `local_path` to `remote_path`, keeping its
+    /// permissions. Should the connection break during the transfer, the
+    /// upload starts over.
+    pub async fn upload_file<F>(
+        &self,
+        local_path: &Utf8Path,
+        remote_path: &Utf8Path,
+        force: bool,
+        mut on_progress: F,
+    ) -> Result<u64>
+    where
+        F: FnMut(u64, u64),
+    {
+        let mut attempt = 0;
+        loop {
+            let mut file = fs::File::open(local_path)
+                .await
+                .map_err(|e| Error::Io(format!("Failed to open file `{}`", local_path), e))?;
+            let metadata = file.metadata().await.map_err(|e| {
+                Error::Io(format!("Failed to get metadata for `{}`", local_path), e)
+            })?;
+
+            let size = metadata.len();
+            let request =
+                Packet::UploadStart(remote_path.to_string(), size, metadata.mode(), force);
+            let mut sent = false;
+            let result = async {
+                let conn = self.send_request(&request).await?;
+                self.send_upload(conn, &mut file, size, &mut sent, &mut on_progress)
+                    .await
+            }
+            .await;
+            match result {
+                Ok(size) => return Ok(size),
+                // Once the whole file is out the server may have saved it, so
+                // sending it again could fail or overwrite a newer upload.
+                Err(e) if sent => return Err(e),
+                Err(e) => self.backoff(e, &mut attempt).await?,
+            }
+        }
+    }
+
+    /// Sends the `size` bytes of an upload requested over `conn`, setting
+    /// `sent` once the whole file is on its way.
+    async fn send_upload<R, F>(
+        &self,
+        mut conn: Connection,
+        reader: &mut R,
+        size: u64,
+        sent: &mut bool,
+        on_progress: &mut F,
+    ) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        F: FnMut(u64, u64),
+    {
+        let throttle = self.throttle();
+        expect_ok(&mut conn).await?;
+
+        let mut buffer = vec![0u8; utils::optimal_chunk_size(size)];
+        let mut sent_bytes = 0;
+        on_progress(sent_bytes, size);
+
+        while sent_bytes < size {
+            let len = buffer.len().min((size - sent_bytes) as usize);
+            let chunk = async {
+                let bytes_read = reader
+                    .read(&mut buffer[..len])
+                    .await
+                    .map_err(|e| Error::Io("Failed to read upload".to_string(), e))?;
+                throttle.consume(bytes_read as u64).await;
+                Ok::<_, Error>(bytes_read)
+            };
+            // Only the wait for the next chunk is interrupted, as a packet cut off
+            // halfway would leave the server unable to read the abort.
+            let bytes_read = tokio::select! {
+                biased;
+                _ = self.abort.cancelled() => None,
+                bytes_read = chunk => Some(bytes_read?),
+            };
+            let Some(bytes_read) = bytes_read else {
+                conn.write_packet(&Packet::Abort).await?;
+                return Err(Error::Aborted);
+            };
+            if bytes_read == 0 {
+                return Err(Error::SizeMismatch {
+                    expected: size,
+                    actual: sent_bytes,
+                });
+            }
+
+            conn.write_packet(&Packet::UploadChunk(buffer[..bytes_read].to_vec()))
+                .await?;
+
+            sent_bytes += bytes_read as u64;
+            on_progress(sent_bytes, size);
+        }
+
+        *sent = true;
+        conn.write_packet(&Packet::UploadEnd).await?;
+        expect_ok(&mut conn).await?;
+        conn.finish().await;
+        Ok(size)
+    }
+
+    /// Sends a request for a download, returning the connection to read the
+    /// file from along with its name, size, permissions and modification time.
+    async fn start_download(
+        &self,
+        request: &Packet,
+    ) -> Result<(Connection, String, u64, u32, u64)> {
+        let mut conn = self.send_request(request).await?;
+        match conn.read_packet().await? {
+            Packet::DownloadStart(name, size, mode, modified) => {
+                Ok((conn, name, size, mode, modified))
+            }
+            other => Err(response_error(other)),
+        }
+    }
+
+    /// Runs a request that changes nothing on the server, starting it over
+    /// whenever it fails on a network error.
+    async fn idempotent<T, F, Fut>(&self, request: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Ok(value) => return Ok(value),
+                Err(e) => self.backoff(e, &mut attempt).await?,
+            }
+        }
+    }
+
+    /// Sends `packet` as a new request, retrying failures before it reaches
+    /// the server. Requests that change something can't be repeated once
+    /// sent, as the server may have carried them out already.
+    async fn request(&self, packet: &Packet) -> Result<Connection> {
+        let mut attempt = 0;
+        loop {
+            match self.send_request(packet).await {
+                Ok(conn) => return Ok(conn),
+                Err(e) => self.backoff(e, &mut attempt).await?,
+            }
+        }
+    }
+
+    /// Sends `packet` as a new request, reconnecting if the shared
+    /// connection closed since the last one.
+    async fn send_request(&self, packet: &Packet) -> Result<Connection> {
+        let mut conn = {
+            let mut session = self.session.lock().await;
+            if session.as_ref().is_some_and(Session::is_closed) {
+                *session = open_session(&self.server).await?;
+            }
+            match session.as_ref() {
+                Some(session) => Connection::multiplexed(session.open()?),
+                None => {
+                    drop(session);
+                    open(&self.server).await?
+                }
+            }
+        };
+        conn.write_packet(packet).await?;
+        Ok(conn)
+    }
+
+    /// Waits before the next attempt after `error`. Gives up with the error
+    /// when it isn't a network error or the retries are used up.
+    async fn backoff(&self, error: Error, attempt: &mut u32) -> Result<()> {
+        if !error.is_transient() || *attempt >= self.retries {
+            return Err(error);
+        }
+        *attempt += 1;
+
+        let delay = retry_delay(self.retry_delay, *attempt);
+        log::warn!(
+            "{}, retrying in {:.1?} ({}/{})",
+            error,
+            delay,
+            attempt,
+            self.retries
+        );
+        tokio::select! {
+            _ = time::sleep(delay) => Ok(()),
+            _ = self.abort.cancelled() => Err(Error::Aborted),
+        }
+    }
+
+    fn throttle(&self) -> Throttle {
+        Throttle::new(self.rate_limit)
+    }
+}
+
+impl Download {
+    /// Reconnects after `error` broke the download and asks for the rest of
+    /// the file from `offset`, unless the download can't resume or the
+    /// retries are used up.
+    async fn resume(&mut self, mut error: Error, offset: u64, attempt: &mut u32) -> Result<()> {
+        let Some((client, path)) = self.resume.clone() else {
+            return Err(error);
+        };
+        loop {
+            client.backoff(error, attempt).await?;
+            let request = Packet::DownloadStart(path.clone(), offset, 0, 0);
+            error = match client.start_download(&request).await {
+                // A file rewritten at the same size would otherwise be
+                // stitched together from both versions.
+                Ok((conn, _, size, _, modified))
+                    if size == self.size && modified == self.modified =>
+                {
+                    log::info!("Resuming download of `{}` at {} bytes", path, offset);
+                    self.conn = conn;
+                    return Ok(());
+                }
+                Ok(_) => return Err(Error::Changed(path)),
+                Err(e) => e,
+            };
+        }
+    }
+
+    /// Writes the file into `writer`, calling `on_progress(received, total)`
+    /// after every chunk. Returns the file size.
+    pub async fn write_to<W, F>(mut self, writer: &mut W, mut on_progress: F) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+        F: FnMut(u64, u64),
+    {
+        let mut received_bytes = 0;
+        // Starts over whenever data arrives, so every interruption gets all
+        // the retries.
+        let mut attempt = 0;
+        on_progress(received_bytes, self.size);
+
+        loop {
+            // Nothing is left to tell the server, which stops sending once the
+            // connection or stream is dropped.
+            let packet = tokio::select! {
+                biased;
+                _ = self.abort.cancelled() => return Err(Error::Aborted),
+                packet = self.conn.read_packet() => packet,
+            };
+            let packet = match packet {
+                Ok(packet) => packet,
+                Err(e) => {
+                    self.resume(e.into(), received_bytes, &mut attempt).await?;
+                    continue;
+                }
+            };
+            match packet {
+                Packet::DownloadChunk(data) => {
+                    tokio::select! {
+                        biased;
+                        _ = self.abort.cancelled() => return Err(Error::Aborted),
+                        _ = self.throttle.consume(data.len() as u64) => {}
+                    }
+                    received_bytes += data.len() as u64;
+                    writer
+                        .write_all(&data)
+                        .await
+                        .map_err(|e| Error::Io("Failed to write download".to_string(), e))?;
+                    on_progress(received_bytes, self.size);
+                    attempt = 0;
+                }
+                Packet::DownloadEnd => break,
+                other => return Err(response_error(other)),
+            }
+        }
+        writer
+            .flush()
+            .await
+            .map_err(|e| Error::Io("Failed to write download".to_string(), e))?;
+
+        if received_bytes != self.size {
+            return Err(Error::SizeMismatch {
+                expected: self.size,
+                actual: received_bytes,
+            });
+        }
+
+        self.conn.finish().await;
+        Ok(self.size)
+    }
+
+    /// Keeps what was written of the file when [`save`](Self::save) fails,
+    /// instead of removing it.
+    pub fn keep_partial(mut self, keep: bool) -> Self {
+        self.keep_partial = keep;
+        self
+    }
+
+    /// Writes the file to `local_path` with the permissions it has on the
+    /// server. A failed or aborted download leaves nothing behind, unless
+    /// [`keep_partial`](Self::keep_partial) is set.
+    pub async fn save<F>(self, local_path: &Utf8Path, on_progress: F) -> Result<u64>
+    where
+        F: FnMut(u64, u64),
+    {
+        let mut file = fs::File::create(local_path)
+            .await
+            .map_err(|e| Error::Io(format!("Failed to create file `{}`", local_path), e))?;
+
+        let keep_partial = self.keep_partial;
+        let result = async {
+            #[cfg(unix)]
+            {
+                use std::fs::Permissions;
+                use std::os::unix::fs::PermissionsExt;
+                file.set_permissions(Permissions::from_mode(self.mode))
+                    .await
+                    .map_err(|e| {
+                        Error::Io(format!("Failed to set permissions for `{}`", local_path), e)
+                    })?;
+            }
+
+            self.write_to(&mut file, on_progress)
+                .await
+                .map_err(|e| match e {
+                    Error::Io(_, e) => {
+                        Error::Io(format!("Failed to write to file `{}`", local_path), e)
+                    }
+                    e => e,
+                })
+        }
+        .await;
+
+        if result.is_err() && !keep_partial {
+            drop(file);
+            match fs::remove_file(local_path).await {
+                Ok(()) => log::debug!("Removed partial download `{}`", local_path),
+                Err(e) => log::warn!("Failed to remove partial download `{}`: {}", local_path, e),
+            }
+        }
+        result
+    }
+}
+
+/// Delay before the first retry unless the server's settings say otherwise.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between two attempts, however many retries came before.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Doubles `base` for every attempt after the first, scaled by a random factor
+/// so clients that failed together don't all retry at once.
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let delay = base
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(MAX_RETRY_DELAY);
+    delay.mul_f64(rand::rng().random_range(0.5..=1.0))
+}
+
+/// Opens a connection to `server` and asks to multiplex requests over it.
+/// Returns `None` when the server can't, after which each request needs a
+/// connection of its own.
+async fn open_session(server: &Server) -> Result<Option<Session>> {
+    let mut conn = open(server).await?;
+    conn.write_packet(&Packet::Multiplex).await?;
+    match conn.read_packet().await? {
+        Packet::Ok => Ok(Some(Session::client(conn)?)),
+        Packet::Error(..) => {
+            log::debug!("`{}` can't multiplex requests", server.addr);
+            Ok(None)
+        }
+        other => Err(response_error(other)),
+    }
+}
+
+/// Opens a connection to `server`, negotiating TLS and authenticating as
+/// configured.
+async fn open(server: &Server) -> Result<Connection> {
+    let stream = TcpStream::connect(&server.addr)
+        .await
+        .map_err(|e| Error::Connect(server.addr.clone(), e))?;
+    if let Err(e) = stream.set_nodelay(true) {
+        log::debug!("Failed to disable Nagle's algorithm: {}", e);
+    }
+
+    let mut conn = if server.tls {
+        let stream = tls::connect(stream, &server.addr, server.ca_cert.as_deref())
+            .await
+            .map_err(Error::tls)?;
+        Connection::new(stream)
+    } else {
+        Connection::new(stream)
+    };
+
+    if let Some(user) = &server.user {
+        let token = server.token.clone().unwrap_or_default();
+        conn.write_packet(&Packet::Auth(user.clone(), token))
+            .await?;
+        expect_ok(&mut conn).await?;
+    }
+
+    Ok(conn)
+}
+
+async fn expect_ok(conn: &mut Connection) -> Result<()> {
+    match conn.read_packet().await? {
+        Packet::Ok => Ok(()),
+        other => Err(response_error(other)),
+    }
+}
+
+fn response_error(packet: Packet) -> Error {
+    match packet {
+        Packet::Error(code, message) => Error::Rejected(code, message),
+        other => Error::UnexpectedResponse(other.to_string()),
+    }
+}
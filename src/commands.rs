@@ -1,30 +1,51 @@
-use std::os::unix::fs::MetadataExt;
+use std::{
+    collections::HashSet,
+    io::{self, IsTerminal, Write},
+    ops::RangeInclusive,
+    path::Path,
+    time::Duration,
+};
 
 use anyhow::{Context, Result, anyhow};
-use camino::Utf8PathBuf;
-use chrono::Utc;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{Local, Utc};
 use humansize::{BINARY, format_size};
 use indicatif::{ProgressBar, ProgressStyle};
-use tokio::{
-    fs,
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, ToSocketAddrs},
-    time::Instant,
+use lud::{
+    Client, ErrorCode,
+    discovery::{self, Discovered},
+    server::{FileKind, FindQuery},
+    settings::{self, Server, Settings},
+    utils,
 };
-
-use crate::{server::{Connection, Packet}, utils};
+use tokio::{fs, signal};
+use tokio_util::sync::CancellationToken;
 
 const TIME_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
 
 const PROGRESS_STYLE: &str = "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})";
 const PROGRESS_CHARS: &str = "#>-";
 
-pub async fn download<A: ToSocketAddrs>(
+pub async fn download(
     remote_path: Utf8PathBuf,
     local_path: Option<Utf8PathBuf>,
     force: bool,
-    addr: A,
+    limit_rate: Option<u64>,
+    keep_partial: bool,
+    server: &Server,
 ) -> Result<()> {
+    if utils::is_glob(remote_path.as_str()) {
+        return download_glob(
+            remote_path,
+            local_path,
+            force,
+            limit_rate,
+            keep_partial,
+            server,
+        )
+        .await;
+    }
+
     let local_path = local_path.unwrap_or_else(|| {
         remote_path.file_name().map(Into::into).unwrap_or_else(|| {
             let timestamp = Utc::now().format(TIME_FORMAT).to_string();
@@ -33,81 +54,175 @@ pub async fn download<A: ToSocketAddrs>(
     });
 
     if !force && fs::try_exists(&local_path).await.unwrap_or(false) {
-        return Err(anyhow!("File already exists"));
+        return Err(already_exists(&local_path));
     }
 
-    with_connection(addr, |mut conn| async move {
-        conn.write_packet(&Packet::DownloadStart(remote_path.into(), 0, 0))
-            .await
-            .context("Failed to send download request")?;
+    let client = Client::connect(server)
+        .await?
+        .with_rate_limit(limit_rate)
+        .with_abort(abort_on_ctrl_c());
+    let download = client.download(&remote_path).await?;
+
+    let pb = progress_bar(download.size);
+    let total_size = download
+        .keep_partial(keep_partial)
+        .save(&local_path, |received, _| pb.set_position(received))
+        .await?;
+
+    pb.finish_and_clear();
+
+    log::info!(
+        "Successfully downloaded file `{}` ({})",
+        local_path,
+        format_size(total_size, BINARY)
+    );
+    Ok(())
+}
 
-        let (_, total_size, mode) = match conn.read_packet().await? {
-            Packet::DownloadStart(name, size, mode) => (name, size, mode),
-            Packet::Error(e) => return Err(anyhow!(e)),
-            other => return Err(anyhow!("Unexpected response: {:?}", other)),
-        };
+/// Downloads every remote file matching `pattern` into the directory
+/// `local_dir`, keeping their paths below the pattern's base.
+async fn download_glob(
+    pattern: Utf8PathBuf,
+    local_dir: Option<Utf8PathBuf>,
+    force: bool,
+    limit_rate: Option<u64>,
+    keep_partial: bool,
+    server: &Server,
+) -> Result<()> {
+    let client = Client::connect(server)
+        .await?
+        .with_rate_limit(limit_rate)
+        .with_abort(abort_on_ctrl_c());
+    let files = client.glob(pattern.as_str(), false).await?;
+    if files.is_empty() {
+        return Err(no_matches(&pattern));
+    }
 
-        let mut file = fs::File::create(&local_path)
-            .await
-            .context(format!("Failed to create file `{}`", local_path))?;
+    let local_dir = local_dir.unwrap_or_default();
+    let base = utils::glob_base(pattern.as_str());
+    let targets: Vec<_> = files
+        .iter()
+        .map(|file| {
+            let remote_path = Utf8Path::new(&file.path);
+            (remote_path, local_dir.join(below_base(remote_path, &base)))
+        })
+        .collect();
 
-        #[cfg(unix)]
-        {
-            use std::fs::Permissions;
-            use std::os::unix::fs::PermissionsExt;
-            file.set_permissions(Permissions::from_mode(mode))
-                .await
-                .context(format!("Failed to set permissions for `{}`", local_path))?;
+    // Refuse before transferring anything rather than stopping halfway.
+    if !force {
+        for (_, local_path) in &targets {
+            if fs::try_exists(local_path).await.unwrap_or(false) {
+                return Err(already_exists(local_path));
+            }
         }
+    }
 
-        let pb = ProgressBar::new(total_size);
-        pb.set_style(ProgressStyle::with_template(PROGRESS_STYLE)
-            .unwrap()
-            .progress_chars(PROGRESS_CHARS));
-
-        let mut received_bytes = 0;
-
-        loop {
-            match conn.read_packet().await? {
-                Packet::DownloadChunk(data) => {
-                    received_bytes += data.len() as u64;
-                    file.write_all(&data)
-                        .await
-                        .context(format!("Failed to write to file `{}`", local_path))?;
-                    pb.set_position(received_bytes);
-                }
-                Packet::DownloadEnd => break,
-                Packet::Error(e) => return Err(anyhow!(e)),
-                other => return Err(anyhow!("Unexpected packet: {:?}", other)),
-            }
+    let mut total_size = 0;
+    for (remote_path, local_path) in targets {
+        if let Some(parent) = local_path.parent()
+            && !parent.as_str().is_empty()
+        {
+            fs::create_dir_all(parent)
+                .await
+                .context(format!("Failed to create directory `{}`", parent))?;
         }
 
+        let download = client.download(remote_path).await?;
+        let pb = progress_bar(download.size);
+        let size = download
+            .keep_partial(keep_partial)
+            .save(&local_path, |received, _| pb.set_position(received))
+            .await?;
         pb.finish_and_clear();
 
-        if received_bytes != total_size {
-            return Err(anyhow!(
-                "File size mismatch (received {} of {} bytes)",
-                received_bytes,
-                total_size
-            ));
-        }
-
         log::info!(
-            "Successfully downloaded file `{}` ({})",
+            "Downloaded file `{}` ({})",
             local_path,
-            format_size(total_size, BINARY)
+            format_size(size, BINARY)
         );
-        Ok(())
-    })
-    .await
+        total_size += size;
+    }
+
+    log::info!(
+        "Successfully downloaded {} files matching `{}` ({})",
+        files.len(),
+        pattern,
+        format_size(total_size, BINARY)
+    );
+    Ok(())
 }
 
-pub async fn upload<A: ToSocketAddrs>(
+/// Downloads the file shared with `token`, saving it under its remote name
+/// unless `local_path` is given.
+pub async fn download_shared(
+    token: String,
+    local_path: Option<Utf8PathBuf>,
+    force: bool,
+    limit_rate: Option<u64>,
+    keep_partial: bool,
+    server: &Server,
+) -> Result<()> {
+    if let Some(local_path) = &local_path
+        && !force
+        && fs::try_exists(local_path).await.unwrap_or(false)
+    {
+        return Err(already_exists(local_path));
+    }
+
+    // Share links work without credentials, so don't send any.
+    let server = Server {
+        user: None,
+        token: None,
+        ..server.clone()
+    };
+
+    let client = Client::connect(&server)
+        .await?
+        .with_rate_limit(limit_rate)
+        .with_abort(abort_on_ctrl_c());
+    let download = client.download_shared(&token).await?;
+
+    let local_path = match local_path {
+        Some(local_path) => local_path,
+        None => {
+            let name = Utf8Path::new(&download.name)
+                .file_name()
+                .unwrap_or("shared-file");
+            let local_path = Utf8PathBuf::from(name);
+            if !force && fs::try_exists(&local_path).await.unwrap_or(false) {
+                return Err(already_exists(&local_path));
+            }
+            local_path
+        }
+    };
+
+    let pb = progress_bar(download.size);
+    let total_size = download
+        .keep_partial(keep_partial)
+        .save(&local_path, |received, _| pb.set_position(received))
+        .await?;
+
+    pb.finish_and_clear();
+
+    log::info!(
+        "Successfully downloaded file `{}` ({})",
+        local_path,
+        format_size(total_size, BINARY)
+    );
+    Ok(())
+}
+
+pub async fn upload(
     local_path: Utf8PathBuf,
     remote_path: Option<Utf8PathBuf>,
     force: bool,
-    addr: A,
+    limit_rate: Option<u64>,
+    server: &Server,
 ) -> Result<()> {
+    if utils::is_glob(local_path.as_str()) {
+        return upload_glob(local_path, remote_path, force, limit_rate, server).await;
+    }
+
     let remote_path = remote_path.unwrap_or_else(|| {
         local_path.file_name().map(Into::into).unwrap_or_else(|| {
             let timestamp = Utc::now().format(TIME_FORMAT).to_string();
@@ -119,148 +234,401 @@ pub async fn upload<A: ToSocketAddrs>(
         .await
         .context(format!("Failed to get metadata for `{}`", &local_path))?;
 
-    with_connection(addr, |mut conn| async move {
-        conn.write_packet(&Packet::UploadStart(
-            remote_path.into(),
-            metadata.len(),
-            metadata.mode(),
-            force,
-        ))
-        .await
-        .context("Failed to send upload start packet")?;
-
-        match conn.read_packet().await? {
-            Packet::Ok => {}
-            Packet::Error(e) => return Err(anyhow!(e)),
-            other => return Err(anyhow!("Unexpected response: {:?}", other)),
-        }
-
-        let chunk_size = utils::optimal_chunk_size(metadata.len());
-        let mut file = fs::File::open(&local_path)
-            .await
-            .context(format!("Failed to open file `{}`", &local_path))?;
-
-        let pb = ProgressBar::new(metadata.len());
-        pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-            .unwrap()
-            .progress_chars("#>-"));
-
-        let mut buffer = vec![0u8; chunk_size];
-        let mut sent_bytes = 0;
+    let pb = progress_bar(metadata.len());
+    let client = Client::connect(server)
+        .await?
+        .with_rate_limit(limit_rate)
+        .with_abort(abort_on_ctrl_c());
+    let total_size = client
+        .upload_file(&local_path, &remote_path, force, |sent, _| {
+            pb.set_position(sent)
+        })
+        .await?;
 
-        loop {
-            let bytes_read = file
-                .read(&mut buffer)
-                .await
-                .context(format!("Failed to read file `{}`", &local_path))?;
+    pb.finish_and_clear();
 
-            if bytes_read == 0 {
-                break;
-            }
+    log::info!(
+        "Successfully uploaded file `{}` ({})",
+        local_path,
+        format_size(total_size, BINARY)
+    );
+    Ok(())
+}
 
-            conn.write_packet(&Packet::UploadChunk(buffer[..bytes_read].to_vec()))
-                .await
-                .context("Failed to send file chunk")?;
-            
-            sent_bytes += bytes_read as u64;
-            pb.set_position(sent_bytes);
-        }
+/// Uploads every local file matching `pattern` into the remote directory
+/// `remote_dir`, keeping their paths below the pattern's base.
+async fn upload_glob(
+    pattern: Utf8PathBuf,
+    remote_dir: Option<Utf8PathBuf>,
+    force: bool,
+    limit_rate: Option<u64>,
+    server: &Server,
+) -> Result<()> {
+    let files = utils::glob(Utf8Path::new(""), pattern.as_str(), false, |_| false)?;
+    if files.is_empty() {
+        return Err(no_matches(&pattern));
+    }
 
+    let client = Client::connect(server)
+        .await?
+        .with_rate_limit(limit_rate)
+        .with_abort(abort_on_ctrl_c());
+
+    let remote_dir = remote_dir.unwrap_or_default();
+    let base = utils::glob_base(pattern.as_str());
+    let mut total_size = 0;
+    for file in &files {
+        let local_path = Utf8Path::new(&file.path);
+        let remote_path = remote_dir.join(below_base(local_path, &base));
+
+        let pb = progress_bar(file.size);
+        let size = client
+            .upload_file(local_path, &remote_path, force, |sent, _| {
+                pb.set_position(sent)
+            })
+            .await?;
         pb.finish_and_clear();
 
-        conn.write_packet(&Packet::UploadEnd)
-            .await
-            .context("Failed to send upload end packet")?;
-
-        match conn.read_packet().await? {
-            Packet::Ok => {
-                log::info!(
-                    "Successfully uploaded file `{}` ({})",
-                    local_path,
-                    format_size(metadata.len(), BINARY)
-                );
-                Ok(())
-            }
-            Packet::Error(e) => Err(anyhow!(e)),
-            other => Err(anyhow!("Unexpected response: {:?}", other)),
-        }
-    })
-    .await
+        log::info!(
+            "Uploaded file `{}` ({})",
+            local_path,
+            format_size(size, BINARY)
+        );
+        total_size += size;
+    }
+
+    log::info!(
+        "Successfully uploaded {} files matching `{}` ({})",
+        files.len(),
+        pattern,
+        format_size(total_size, BINARY)
+    );
+    Ok(())
 }
 
-pub async fn list<A: ToSocketAddrs>(path: Option<Utf8PathBuf>, addr: A) -> Result<()> {
+pub async fn list(path: Option<Utf8PathBuf>, server: &Server) -> Result<()> {
     let path = path.unwrap_or_else(|| "./".into());
 
-    with_connection(addr, |mut conn| async move {
-        conn.write_packet(&Packet::List(path.clone().into(), Vec::new()))
-            .await
-            .context("Failed to send list request")?;
+    let files = Client::connect(server).await?.list(&path).await?;
+    utils::pretty_print(files);
+    Ok(())
+}
 
-        match conn.read_packet().await? {
-            Packet::List(_, files) => {
-                utils::pretty_print(files);
-                Ok(())
+/// Prints the paths on the server matching every criterion given, as they
+/// are found.
+pub async fn find(
+    path: Option<Utf8PathBuf>,
+    name: Option<String>,
+    size: Vec<RangeInclusive<u64>>,
+    newer: Option<Duration>,
+    kind: Option<FileKind>,
+    server: &Server,
+) -> Result<()> {
+    let query = FindQuery {
+        path: path.unwrap_or_else(|| "./".into()).into_string(),
+        name,
+        // Several size limits all have to hold, like repeated `find -size`.
+        min_size: size.iter().map(|range| *range.start()).max(),
+        max_size: size.iter().map(|range| *range.end()).min(),
+        newer: newer.map(|newer| newer.as_secs()),
+        kind,
+    };
+
+    let mut found = 0;
+    let mut stdout = io::stdout();
+    let result = Client::connect(server)
+        .await?
+        .find(&query, |files| {
+            found += files.len();
+            for file in files {
+                writeln!(stdout, "{}", file.path)?;
             }
-            Packet::Error(e) => Err(anyhow!(e)),
-            other => Err(anyhow!("Unexpected response: {:?}", other)),
+            Ok(())
+        })
+        .await;
+
+    match result {
+        // The reader went away, as with `lud find | head`, so stop quietly.
+        Err(lud::Error::Io(_, e)) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e.into()),
+        Ok(()) => {
+            log::debug!("Found {} entries", found);
+            Ok(())
         }
-    })
-    .await
+    }
 }
 
-pub async fn remove<A: ToSocketAddrs>(
+pub async fn remove(
     path: Utf8PathBuf,
     force: bool,
     recursive: bool,
-    addr: A,
+    dry_run: bool,
+    server: &Server,
 ) -> Result<()> {
-    with_connection(addr, |mut conn| async move {
-        conn.write_packet(&Packet::Remove(path.clone().into(), force, recursive))
-            .await
-            .context("Failed to send remove request")?;
-
-        match conn.read_packet().await? {
-            Packet::Ok => {
-                log::info!("Successfully removed path: {}", path);
-                Ok(())
-            }
-            Packet::Error(e) => Err(anyhow!(e)),
-            other => Err(anyhow!("Unexpected response: {:?}", other)),
+    let client = Client::connect(server).await?;
+    if !utils::is_glob(path.as_str()) && !dry_run {
+        client.remove(&path, force, recursive).await?;
+        log::info!("Successfully removed path: {}", path);
+        return Ok(());
+    }
+
+    // Directories only match with `recursive`, the same as removing them.
+    let matches = client.glob(path.as_str(), recursive).await?;
+    if matches.is_empty() {
+        if force {
+            log::info!("No paths match `{}`, nothing to remove", path);
+            return Ok(());
+        }
+        return Err(no_matches(&path));
+    }
+
+    if dry_run {
+        for file in &matches {
+            println!("{}", file.path);
+        }
+        log::info!("Would remove {} paths matching `{}`", matches.len(), path);
+        return Ok(());
+    }
+
+    // Only ask when someone is there to answer, so scripts aren't stuck.
+    if !force && io::stdin().is_terminal() {
+        for file in &matches {
+            println!("{}", file.path);
+        }
+        let question = format!("Remove {} paths matching `{}`?", matches.len(), path);
+        if !confirm(&question)? {
+            log::info!("Nothing removed");
+            return Ok(());
         }
-    })
-    .await
+    }
+
+    for file in &matches {
+        client
+            .remove(Utf8Path::new(&file.path), force, recursive)
+            .await?;
+        log::info!("Removed path: {}", file.path);
+    }
+    log::info!(
+        "Successfully removed {} paths matching `{}`",
+        matches.len(),
+        path
+    );
+    Ok(())
 }
 
-pub async fn ping<A: ToSocketAddrs>(addr: A) -> Result<()> {
-    let start_time = Instant::now();
+/// Asks `question` on the terminal, returning whether the answer was yes.
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("Failed to read the answer")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
 
-    with_connection(addr, |mut conn| async move {
-        conn.write_packet(&Packet::Ping)
-            .await
-            .context("Failed to send ping")?;
+pub async fn share(
+    path: Utf8PathBuf,
+    expires: Option<Duration>,
+    max_downloads: Option<u32>,
+    server: &Server,
+) -> Result<()> {
+    if expires.is_some_and(|expires| expires.as_secs() == 0) {
+        return Err(anyhow!("Expiry must be at least one second"));
+    }
+    if max_downloads == Some(0) {
+        return Err(anyhow!("Maximum downloads must be at least one"));
+    }
 
-        match conn.read_packet().await? {
-            Packet::Ok => {
-                let duration = start_time.elapsed();
-                log::info!("Server is online ({:?})", duration);
-                Ok(())
-            }
-            Packet::Error(e) => Err(anyhow!(e)),
-            other => Err(anyhow!("Unexpected response: {:?}", other)),
+    let (token, gateway) = Client::connect(server)
+        .await?
+        .share(&path, expires, max_downloads)
+        .await?;
+
+    let mut limits = Vec::new();
+    if let Some(expires) = expires {
+        let expires_at = Local::now() + expires;
+        limits.push(format!("expires {}", expires_at.format("%Y-%m-%d %H:%M")));
+    }
+    if let Some(max) = max_downloads {
+        limits.push(format!(
+            "{} download{}",
+            max,
+            if max == 1 { "" } else { "s" }
+        ));
+    }
+    if limits.is_empty() {
+        limits.push("no expiry".to_string());
+    }
+
+    log::info!("Shared `{}` ({})", path, limits.join(", "));
+    println!("{}", token);
+    println!();
+    println!(
+        "Download with:  lud --server {} d --token {}",
+        server.addr, token
+    );
+    if let Some(gateway) = gateway {
+        println!(
+            "Or over HTTP:   {}{}?token={}",
+            utils::gateway_url(&gateway, &server.addr),
+            utils::url_path(path.as_str()),
+            token
+        );
+    }
+    Ok(())
+}
+
+pub async fn discover(
+    timeout: Duration,
+    save: bool,
+    config: Option<&Path>,
+    settings: &Settings,
+) -> Result<()> {
+    let found = discovery::discover(timeout).await?;
+    if found.is_empty() {
+        log::info!("No servers found");
+        return Ok(());
+    }
+    utils::pretty_print_discovered(&found);
+
+    if !save {
+        return Ok(());
+    }
+
+    let mut names: HashSet<String> = settings.servers.iter().map(|s| s.name.clone()).collect();
+    for Discovered { server, .. } in found {
+        if settings.servers.iter().any(|s| s.addr == server.addr) {
+            log::info!("Server {} is already configured", server.addr);
+            continue;
         }
-    })
-    .await
+        if !names.insert(server.name.clone()) {
+            log::warn!(
+                "Skipping {}, a server named `{}` already exists",
+                server.addr,
+                server.name
+            );
+            continue;
+        }
+        settings::add_server(config, server.name, server.addr, false, server.tls)?;
+    }
+    Ok(())
 }
 
-async fn with_connection<A, F, Fut>(addr: A, operation: F) -> Result<()>
-where
-    A: ToSocketAddrs,
-    F: FnOnce(Connection) -> Fut,
-    Fut: Future<Output = Result<()>>,
-{
-    let stream = TcpStream::connect(addr)
-        .await
-        .context("Failed to connect to server")?;
+pub async fn df(json: bool, server: &Server) -> Result<()> {
+    let client = Client::connect(server).await?;
+    let space = match client.space().await {
+        Ok(space) => Some(space),
+        // Servers from before volume space was reported don't know the request.
+        Err(lud::Error::Rejected(ErrorCode::Protocol, _)) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let usage = client.usage().await?;
+
+    if json {
+        let report = serde_json::json!({ "volume": space, "quotas": usage });
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        utils::pretty_print_usage(space.as_ref(), &usage);
+    }
+    Ok(())
+}
+
+/// Prints the total size of `path` and the directories below it, children
+/// before their parents as du(1) does.
+pub async fn du(
+    path: Option<Utf8PathBuf>,
+    human_readable: bool,
+    max_depth: Option<u32>,
+    json: bool,
+    server: &Server,
+) -> Result<()> {
+    let path = path.unwrap_or_else(|| "./".into());
+    let mut usage = Client::connect(server)
+        .await?
+        .disk_usage(&path, max_depth)
+        .await?;
+    // The storage root comes back as an empty path.
+    for entry in usage.iter_mut().filter(|entry| entry.path.is_empty()) {
+        entry.path = ".".to_string();
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&usage)?);
+        return Ok(());
+    }
+    for entry in usage {
+        let size = if human_readable {
+            format_size(entry.size, BINARY)
+        } else {
+            entry.size.to_string()
+        };
+        println!("{}\t{}", size, entry.path);
+    }
+    Ok(())
+}
 
-    operation(Connection::new(stream)).await
+pub async fn ping(server: &Server) -> Result<()> {
+    let duration = Client::connect(server).await?.ping().await?;
+    log::info!("Server is online ({:?})", duration);
+    Ok(())
+}
+
+/// The error for a local file that would be overwritten without `--force`,
+/// carrying the code scripts see as the exit status.
+pub fn already_exists(local_path: &Utf8Path) -> anyhow::Error {
+    lud::Error::Io(
+        format!("File `{}` already exists", local_path),
+        io::Error::new(io::ErrorKind::AlreadyExists, "use --force to overwrite"),
+    )
+    .into()
+}
+
+/// The error for a glob that matched nothing, which exits like a missing
+/// file.
+fn no_matches(pattern: &Utf8Path) -> anyhow::Error {
+    lud::Error::Io(
+        format!("No files match `{}`", pattern),
+        io::Error::new(io::ErrorKind::NotFound, "check the pattern"),
+    )
+    .into()
+}
+
+/// Where a file matched by a glob goes below the destination directory: its
+/// path below the pattern's base, or just its name when the pattern has no
+/// directories to strip.
+fn below_base<'a>(path: &'a Utf8Path, base: &Utf8Path) -> &'a Utf8Path {
+    match path.strip_prefix(base) {
+        Ok(relative) if !relative.as_str().is_empty() => relative,
+        _ => path.file_name().map(Utf8Path::new).unwrap_or(path),
+    }
+}
+
+/// A token cancelled on Ctrl-C, so a transfer can tell the server it was
+/// aborted instead of just dropping the connection. A second Ctrl-C exits
+/// right away.
+pub fn abort_on_ctrl_c() -> CancellationToken {
+    let abort = CancellationToken::new();
+    let token = abort.clone();
+    tokio::spawn(async move {
+        if signal::ctrl_c().await.is_err() {
+            return;
+        }
+        log::warn!("Aborting transfer, press Ctrl-C again to exit immediately");
+        token.cancel();
+        if signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    abort
+}
+
+pub fn progress_bar(total_size: u64) -> ProgressBar {
+    let pb = ProgressBar::new(total_size);
+    pb.set_style(
+        ProgressStyle::with_template(PROGRESS_STYLE)
+            .unwrap()
+            .progress_chars(PROGRESS_CHARS),
+    );
+    pb
 }
@@ -0,0 +1,158 @@
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{net::UdpSocket, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::settings::Server;
+
+/// UDP port servers answer discovery queries on.
+pub const PORT: u16 = 4898;
+
+/// How long the server picker waits for answers before showing up.
+pub const PICKER_TIMEOUT: Duration = Duration::from_millis(500);
+
+const QUERY: &[u8] = b"lud-discover";
+
+/// What a server tells clients looking for it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Announcement {
+    /// Tells answers from the same server apart when it is reached on
+    /// several addresses.
+    id: String,
+    name: String,
+    port: u16,
+    version: String,
+    tls: bool,
+}
+
+/// A server that answered a discovery query.
+#[derive(Debug, Clone)]
+pub struct Discovered {
+    pub server: Server,
+    pub version: String,
+}
+
+/// Answers discovery queries on the local network until `shutdown` fires.
+/// Clients reach the server on `port` at the address the answer came from,
+/// so a server only reachable through loopback only answers this machine.
+pub async fn announce(
+    name: String,
+    port: u16,
+    tls: bool,
+    loopback_only: bool,
+    shutdown: CancellationToken,
+) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, PORT)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("Failed to announce server on UDP port {}: {}", PORT, e);
+            return;
+        }
+    };
+    log::info!("Announcing `{}` on UDP port {}", name, PORT);
+
+    let announcement = Announcement {
+        id: rand::rng()
+            .random::<[u8; 8]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+        name,
+        port,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        tls,
+    };
+    let reply = serde_json::to_vec(&announcement).expect("announcement serializes");
+
+    let mut buffer = [0u8; 64];
+    loop {
+        let (len, peer) = tokio::select! {
+            received = socket.recv_from(&mut buffer) => match received {
+                Ok(received) => received,
+                Err(e) => {
+                    log::debug!("Failed to receive discovery query: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => return,
+        };
+
+        if loopback_only && !peer.ip().is_loopback() {
+            continue;
+        }
+        if &buffer[..len] == QUERY
+            && let Err(e) = socket.send_to(&reply, peer).await
+        {
+            log::debug!("Failed to answer discovery query from {}: {}", peer, e);
+        }
+    }
+}
+
+/// Broadcasts a discovery query and collects the servers answering within
+/// `timeout`, sorted by name.
+pub async fn discover(timeout: Duration) -> Result<Vec<Discovered>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .context("Failed to open discovery socket")?;
+    socket
+        .set_broadcast(true)
+        .context("Failed to enable broadcast")?;
+
+    // Broadcasts don't loop back everywhere, so ask this machine directly too.
+    for target in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+        if let Err(e) = socket.send_to(QUERY, (target, PORT)).await {
+            log::debug!("Failed to send discovery query to {}: {}", target, e);
+        }
+    }
+
+    let mut found = BTreeMap::new();
+    let mut buffer = [0u8; 1024];
+    let deadline = Instant::now() + timeout;
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+    {
+        let (len, peer) = received.context("Failed to receive discovery answer")?;
+        let Ok(announcement) = serde_json::from_slice::<Announcement>(&buffer[..len]) else {
+            log::debug!("Ignoring invalid discovery answer from {}", peer);
+            continue;
+        };
+
+        // Prefer the address other machines can use over the loopback one.
+        let addr = SocketAddr::new(peer.ip(), announcement.port);
+        if peer.ip().is_loopback() && found.contains_key(&announcement.id) {
+            continue;
+        }
+        found.insert(
+            announcement.id,
+            Discovered {
+                server: Server {
+                    name: announcement.name,
+                    addr: addr.to_string(),
+                    tls: announcement.tls,
+                    ..Default::default()
+                },
+                version: announcement.version,
+            },
+        );
+    }
+
+    let mut found: Vec<Discovered> = found.into_values().collect();
+    found.sort_by(|a, b| (&a.server.name, &a.server.addr).cmp(&(&b.server.name, &b.server.addr)));
+    Ok(found)
+}
+
+/// Name to announce when none is configured.
+pub fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string())
+}
@@ -0,0 +1,156 @@
+use std::{fmt, io};
+
+use crate::server::ErrorCode;
+
+/// Errors returned by [`Client`](crate::Client) and [`Server`](crate::Server).
+#[derive(Debug)]
+pub enum Error {
+    /// The server couldn't be reached.
+    Connect(String, io::Error),
+    /// The server turned the request down, with its reason.
+    Rejected(ErrorCode, Option<String>),
+    /// The server answered with a packet that doesn't fit the request.
+    UnexpectedResponse(String),
+    /// The file changed on the server before an interrupted download could
+    /// resume.
+    Changed(String),
+    /// A transfer ended with a different size than announced.
+    SizeMismatch { expected: u64, actual: u64 },
+    /// The transfer was aborted through the client's abort token.
+    Aborted,
+    /// A local file, reader or writer failed.
+    Io(String, io::Error),
+    /// The connection broke or timed out.
+    Transport(anyhow::Error),
+    /// TLS couldn't be set up, such as for an untrusted certificate or a
+    /// missing `ca_cert`.
+    Tls(anyhow::Error),
+    /// The peer sent something the protocol doesn't allow, such as a packet
+    /// that can't be decoded or is too large.
+    Protocol(anyhow::Error),
+    /// The server settings are invalid.
+    Config(anyhow::Error),
+    /// The server failed to start or stopped with an error.
+    Server(anyhow::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// What went wrong, for requests the server turned down and local files
+    /// that were missing, already existed or couldn't be accessed.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::Rejected(code, _) => Some(*code),
+            Error::Io(_, e) => match ErrorCode::from_io(e) {
+                ErrorCode::Internal => None,
+                code => Some(code),
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether the error came from the network rather than the request, so
+    /// the request may succeed when tried again.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::Connect(..) | Error::Transport(_) | Error::Rejected(ErrorCode::Unavailable, _)
+        )
+    }
+
+    /// Wraps an error from setting up TLS, keeping it transient when the
+    /// connection broke during the handshake.
+    pub(crate) fn tls(e: anyhow::Error) -> Self {
+        match is_network_error(&e) {
+            true => Error::Transport(e),
+            false => Error::Tls(e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Connect(addr, _) => write!(f, "Failed to connect to `{}`", addr),
+            Error::Rejected(_, Some(message)) => f.write_str(message),
+            Error::Rejected(code, None) => write!(f, "{}", code),
+            Error::UnexpectedResponse(packet) => write!(f, "Unexpected response: {}", packet),
+            Error::Changed(path) => {
+                write!(f, "`{}` changed on the server during the download", path)
+            }
+            Error::SizeMismatch { expected, actual } => write!(
+                f,
+                "File size mismatch (transferred {} of {} bytes)",
+                actual, expected
+            ),
+            Error::Aborted => f.write_str("Transfer aborted"),
+            Error::Io(context, _) => f.write_str(context),
+            Error::Transport(e)
+            | Error::Tls(e)
+            | Error::Protocol(e)
+            | Error::Config(e)
+            | Error::Server(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Connect(_, e) | Error::Io(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        match is_network_error(&e) {
+            true => Error::Transport(e),
+            false => Error::Protocol(e),
+        }
+    }
+}
+
+/// Whether `e` was caused by the connection failing, rather than by a local
+/// file, a certificate or a malformed packet.
+fn is_network_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|e| {
+            !matches!(
+                e.kind(),
+                io::ErrorKind::NotFound
+                    | io::ErrorKind::PermissionDenied
+                    | io::ErrorKind::InvalidData
+                    | io::ErrorKind::InvalidInput
+                    | io::ErrorKind::Unsupported
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, anyhow};
+
+    fn io_error(kind: io::ErrorKind) -> anyhow::Error {
+        Err::<(), _>(io::Error::from(kind))
+            .context("Failed to read packet data")
+            .unwrap_err()
+    }
+
+    #[test]
+    fn retries_only_network_errors() {
+        assert!(Error::from(io_error(io::ErrorKind::UnexpectedEof)).is_transient());
+        assert!(Error::from(io_error(io::ErrorKind::ConnectionReset)).is_transient());
+        assert!(Error::from(io_error(io::ErrorKind::TimedOut)).is_transient());
+
+        assert!(!Error::from(anyhow!("Packet of 9 bytes exceeds limit of 4 bytes")).is_transient());
+        assert!(!Error::from(io_error(io::ErrorKind::InvalidData)).is_transient());
+        assert!(!Error::tls(io_error(io::ErrorKind::NotFound)).is_transient());
+        assert!(!Error::tls(anyhow!("invalid peer certificate: UnknownIssuer")).is_transient());
+        assert!(Error::tls(io_error(io::ErrorKind::ConnectionReset)).is_transient());
+    }
+}
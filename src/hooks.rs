@@ -0,0 +1,52 @@
+use std::{net::SocketAddr, process::Stdio};
+
+use tokio::process::Command;
+
+/// Details passed to a hook command through `LUD_*` environment variables.
+pub struct HookEvent<'a> {
+    pub name: &'static str,
+    pub path: &'a str,
+    pub peer: &'a SocketAddr,
+    pub user: Option<&'a str>,
+    pub size: Option<u64>,
+}
+
+/// Runs `command` through the shell in the background. Failures are logged and
+/// never affect the request that triggered the hook.
+pub fn run(command: Option<&str>, event: HookEvent) {
+    let Some(command) = command else {
+        return;
+    };
+
+    #[cfg(unix)]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    };
+
+    cmd.env("LUD_EVENT", event.name)
+        .env("LUD_PATH", event.path)
+        .env("LUD_PEER", event.peer.to_string())
+        .env("LUD_USER", event.user.unwrap_or_default())
+        .env("LUD_SIZE", event.size.unwrap_or_default().to_string())
+        .stdin(Stdio::null());
+
+    let name = event.name;
+    let command = command.to_string();
+    tokio::spawn(async move {
+        match cmd.status().await {
+            Ok(status) if status.success() => {
+                log::debug!("Hook `{}` finished: {}", name, command);
+            }
+            Ok(status) => log::warn!("Hook `{}` exited with {}: {}", name, status, command),
+            Err(e) => log::warn!("Failed to run hook `{}`: {:#}", name, e),
+        }
+    });
+}
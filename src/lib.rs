@@ -0,0 +1,25 @@
+//! Client and server for lud file transfers.
+//!
+//! [`Client`] talks to a lud server from Rust instead of shelling out to the
+//! CLI, and [`Server`] embeds one in another program.
+
+pub mod client;
+pub mod discovery;
+pub mod error;
+pub mod server;
+pub mod server_settings;
+pub mod settings;
+pub mod utils;
+
+mod audit;
+mod hooks;
+mod mux;
+mod quota;
+mod rate_limit;
+mod share;
+mod telemetry;
+mod tls;
+
+pub use client::{Client, Download};
+pub use error::{Error, Result};
+pub use server::{ErrorCode, Server};
@@ -13,15 +13,32 @@ use tui::{
     widgets::{Block, Borders, List, ListItem},
 };
 
-use crate::settings::Server;
+use lud::{discovery::Discovered, settings::Server};
+
+enum Entry<'a> {
+    /// Labels the servers below it and can't be selected.
+    Heading(&'static str),
+    Configured(&'a Server),
+    Discovered(&'a Discovered),
+}
+
+impl<'a> Entry<'a> {
+    fn server(&self) -> Option<&'a Server> {
+        match self {
+            Entry::Heading(_) => None,
+            Entry::Configured(server) => Some(server),
+            Entry::Discovered(discovered) => Some(&discovered.server),
+        }
+    }
+}
 
 pub struct StatefulList<'a> {
     state: ListState,
-    items: Vec<&'a Server>,
+    items: Vec<Entry<'a>>,
 }
 
 impl<'a> StatefulList<'a> {
-    fn new(items: Vec<&'a Server>) -> StatefulList<'a> {
+    fn new(items: Vec<Entry<'a>>) -> StatefulList<'a> {
         StatefulList {
             state: ListState::default(),
             items,
@@ -29,35 +46,40 @@ impl<'a> StatefulList<'a> {
     }
 
     fn next(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i >= self.items.len() - 1 {
-                    0
-                } else {
-                    i + 1
-                }
+        let mut i = self.state.selected().unwrap_or(self.items.len() - 1);
+        loop {
+            i = if i >= self.items.len() - 1 { 0 } else { i + 1 };
+            if self.items[i].server().is_some() {
+                break;
             }
-            None => 0,
-        };
+        }
         self.state.select(Some(i));
     }
 
     fn previous(&mut self) {
-        let i = match self.state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    self.items.len() - 1
-                } else {
-                    i - 1
-                }
+        let mut i = self.state.selected().unwrap_or(0);
+        loop {
+            i = if i == 0 { self.items.len() - 1 } else { i - 1 };
+            if self.items[i].server().is_some() {
+                break;
             }
-            None => 0,
-        };
+        }
         self.state.select(Some(i));
     }
 }
 
-pub fn select_server_from_list(servers: &[Server]) -> Result<&Server> {
+/// Lets the user pick one of the configured servers or, below them, one of
+/// the servers found on the local network.
+pub fn select_server_from_list<'a>(
+    servers: &'a [Server],
+    discovered: &'a [Discovered],
+) -> Result<&'a Server> {
+    let mut items: Vec<Entry> = servers.iter().map(Entry::Configured).collect();
+    if !discovered.is_empty() {
+        items.push(Entry::Heading("Discovered"));
+        items.extend(discovered.iter().map(Entry::Discovered));
+    }
+
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen)?;
     enable_raw_mode()?;
@@ -66,8 +88,8 @@ pub fn select_server_from_list(servers: &[Server]) -> Result<&Server> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    let mut stateful_list = StatefulList::new(servers.iter().collect());
-    stateful_list.state.select(Some(0));
+    let mut stateful_list = StatefulList::new(items);
+    stateful_list.next();
 
     let result = loop {
         terminal.draw(|f| {
@@ -90,9 +112,17 @@ pub fn select_server_from_list(servers: &[Server]) -> Result<&Server> {
             let items: Vec<ListItem> = stateful_list
                 .items
                 .iter()
-                .map(|server| {
-                    let display_text = format!("{:<20}  {}", server.name, server.addr);
-                    ListItem::new(display_text)
+                .map(|entry| match entry {
+                    Entry::Heading(title) => ListItem::new(format!("── {} ──", title)).style(
+                        tui::style::Style::default().add_modifier(tui::style::Modifier::BOLD),
+                    ),
+                    Entry::Configured(server) => {
+                        ListItem::new(format!("{:<20}  {}", server.name, server.addr))
+                    }
+                    Entry::Discovered(discovered) => ListItem::new(format!(
+                        "{:<20}  {:<24}  v{}",
+                        discovered.server.name, discovered.server.addr, discovered.version
+                    )),
                 })
                 .collect();
 
@@ -114,21 +144,25 @@ pub fn select_server_from_list(servers: &[Server]) -> Result<&Server> {
             f.render_stateful_widget(list, inner_area, &mut stateful_list.state);
         })?;
 
-        if event::poll(std::time::Duration::from_millis(100))? {
-            if let event::Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Esc => {
-                        break Err(anyhow!("Selection cancelled"));
-                    }
-                    KeyCode::Up => stateful_list.previous(),
-                    KeyCode::Down => stateful_list.next(),
-                    KeyCode::Enter => {
-                        if let Some(selected) = stateful_list.state.selected() {
-                            break Ok(stateful_list.items[selected]);
-                        }
+        if event::poll(std::time::Duration::from_millis(100))?
+            && let event::Event::Key(key) = event::read()?
+        {
+            match key.code {
+                KeyCode::Esc => {
+                    break Err(anyhow!("Selection cancelled"));
+                }
+                KeyCode::Up => stateful_list.previous(),
+                KeyCode::Down => stateful_list.next(),
+                KeyCode::Enter => {
+                    if let Some(server) = stateful_list
+                        .state
+                        .selected()
+                        .and_then(|selected| stateful_list.items[selected].server())
+                    {
+                        break Ok(server);
                     }
-                    _ => {}
                 }
+                _ => {}
             }
         }
     };
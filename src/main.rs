@@ -1,29 +1,40 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
+use camino::Utf8Path;
 use clap::Parser as _;
-use cli::{Cli, Command};
+use cli::{Cli, Command, ConfigCommand};
 use list::select_server_from_list;
 use log::LevelFilter;
-use settings::Settings;
-use simplelog::{ColorChoice, ConfigBuilder, TermLogger, TerminalMode};
+use lud::{
+    ErrorCode, discovery,
+    server_settings::{self, ServerOverrides},
+    settings::{self, Server, Settings},
+    utils::HumanDuration,
+};
+use simplelog::{
+    ColorChoice, CombinedLogger, ConfigBuilder, SharedLogger, TermLogger, TerminalMode, WriteLogger,
+};
+use std::fs::OpenOptions;
 
+mod batch;
+mod browse;
 mod cli;
 mod commands;
 mod list;
-mod server;
-mod settings;
-mod utils;
-
-fn init_logger() {
-    let log_level = std::env::var("LUD_LOG").unwrap_or_else(|_| String::from("INFO"));
-
-    let level_filter = match log_level.to_uppercase().as_str() {
-        "OFF" => LevelFilter::Off,
-        "ERROR" => LevelFilter::Error,
-        "WARN" => LevelFilter::Warn,
-        "INFO" => LevelFilter::Info,
-        "DEBUG" => LevelFilter::Debug,
-        "TRACE" => LevelFilter::Trace,
-        _ => LevelFilter::Info,
+mod p2p;
+mod relay;
+
+fn init_logger(default_level: LevelFilter, file: Option<&Utf8Path>) -> Result<()> {
+    let level_filter = match std::env::var("LUD_LOG") {
+        Ok(log_level) => match log_level.to_uppercase().as_str() {
+            "OFF" => LevelFilter::Off,
+            "ERROR" => LevelFilter::Error,
+            "WARN" => LevelFilter::Warn,
+            "INFO" => LevelFilter::Info,
+            "DEBUG" => LevelFilter::Debug,
+            "TRACE" => LevelFilter::Trace,
+            _ => LevelFilter::Info,
+        },
+        Err(_) => default_level,
     };
 
     let config = ConfigBuilder::new()
@@ -33,38 +44,121 @@ fn init_logger() {
         .set_thread_level(LevelFilter::Off)
         .build();
 
-    TermLogger::init(level_filter, config, TerminalMode::Mixed, ColorChoice::Auto).unwrap();
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![TermLogger::new(
+        level_filter,
+        config.clone(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )];
+
+    if let Some(file) = file {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .context(format!("Failed to open log file `{}`", file))?;
+        loggers.push(WriteLogger::new(level_filter, config, file));
+    }
+
+    CombinedLogger::init(loggers).context("Failed to initialize logger")
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    init_logger();
-
     let cli = Cli::parse();
 
-    if let Command::Listen { addr, output } = cli.cmd {
-        return run_or_exit(server::start(addr, output)).await;
+    if let Command::Listen {
+        addr,
+        output,
+        config,
+        metrics,
+        http,
+        webdav,
+        announce,
+    } = cli.cmd
+    {
+        let overrides = ServerOverrides {
+            addr,
+            output,
+            metrics,
+            http,
+            webdav,
+            announce,
+        };
+        let settings = server_settings::load_server_settings(config.as_deref(), overrides);
+        let (level, file) = match &settings {
+            Ok(s) => (s.log_level(), s.logging.file.as_deref()),
+            Err(_) => (None, None),
+        };
+        init_logger(level.unwrap_or(LevelFilter::Info), file)?;
+
+        return run_or_exit(async move { Ok(lud::Server::from_settings(settings?).run().await?) })
+            .await;
+    }
+
+    init_logger(LevelFilter::Info, None)?;
+
+    if let Command::Relay { addr } = cli.cmd {
+        let addr = addr.unwrap_or_else(|| relay::DEFAULT_ADDR.to_string());
+        return run_or_exit(async move { relay::start(&addr).await }).await;
     }
 
-    let settings: Settings = settings::try_load_config_file()?.try_deserialize()?;
-
-    let server = settings
-        .servers
-        .iter()
-        .find(|x| x.default)
-        .or_else(|| {
-            if settings.servers.len() == 1 {
-                Some(&settings.servers[0])
-            } else {
-                select_server_from_list(&settings.servers).ok()
+    let config = cli.config.as_deref();
+
+    if let Command::Config { cmd } = cli.cmd {
+        return run_or_exit(async move {
+            match cmd {
+                ConfigCommand::Init => settings::init_config_file(config),
+                ConfigCommand::Add {
+                    name,
+                    addr,
+                    default,
+                } => settings::add_server(config, name, addr, default, false),
+                ConfigCommand::Remove { name } => settings::remove_server(config, name),
+                ConfigCommand::SetDefault { name } => settings::set_default_server(config, name),
+                ConfigCommand::List => settings::list_servers(config),
+                ConfigCommand::Path => settings::print_config_path(config),
             }
         })
-        .unwrap_or_else(|| {
-            log::error!("No default server found, and no server was selected.");
+        .await;
+    }
+
+    let settings = settings::load_settings(config).unwrap_or_else(|e| {
+        log::error!("{:#}", e);
+        std::process::exit(1);
+    });
+
+    // These commands don't talk to a configured server.
+    match cli.cmd {
+        Command::Discover { timeout, save } => {
+            return run_or_exit(commands::discover(timeout, save, config, &settings)).await;
+        }
+        Command::Send { file, relay } => {
+            return run_or_exit(p2p::send(file, relay.or(settings.relay))).await;
+        }
+        Command::Receive {
+            code,
+            output,
+            force,
+            relay,
+        } => {
+            return run_or_exit(p2p::receive(code, output, force, relay.or(settings.relay))).await;
+        }
+        _ => {}
+    }
+
+    let mut server = select_server(&settings, cli.server.as_deref())
+        .await
+        .unwrap_or_else(|e| {
+            log::error!("{:#}", e);
             std::process::exit(1);
         });
-
-    let addr = server.addr.clone();
+    if let Some(retries) = cli.retries {
+        server.retries = Some(retries);
+    }
+    if let Some(delay) = cli.retry_delay {
+        server.retry_delay = Some(HumanDuration(delay));
+    }
 
     log::debug!("Using server `{}`", server.name);
 
@@ -73,29 +167,175 @@ async fn main() -> Result<()> {
             input,
             output,
             force,
-        } => run_or_exit(commands::download(input, output, force, addr)).await,
+            limit_rate,
+            token,
+            keep_partial,
+        } => match (token, input) {
+            (Some(token), _) => {
+                run_or_exit(commands::download_shared(
+                    token,
+                    output,
+                    force,
+                    limit_rate,
+                    keep_partial,
+                    &server,
+                ))
+                .await
+            }
+            (None, Some(input)) => {
+                run_or_exit(commands::download(
+                    input,
+                    output,
+                    force,
+                    limit_rate,
+                    keep_partial,
+                    &server,
+                ))
+                .await
+            }
+            (None, None) => Ok(()),
+        },
         Command::Upload {
             input,
             output,
             force,
-        } => run_or_exit(commands::upload(input, output, force, addr)).await,
-        Command::List { path } => run_or_exit(commands::list(path, addr)).await,
+            limit_rate,
+        } => run_or_exit(commands::upload(input, output, force, limit_rate, &server)).await,
+        Command::List { path } => run_or_exit(commands::list(path, &server)).await,
+        Command::Find {
+            path,
+            name,
+            size,
+            newer,
+            kind,
+        } => run_or_exit(commands::find(path, name, size, newer, kind, &server)).await,
         Command::Remove {
             path,
             force,
             recursive,
-        } => run_or_exit(commands::remove(path, force, recursive, addr)).await,
-        Command::Ping => run_or_exit(commands::ping(addr)).await,
+            dry_run,
+        } => run_or_exit(commands::remove(path, force, recursive, dry_run, &server)).await,
+        Command::Ping => run_or_exit(commands::ping(&server)).await,
+        Command::Share {
+            path,
+            expires,
+            max_downloads,
+        } => run_or_exit(commands::share(path, expires, max_downloads, &server)).await,
+        Command::Df { json } => run_or_exit(commands::df(json, &server)).await,
+        Command::Du {
+            path,
+            human_readable,
+            max_depth,
+            json,
+            ..
+        } => run_or_exit(commands::du(path, human_readable, max_depth, json, &server)).await,
+        Command::Batch {
+            manifest,
+            from_file,
+            concurrency,
+            continue_on_error,
+        } => {
+            let manifest = manifest.or(from_file).unwrap_or_default();
+            run_or_exit(batch::batch(
+                &manifest,
+                concurrency as usize,
+                continue_on_error,
+                &server,
+            ))
+            .await
+        }
+        Command::Browse { path } => run_or_exit(browse::browse(path, server.clone())).await,
         _ => Ok(()),
     }
 }
 
+const NO_SERVERS: &str =
+    "No servers configured. Add one to `lud.toml`, pass --server <addr> or run `lud discover`";
+
+async fn select_server(settings: &Settings, requested: Option<&str>) -> Result<Server> {
+    if let Some(requested) = requested {
+        if let Some(server) = settings.find_server(requested) {
+            return Ok(server.clone());
+        }
+        if settings::is_server_addr(requested) {
+            return Ok(Server {
+                name: requested.to_string(),
+                addr: requested.to_string(),
+                ..Default::default()
+            });
+        }
+        bail!(
+            "Unknown server `{}` (available: {})",
+            requested,
+            settings.server_names()
+        );
+    }
+
+    if let Some(server) = settings.servers.iter().find(|x| x.default) {
+        return Ok(server.clone());
+    }
+
+    let interactive = atty::is(atty::Stream::Stdin) && atty::is(atty::Stream::Stdout);
+    match settings.servers.len() {
+        0 if !interactive => bail!(NO_SERVERS),
+        1 => Ok(settings.servers[0].clone()),
+        _ if !interactive => bail!(
+            "No default server found and no terminal to select one. Use --server or LUD_SERVER with one of: {}",
+            settings.server_names()
+        ),
+        _ => {
+            // Offer servers on the local network next to the configured ones.
+            let discovered: Vec<_> = discovery::discover(discovery::PICKER_TIMEOUT)
+                .await
+                .unwrap_or_else(|e| {
+                    log::debug!("Server discovery failed: {:#}", e);
+                    Vec::new()
+                })
+                .into_iter()
+                .filter(|d| !settings.servers.iter().any(|s| s.addr == d.server.addr))
+                .collect();
+            if settings.servers.is_empty() && discovered.is_empty() {
+                bail!(NO_SERVERS);
+            }
+
+            select_server_from_list(&settings.servers, &discovered)
+                .cloned()
+                .context("No default server found, and no server was selected.")
+        }
+    }
+}
+
 async fn run_or_exit<F>(fut: F) -> Result<()>
 where
     F: std::future::Future<Output = Result<(), anyhow::Error>>,
 {
     fut.await.map_err(|e| {
         log::error!("{:#}", e);
-        std::process::exit(1);
+        std::process::exit(exit_code(&e));
     })
 }
+
+/// The exit status for a failed command, telling scripts why it failed.
+/// Clap already exits with 2 for usage errors, and aborted transfers exit
+/// with 130 like other programs interrupted with Ctrl-C.
+fn exit_code(e: &anyhow::Error) -> i32 {
+    let Some(e) = e.chain().find_map(|e| e.downcast_ref::<lud::Error>()) else {
+        return 1;
+    };
+    match e {
+        lud::Error::Connect(..) => return 3,
+        lud::Error::Aborted => return 130,
+        _ => {}
+    }
+    match e.code() {
+        Some(ErrorCode::Unauthenticated) => 4,
+        Some(ErrorCode::PermissionDenied) => 5,
+        Some(ErrorCode::NotFound) => 6,
+        Some(ErrorCode::AlreadyExists) => 7,
+        Some(ErrorCode::InvalidPath | ErrorCode::NotAFile) => 8,
+        Some(ErrorCode::DirectoryNotEmpty) => 9,
+        Some(ErrorCode::QuotaExceeded | ErrorCode::TooLarge) => 10,
+        Some(ErrorCode::InvalidToken) => 11,
+        Some(ErrorCode::Protocol | ErrorCode::Internal | ErrorCode::Unavailable) | None => 1,
+    }
+}
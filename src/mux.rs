@@ -0,0 +1,453 @@
+//! Several requests sharing one connection. Once a client sends
+//! `Packet::Multiplex`, every packet travels in a `Packet::Frame` tagged with
+//! the id of the stream it belongs to, and each request gets its own stream.
+//!
+//! Streams take turns writing frames, so a large transfer doesn't hold up a
+//! listing running next to it. A stream may only have `WINDOW` frames in
+//! flight until the peer reads them and grants more with `Packet::Credit`,
+//! so a slow transfer can't make the reading side buffer without bound.
+//! Either side ends a stream early with `Packet::Cancel`, which the server
+//! also sends once it has answered a request, and in place of accepting a
+//! stream beyond the number it allows at once.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use tokio::sync::{Semaphore, mpsc};
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use crate::server::{Connection, Framed, Packet, PacketReader, PacketWriter};
+
+/// Frames a stream may send before the peer grants more credit.
+const WINDOW: u32 = 16;
+
+/// Frames waiting for the writer. Streams wait for a slot in the order they
+/// asked for one, so each gets a turn between the chunks of the others.
+const WRITE_QUEUE: usize = 1;
+
+/// A connection carrying several requests at once. Clones share the
+/// connection, which closes once every clone and stream is dropped.
+#[derive(Clone)]
+pub struct Session {
+    shared: Arc<Shared>,
+    _guard: Arc<DropGuard>,
+}
+
+struct Shared {
+    /// Frames, sent in turns.
+    frames: mpsc::Sender<Packet>,
+    /// Credit, sent ahead of frames so it never waits behind a transfer.
+    control: mpsc::UnboundedSender<Packet>,
+    streams: Mutex<Streams>,
+    next_id: AtomicU32,
+    /// Streams the peer may have open at once.
+    max_streams: usize,
+    /// Cancelled once the connection is gone.
+    closed: CancellationToken,
+}
+
+#[derive(Default)]
+struct Streams {
+    open: HashMap<u32, StreamState>,
+    /// Highest id the peer opened a stream with. Frames for lower ids that
+    /// aren't open belong to streams that already ended.
+    last_accepted: u32,
+    /// Streams refused since one was last accepted.
+    refused: usize,
+    /// Why the connection closed.
+    error: Option<String>,
+}
+
+struct StreamState {
+    inbound: mpsc::UnboundedSender<Packet>,
+    /// Frames received but not read yet, bounded by the window.
+    queued: Arc<AtomicU32>,
+    credit: Arc<Semaphore>,
+    cancel: CancellationToken,
+}
+
+/// One request's share of a session.
+pub struct Stream {
+    id: u32,
+    session: Session,
+    inbound: mpsc::UnboundedReceiver<Packet>,
+    queued: Arc<AtomicU32>,
+    credit: Arc<Semaphore>,
+    cancel: CancellationToken,
+    /// Frames read since credit was last granted.
+    unacknowledged: u32,
+}
+
+impl Session {
+    /// Multiplexes requests the client opens over `conn`.
+    pub fn client(conn: Connection) -> Result<Self> {
+        let (session, _) = Self::start(conn, None, None)?;
+        Ok(session)
+    }
+
+    /// Accepts requests from a client over `conn`, handing out a stream for
+    /// each and refusing streams beyond `max_streams` at once. The connection
+    /// is closed after `idle_timeout` without requests.
+    pub fn server(
+        conn: Connection,
+        idle_timeout: Duration,
+        max_streams: usize,
+    ) -> Result<(Self, mpsc::Receiver<Stream>)> {
+        Self::start(conn, Some(idle_timeout), Some(max_streams))
+    }
+
+    fn start(
+        conn: Connection,
+        idle_timeout: Option<Duration>,
+        max_streams: Option<usize>,
+    ) -> Result<(Self, mpsc::Receiver<Stream>)> {
+        let (reader, writer) = conn.split()?;
+        let (frames, frames_rx) = mpsc::channel(WRITE_QUEUE);
+        let (control, control_rx) = mpsc::unbounded_channel();
+        // Accepted streams count toward the limit until dropped, so they
+        // never outnumber the channel's slots.
+        let (incoming, incoming_rx) = mpsc::channel(max_streams.unwrap_or(1));
+
+        let closed = CancellationToken::new();
+        let shared = Arc::new(Shared {
+            frames,
+            control,
+            streams: Mutex::default(),
+            next_id: AtomicU32::new(1),
+            max_streams: max_streams.unwrap_or(usize::MAX),
+            closed: closed.clone(),
+        });
+        let session = Self {
+            shared: shared.clone(),
+            _guard: Arc::new(closed.drop_guard()),
+        };
+
+        tokio::spawn(write_frames(shared.clone(), writer, frames_rx, control_rx));
+        tokio::spawn(read_frames(
+            session.clone(),
+            reader,
+            idle_timeout,
+            max_streams.map(|_| incoming),
+        ));
+
+        Ok((session, incoming_rx))
+    }
+
+    /// Opens a stream for a new request.
+    pub fn open(&self) -> Result<Stream> {
+        let mut streams = self.shared.streams.lock().unwrap();
+        if let Some(error) = &streams.error {
+            return Err(closed(format!("Connection closed: {}", error)));
+        }
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        Ok(self.register(&mut streams, id))
+    }
+
+    /// Whether the connection is gone, so requests need a new one.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.is_cancelled()
+    }
+
+    fn register(&self, streams: &mut Streams, id: u32) -> Stream {
+        let (inbound, inbound_rx) = mpsc::unbounded_channel();
+        let state = StreamState {
+            inbound,
+            queued: Arc::default(),
+            credit: Arc::new(Semaphore::new(WINDOW as usize)),
+            cancel: CancellationToken::new(),
+        };
+        let stream = Stream {
+            id,
+            session: self.clone(),
+            inbound: inbound_rx,
+            queued: state.queued.clone(),
+            credit: state.credit.clone(),
+            cancel: state.cancel.clone(),
+            unacknowledged: 0,
+        };
+        streams.open.insert(id, state);
+        stream
+    }
+
+    /// Routes a packet from the peer to its stream.
+    fn receive(&self, packet: Packet, incoming: Option<&mpsc::Sender<Stream>>) -> Result<()> {
+        let mut streams = self.shared.streams.lock().unwrap();
+        match packet {
+            Packet::Frame(id, Framed(packet)) => {
+                if let Some(state) = streams.open.get(&id) {
+                    if state.queued.fetch_add(1, Ordering::AcqRel) >= WINDOW {
+                        bail!("Peer sent more than {} frames without credit", WINDOW);
+                    }
+                    let _ = state.inbound.send(*packet);
+                } else if let Some(incoming) = incoming
+                    && id > streams.last_accepted
+                {
+                    streams.last_accepted = id;
+                    if streams.open.len() >= self.shared.max_streams {
+                        // A client is refused a few streams at most before
+                        // its others end, so one that keeps opening them is
+                        // cut off.
+                        streams.refused += 1;
+                        if streams.refused > self.shared.max_streams {
+                            bail!(
+                                "Peer opened streams beyond the limit of {}",
+                                self.shared.max_streams
+                            );
+                        }
+                        let _ = self.shared.control.send(Packet::Cancel(id));
+                        return Ok(());
+                    }
+                    streams.refused = 0;
+
+                    let stream = self.register(&mut streams, id);
+                    stream.queued.fetch_add(1, Ordering::AcqRel);
+                    let _ = streams.open[&id].inbound.send(*packet);
+                    // Dropping a stream takes the lock, so only once it's
+                    // released. One that can't be queued cancels itself.
+                    drop(streams);
+                    let _ = incoming.try_send(stream);
+                }
+            }
+            Packet::Credit(id, credit) => {
+                if let Some(state) = streams.open.get(&id) {
+                    // Never hold more than a window, whatever the peer grants.
+                    let room = (WINDOW as usize).saturating_sub(state.credit.available_permits());
+                    state.credit.add_permits((credit as usize).min(room));
+                }
+            }
+            Packet::Cancel(id) => {
+                if let Some(state) = streams.open.remove(&id) {
+                    state.close();
+                }
+            }
+            other => bail!("Unexpected packet `{}` outside a frame", other),
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    /// Fails every open stream, remembering why the connection closed.
+    fn close(&self, error: String) {
+        let mut streams = self.streams.lock().unwrap();
+        streams.error.get_or_insert(error);
+        for (_, state) in streams.open.drain() {
+            state.close();
+        }
+        self.closed.cancel();
+    }
+}
+
+impl StreamState {
+    /// Wakes the stream's reads and writes, which then fail.
+    fn close(self) {
+        self.cancel.cancel();
+        self.credit.close();
+    }
+}
+
+impl Stream {
+    pub async fn read_packet(&mut self) -> Result<Packet> {
+        let Some(packet) = self.inbound.recv().await else {
+            return Err(self.closed_error());
+        };
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+
+        self.unacknowledged += 1;
+        if self.unacknowledged >= WINDOW / 2 {
+            let _ = self
+                .session
+                .shared
+                .control
+                .send(Packet::Credit(self.id, self.unacknowledged));
+            self.unacknowledged = 0;
+        }
+        Ok(packet)
+    }
+
+    pub async fn write_packet(&mut self, packet: &Packet) -> Result<()> {
+        self.credit
+            .acquire()
+            .await
+            .map_err(|_| self.closed_error())?
+            .forget();
+        self.session
+            .shared
+            .frames
+            .send(Packet::Frame(self.id, Framed(Box::new(packet.clone()))))
+            .await
+            .map_err(|_| self.closed_error())
+    }
+
+    /// Cancelled once the peer cancels the stream or the connection closes.
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Reads until the peer ends the stream.
+    pub async fn finish(&mut self) {
+        while self.read_packet().await.is_ok() {}
+    }
+
+    fn closed_error(&self) -> anyhow::Error {
+        match &self.session.shared.streams.lock().unwrap().error {
+            Some(error) => closed(format!("Connection closed: {}", error)),
+            None => closed("Request was cancelled".to_string()),
+        }
+    }
+}
+
+impl Drop for Stream {
+    /// Tells the peer the stream is over, unless it said so first. The
+    /// cancellation queues behind the stream's frames, so it can't overtake
+    /// them.
+    fn drop(&mut self) {
+        let shared = &self.session.shared;
+        if shared
+            .streams
+            .lock()
+            .unwrap()
+            .open
+            .remove(&self.id)
+            .is_none()
+        {
+            return;
+        }
+        let frames = shared.frames.clone();
+        let id = self.id;
+        tokio::spawn(async move {
+            let _ = frames.send(Packet::Cancel(id)).await;
+        });
+    }
+}
+
+/// An error for a stream whose connection closed, which the client retries
+/// like any other network error.
+fn closed(message: String) -> anyhow::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, message).into()
+}
+
+async fn read_frames(
+    session: Session,
+    mut reader: PacketReader,
+    idle_timeout: Option<Duration>,
+    incoming: Option<mpsc::Sender<Stream>>,
+) {
+    // Only the clones handed out keep the connection open, so hold on to the
+    // session weakly.
+    let shared = session.shared;
+    let guard = Arc::downgrade(&session._guard);
+    drop(session._guard);
+
+    let error = loop {
+        // Transfers can go quiet in one direction, so only time out a
+        // connection with nothing in progress.
+        let idle = shared.streams.lock().unwrap().open.is_empty();
+        let packet = tokio::select! {
+            packet = reader.read_packet(if idle { idle_timeout } else { None }) => packet,
+            _ = shared.closed.cancelled() => break "Connection closed".to_string(),
+        };
+        let Some(guard) = guard.upgrade() else {
+            break "Connection closed".to_string();
+        };
+
+        let session = Session {
+            shared: shared.clone(),
+            _guard: guard,
+        };
+        if let Err(e) = packet.and_then(|packet| session.receive(packet, incoming.as_ref())) {
+            break format!("{:#}", e);
+        }
+    };
+
+    log::debug!("Multiplexed connection ended: {}", error);
+    shared.close(error);
+}
+
+async fn write_frames(
+    shared: Arc<Shared>,
+    mut writer: PacketWriter,
+    mut frames: mpsc::Receiver<Packet>,
+    mut control: mpsc::UnboundedReceiver<Packet>,
+) {
+    loop {
+        let packet = tokio::select! {
+            biased;
+            _ = shared.closed.cancelled() => break,
+            Some(packet) = control.recv() => packet,
+            Some(packet) = frames.recv() => packet,
+        };
+        if let Err(e) = writer.write_packet(&packet).await {
+            shared.close(format!("{:#}", e));
+            break;
+        }
+    }
+    writer.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Framed;
+
+    fn frame(id: u32) -> Packet {
+        Packet::Frame(id, Framed(Box::new(Packet::Ping)))
+    }
+
+    fn server(max_streams: usize) -> (Connection, Session, mpsc::Receiver<Stream>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (session, incoming) = Session::server(
+            Connection::new(server),
+            Duration::from_secs(60),
+            max_streams,
+        )
+        .unwrap();
+        (Connection::new(client), session, incoming)
+    }
+
+    #[tokio::test]
+    async fn refuses_streams_beyond_the_limit() {
+        let (mut client, _session, mut incoming) = server(2);
+        for id in 1..=3 {
+            client.write_packet(&frame(id)).await.unwrap();
+        }
+
+        let first = incoming.recv().await.unwrap();
+        let _second = incoming.recv().await.unwrap();
+        assert!(matches!(
+            client.read_packet().await.unwrap(),
+            Packet::Cancel(3)
+        ));
+
+        // Ending a stream makes room for another.
+        drop(first);
+        assert!(matches!(
+            client.read_packet().await.unwrap(),
+            Packet::Cancel(1)
+        ));
+        client.write_packet(&frame(4)).await.unwrap();
+        assert_eq!(incoming.recv().await.unwrap().id, 4);
+    }
+
+    #[tokio::test]
+    async fn closes_a_session_flooded_with_streams() {
+        let (mut client, _session, mut incoming) = server(1);
+        for id in 1..=3 {
+            client.write_packet(&frame(id)).await.unwrap();
+        }
+
+        // The connection closes, whether or not the first refusal got out.
+        let _stream = incoming.recv().await.unwrap();
+        while let Ok(packet) = client.read_packet().await {
+            assert!(matches!(packet, Packet::Cancel(2)));
+        }
+    }
+}
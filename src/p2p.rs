@@ -0,0 +1,598 @@
+use std::{net::UdpSocket, os::unix::fs::MetadataExt, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, aead::Aead};
+use humansize::{BINARY, format_size};
+use rand::seq::IndexedRandom;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use lud::{
+    server::{Connection, ErrorCode, Packet},
+    utils,
+};
+
+use crate::commands::{self, progress_bar};
+
+/// Mixed into the key exchange so codes can't be replayed against other apps.
+const APP_ID: &[u8] = b"lud-p2p";
+
+/// How long the receiver tries each address the sender offered.
+const DIRECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Words the code is made of, after the nameplate.
+const WORDS: &[&str] = &[
+    "acid", "acorn", "actor", "adobe", "agent", "alarm", "album", "alert", "alley", "amber",
+    "angle", "ankle", "apple", "apron", "arena", "armor", "arrow", "atlas", "attic", "audio",
+    "award", "bacon", "badge", "bagel", "baker", "bamboo", "banjo", "barn", "basil", "batch",
+    "beach", "beard", "bench", "berry", "bison", "blade", "blaze", "bloom", "board", "boat",
+    "bonus", "booth", "boxer", "brain", "brass", "bread", "brick", "bride", "brook", "broom",
+    "brush", "bucket", "bugle", "cabin", "cable", "cactus", "camel", "canal", "candy", "canoe",
+    "canvas", "cargo", "carpet", "castle", "cedar", "chalk", "charm", "chess", "chief", "chimney",
+    "cider", "cinema", "circus", "civic", "clamp", "cliff", "clock", "cloud", "clover", "coach",
+    "cobra", "cocoa", "comet", "coral", "cotton", "couch", "crane", "crater", "crayon", "creek",
+    "crown", "cubic", "curry", "cycle", "daisy", "dance", "delta", "denim", "desert", "diary",
+    "dingo", "disco", "dolphin", "donor", "dragon", "drum", "eagle", "easel", "echo", "elbow",
+    "ember", "emerald", "engine", "epoch", "fable", "falcon", "fern", "ferry", "fiber", "fiddle",
+    "flame", "flint", "flute", "focus", "forest", "fossil", "fox", "frost", "fudge", "galaxy",
+    "garden", "garlic", "gecko", "geyser", "giant", "ginger", "glacier", "globe", "goose", "grape",
+    "gravel", "guitar", "hammer", "harbor", "harp", "hazel", "helmet", "heron", "hippo", "honey",
+    "hornet", "hotel", "husky", "igloo", "index", "ivory", "jacket", "jaguar", "jelly", "jewel",
+    "jungle", "kayak", "kettle", "kiwi", "koala", "ladder", "lagoon", "lemon", "lilac", "linen",
+    "lizard", "lobster", "locket", "lotus", "magnet", "mango", "maple", "marble", "meadow",
+    "melon", "mirror", "mocha", "molar", "monkey", "mosaic", "motor", "muffin", "nectar", "needle",
+    "nickel", "noodle", "novel", "oasis", "ocean", "olive", "onion", "opera", "orbit", "orchid",
+    "otter", "oyster", "paddle", "panda", "paper", "parrot", "peach", "pebble", "pepper", "piano",
+    "pickle", "pillow", "pilot", "pixel", "planet", "plaza", "pocket", "polar", "poppy", "potato",
+    "prism", "pumpkin", "puzzle", "quartz", "quill", "rabbit", "radar", "radio", "raven", "reef",
+    "ribbon", "river", "robin", "rocket", "saddle", "salmon", "sandal", "satin", "scarf", "shadow",
+    "shelf", "signal", "silver", "sketch", "sonic", "spice", "spider", "sponge", "spruce", "squid",
+    "statue", "stove", "sugar", "summit", "sunset", "tablet", "tango",
+];
+
+/// Number of words in a generated code.
+const CODE_WORDS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Sender,
+    Receiver,
+}
+
+/// Encrypts packets between the two peers with the key both derived from the
+/// code. Each direction counts its messages to build unique nonces.
+struct Cipher {
+    aead: ChaCha20Poly1305,
+    side: Side,
+    sent: u64,
+    received: u64,
+}
+
+impl Cipher {
+    /// Runs the key exchange over `conn`. A peer holding a different code
+    /// ends up with a different key, which shows on the first message.
+    async fn negotiate(conn: &mut Connection, code: &str, side: Side) -> Result<Self> {
+        let (spake, outbound) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(code.as_bytes()),
+            &Identity::new(APP_ID),
+        );
+        conn.write_packet(&Packet::Pake(outbound))
+            .await
+            .context("Failed to send key exchange")?;
+
+        let inbound = match conn.read_packet().await? {
+            Packet::Pake(message) => message,
+            Packet::Error(code, message) => bail!(lud::Error::Rejected(code, message)),
+            other => bail!("Unexpected packet: {:?}", other),
+        };
+        let key = spake
+            .finish(&inbound)
+            .map_err(|e| anyhow!("Key exchange failed: {}", e))?;
+
+        Ok(Self {
+            aead: ChaCha20Poly1305::new_from_slice(&key)
+                .map_err(|_| anyhow!("Key exchange produced an invalid key"))?,
+            side,
+            sent: 0,
+            received: 0,
+        })
+    }
+
+    fn nonce(side: Side, counter: u64) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[0] = side as u8;
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce.into()
+    }
+
+    fn seal(&mut self, packet: &Packet) -> Result<Packet> {
+        let plaintext = bincode::serialize(packet).context("Failed to serialize packet")?;
+        let ciphertext = self
+            .aead
+            .encrypt(&Self::nonce(self.side, self.sent), plaintext.as_slice())
+            .map_err(|_| anyhow!("Failed to encrypt packet"))?;
+        self.sent += 1;
+        Ok(Packet::Encrypted(ciphertext))
+    }
+
+    /// Decrypts a packet from the peer. Only a packet that decrypts counts,
+    /// so a stray connection can't knock the nonces out of step.
+    fn open(&mut self, packet: &Packet) -> Result<Packet> {
+        let packet = self.peek(packet)?;
+        self.received += 1;
+        Ok(packet)
+    }
+
+    /// Decrypts the next packet from the peer without counting it, for one
+    /// that may be sent again on another connection.
+    fn peek(&self, packet: &Packet) -> Result<Packet> {
+        let peer = match self.side {
+            Side::Sender => Side::Receiver,
+            Side::Receiver => Side::Sender,
+        };
+        let ciphertext = match packet {
+            Packet::Encrypted(ciphertext) => ciphertext,
+            Packet::Error(code, message) => bail!(lud::Error::Rejected(*code, message.clone())),
+            other => bail!("Unexpected packet: {:?}", other),
+        };
+        let plaintext = self
+            .aead
+            .decrypt(&Self::nonce(peer, self.received), ciphertext.as_slice())
+            .map_err(|_| anyhow!("Failed to decrypt packet from peer, is the code correct?"))?;
+        bincode::deserialize(&plaintext).context("Invalid packet from peer")
+    }
+
+    async fn send(&mut self, conn: &mut Connection, packet: &Packet) -> Result<()> {
+        let sealed = self.seal(packet)?;
+        conn.write_packet(&sealed).await
+    }
+
+    async fn recv(&mut self, conn: &mut Connection) -> Result<Packet> {
+        let packet = conn.read_packet().await?;
+        self.open(&packet)
+    }
+}
+
+/// Sends `local_path` to whoever enters the printed code on `lud receive`.
+pub async fn send(local_path: Utf8PathBuf, relay: Option<String>) -> Result<()> {
+    let relay = relay_addr(relay)?;
+    let metadata = fs::metadata(&local_path)
+        .await
+        .context(format!("Failed to get metadata for `{}`", local_path))?;
+    if !metadata.is_file() {
+        bail!("`{}` is not a file", local_path);
+    }
+    let name = local_path
+        .file_name()
+        .context(format!("`{}` has no file name", local_path))?;
+
+    let mut conn = connect_relay(&relay).await?;
+    conn.write_packet(&Packet::Rendezvous(String::new()))
+        .await
+        .context("Failed to request a code")?;
+    let nameplate = match conn.read_packet().await? {
+        Packet::Rendezvous(nameplate) => nameplate,
+        Packet::Error(code, message) => bail!(lud::Error::Rejected(code, message)),
+        other => bail!("Unexpected response: {:?}", other),
+    };
+
+    let mut rng = rand::rng();
+    let words: Vec<&str> = (0..CODE_WORDS)
+        .map(|_| *WORDS.choose(&mut rng).unwrap())
+        .collect();
+    let code = format!("{}-{}", nameplate, words.join("-"));
+
+    log::info!(
+        "Sending `{}` ({})",
+        local_path,
+        format_size(metadata.len(), BINARY)
+    );
+    println!("{}", code);
+    println!();
+    println!("Receive with:  lud receive {}", code);
+
+    expect_ok(&mut conn)
+        .await
+        .context("Failed waiting for the receiver")?;
+    let mut cipher = Cipher::negotiate(&mut conn, &code, Side::Sender).await?;
+    let mut conn = offer_direct(conn, &mut cipher, &relay).await?;
+
+    send_file(&mut conn, &mut cipher, &local_path, name, &metadata).await?;
+    log::info!(
+        "Successfully sent file `{}` ({})",
+        local_path,
+        format_size(metadata.len(), BINARY)
+    );
+    Ok(())
+}
+
+/// Receives the file offered under `code`, saving it under the sender's file
+/// name unless `local_path` is given.
+pub async fn receive(
+    code: String,
+    local_path: Option<Utf8PathBuf>,
+    force: bool,
+    relay: Option<String>,
+) -> Result<()> {
+    let nameplate = match code.split_once('-') {
+        Some((nameplate, words)) if nameplate.parse::<u32>().is_ok() && !words.is_empty() => {
+            nameplate
+        }
+        _ => bail!("Invalid code `{}` (expected e.g. `7-amber-canoe`)", code),
+    };
+    let relay = relay_addr(relay)?;
+
+    if let Some(local_path) = &local_path
+        && !force
+        && fs::try_exists(local_path).await.unwrap_or(false)
+    {
+        return Err(commands::already_exists(local_path));
+    }
+
+    let mut conn = connect_relay(&relay).await?;
+    conn.write_packet(&Packet::Rendezvous(nameplate.to_string()))
+        .await
+        .context("Failed to send code")?;
+    match conn.read_packet().await? {
+        Packet::Rendezvous(_) => {}
+        Packet::Error(code, message) => bail!(lud::Error::Rejected(code, message)),
+        other => bail!("Unexpected response: {:?}", other),
+    }
+
+    expect_ok(&mut conn).await?;
+    let mut cipher = Cipher::negotiate(&mut conn, &code, Side::Receiver).await?;
+    let hints = match cipher.recv(&mut conn).await? {
+        Packet::Transit(hints) => hints,
+        other => bail!("Unexpected packet: {:?}", other),
+    };
+    let mut conn = accept_direct(conn, &mut cipher, &hints).await?;
+
+    let (local_path, total_size) = receive_file(&mut conn, &mut cipher, local_path, force).await?;
+    log::info!(
+        "Successfully received file `{}` ({})",
+        local_path,
+        format_size(total_size, BINARY)
+    );
+    Ok(())
+}
+
+fn relay_addr(relay: Option<String>) -> Result<String> {
+    relay.context(
+        "No relay configured. Pass --relay <addr>, set LUD_RELAY or add `relay` to `lud.toml`",
+    )
+}
+
+async fn connect_relay(relay: &str) -> Result<Connection> {
+    let stream = TcpStream::connect(relay)
+        .await
+        .context(format!("Failed to connect to relay `{}`", relay))?;
+    Ok(Connection::new(stream))
+}
+
+async fn expect_ok(conn: &mut Connection) -> Result<()> {
+    match conn.read_packet().await? {
+        Packet::Ok => Ok(()),
+        Packet::Error(code, message) => Err(lud::Error::Rejected(code, message).into()),
+        other => Err(anyhow!("Unexpected response: {:?}", other)),
+    }
+}
+
+/// Addresses the receiver may reach a listener on `port` at: the local
+/// address used to reach the relay.
+fn direct_hints(relay: &str, port: u16) -> Vec<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+        socket.connect(relay)?;
+        socket.local_addr()
+    });
+    match socket {
+        Ok(addr) => vec![format!("{}:{}", addr.ip(), port)],
+        Err(e) => {
+            log::debug!("Failed to determine local address: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Offers the receiver a direct connection, keeping the relayed one if the
+/// receiver can't reach it.
+async fn offer_direct(
+    mut relayed: Connection,
+    cipher: &mut Cipher,
+    relay: &str,
+) -> Result<Connection> {
+    let listener = match TcpListener::bind("0.0.0.0:0").await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            log::debug!("Failed to listen for a direct connection: {}", e);
+            None
+        }
+    };
+    let hints = match listener.as_ref().map(TcpListener::local_addr) {
+        Some(Ok(addr)) => direct_hints(relay, addr.port()),
+        _ => Vec::new(),
+    };
+    cipher.send(&mut relayed, &Packet::Transit(hints)).await?;
+
+    let packet = {
+        let relay_read = relayed.read_packet();
+        tokio::pin!(relay_read);
+
+        loop {
+            let accepted = match &listener {
+                Some(listener) => tokio::select! {
+                    packet = &mut relay_read => break packet,
+                    accepted = listener.accept() => accepted,
+                },
+                None => break relay_read.await,
+            };
+            let Ok((stream, addr)) = accepted else {
+                continue;
+            };
+
+            // The receiver proves it holds the key before the connection is
+            // used. It may still give up on the connection and send the same
+            // hello through the relay, so the hello only counts once the
+            // receiver confirms it is staying.
+            let mut direct = Connection::new(stream);
+            let hello = match tokio::time::timeout(DIRECT_TIMEOUT, direct.read_packet()).await {
+                Ok(Ok(packet)) => packet,
+                _ => {
+                    log::debug!("Ignoring direct connection from {}", addr);
+                    continue;
+                }
+            };
+            if !matches!(cipher.peek(&hello), Ok(Packet::Transit(_))) {
+                log::debug!("Ignoring direct connection from {}", addr);
+                continue;
+            }
+            // The receiver either confirms right away or closes the
+            // connection to fall back to the relay.
+            direct.write_packet(&Packet::Ok).await.ok();
+            if let Ok(Packet::Ok) = direct.read_packet().await {
+                cipher.open(&hello)?;
+                log::debug!("Connected directly to {}", addr);
+                return Ok(direct);
+            }
+            log::debug!("Receiver gave up on the direct connection from {}", addr);
+        }
+    };
+
+    let packet = packet.context("Receiver disconnected, was the code mistyped?")?;
+    match cipher.open(&packet)? {
+        Packet::Transit(_) => {
+            log::debug!("Sending through the relay");
+            Ok(relayed)
+        }
+        other => bail!("Unexpected packet: {:?}", other),
+    }
+}
+
+/// Tries the addresses the sender offered, falling back to the relayed
+/// connection when none of them answers. The sender only commits to a direct
+/// connection once the receiver confirms it, so both end up on the same one.
+async fn accept_direct(
+    mut relayed: Connection,
+    cipher: &mut Cipher,
+    hints: &[String],
+) -> Result<Connection> {
+    // The same hello goes out on every attempt, since the sender only ever
+    // reads one of them.
+    let hello = cipher.seal(&Packet::Transit(Vec::new()))?;
+
+    for hint in hints {
+        let attempt = async {
+            let stream = TcpStream::connect(hint).await?;
+            let mut direct = Connection::new(stream);
+            direct.write_packet(&hello).await?;
+            match direct.read_packet().await? {
+                Packet::Ok => Ok(direct),
+                other => Err(anyhow!("Unexpected packet: {:?}", other)),
+            }
+        };
+        // Dropping a connection that timed out tells the sender to stop
+        // waiting for it.
+        match tokio::time::timeout(DIRECT_TIMEOUT, attempt).await {
+            Ok(Ok(mut direct)) => {
+                direct.write_packet(&Packet::Ok).await?;
+                log::debug!("Connected directly to {}", hint);
+                return Ok(direct);
+            }
+            Ok(Err(e)) => log::debug!("Direct connection to {} failed: {:#}", hint, e),
+            Err(_) => log::debug!("Direct connection to {} timed out", hint),
+        }
+    }
+
+    log::debug!("Receiving through the relay");
+    relayed.write_packet(&hello).await?;
+    Ok(relayed)
+}
+
+async fn send_file(
+    conn: &mut Connection,
+    cipher: &mut Cipher,
+    local_path: &Utf8Path,
+    name: &str,
+    metadata: &std::fs::Metadata,
+) -> Result<()> {
+    let total_size = metadata.len();
+    cipher
+        .send(
+            conn,
+            &Packet::UploadStart(name.to_string(), total_size, metadata.mode(), false),
+        )
+        .await
+        .context("Failed to send upload start packet")?;
+    match cipher.recv(conn).await? {
+        Packet::Ok => {}
+        other => bail!("Unexpected response: {:?}", other),
+    }
+
+    let pb = progress_bar(total_size);
+    let mut file = fs::File::open(local_path)
+        .await
+        .context(format!("Failed to open file `{}`", local_path))?;
+    let mut buffer = vec![0u8; utils::optimal_chunk_size(total_size)];
+
+    loop {
+        let bytes_read = file
+            .read(&mut buffer)
+            .await
+            .context(format!("Failed to read file `{}`", local_path))?;
+        if bytes_read == 0 {
+            break;
+        }
+
+        cipher
+            .send(conn, &Packet::UploadChunk(buffer[..bytes_read].to_vec()))
+            .await
+            .context("Failed to send file chunk")?;
+        pb.inc(bytes_read as u64);
+    }
+
+    cipher
+        .send(conn, &Packet::UploadEnd)
+        .await
+        .context("Failed to send upload end packet")?;
+    match cipher.recv(conn).await? {
+        Packet::Ok => {}
+        other => bail!("Unexpected response: {:?}", other),
+    }
+
+    pb.finish_and_clear();
+    Ok(())
+}
+
+async fn receive_file(
+    conn: &mut Connection,
+    cipher: &mut Cipher,
+    local_path: Option<Utf8PathBuf>,
+    force: bool,
+) -> Result<(Utf8PathBuf, u64)> {
+    let (name, total_size, mode) = match cipher.recv(conn).await? {
+        Packet::UploadStart(name, size, mode, _) => (name, size, mode),
+        other => bail!("Unexpected packet: {:?}", other),
+    };
+
+    // Only the file name is taken from the sender, never its directories.
+    let local_path = match local_path {
+        Some(local_path) => local_path,
+        None => {
+            let name = Utf8Path::new(&name).file_name().unwrap_or("received-file");
+            let local_path = Utf8PathBuf::from(name);
+            if !force && fs::try_exists(&local_path).await.unwrap_or(false) {
+                cipher
+                    .send(
+                        conn,
+                        &Packet::Error(
+                            ErrorCode::AlreadyExists,
+                            Some("File already exists on the receiving side".to_string()),
+                        ),
+                    )
+                    .await?;
+                return Err(commands::already_exists(&local_path));
+            }
+            local_path
+        }
+    };
+
+    log::info!(
+        "Receiving `{}` ({})",
+        local_path,
+        format_size(total_size, BINARY)
+    );
+    // Written next to the destination and only moved into place once the
+    // whole file arrived, so a failed or aborted transfer leaves nothing.
+    let partial = PartialFile::new(&local_path);
+    let mut file = fs::File::create(&partial.path)
+        .await
+        .context(format!("Failed to create file `{}`", partial.path))?;
+
+    #[cfg(unix)]
+    {
+        use std::fs::Permissions;
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(Permissions::from_mode(mode))
+            .await
+            .context(format!("Failed to set permissions for `{}`", partial.path))?;
+    }
+
+    cipher.send(conn, &Packet::Ok).await?;
+
+    let abort = commands::abort_on_ctrl_c();
+    let pb = progress_bar(total_size);
+    let mut received_bytes = 0;
+    loop {
+        let packet = tokio::select! {
+            biased;
+            _ = abort.cancelled() => return Err(lud::Error::Aborted.into()),
+            packet = cipher.recv(conn) => packet?,
+        };
+        match packet {
+            Packet::UploadChunk(data) => {
+                received_bytes += data.len() as u64;
+                file.write_all(&data)
+                    .await
+                    .context(format!("Failed to write to file `{}`", local_path))?;
+                pb.set_position(received_bytes);
+            }
+            Packet::UploadEnd => break,
+            other => bail!("Unexpected packet: {:?}", other),
+        }
+    }
+    file.flush()
+        .await
+        .context(format!("Failed to write to file `{}`", local_path))?;
+
+    drop(file);
+
+    if received_bytes != total_size {
+        bail!(
+            "File size mismatch (received {} of {} bytes)",
+            received_bytes,
+            total_size
+        );
+    }
+    partial.persist(&local_path).await?;
+    cipher.send(conn, &Packet::Ok).await?;
+
+    pb.finish_and_clear();
+    Ok((local_path, total_size))
+}
+
+/// A file being received, removed when dropped unless it was moved into
+/// place.
+struct PartialFile {
+    path: Utf8PathBuf,
+    persisted: bool,
+}
+
+impl PartialFile {
+    fn new(destination: &Utf8Path) -> Self {
+        let name = destination.file_name().unwrap_or("received-file");
+        Self {
+            path: destination.with_file_name(format!(".{}.part", name)),
+            persisted: false,
+        }
+    }
+
+    async fn persist(mut self, destination: &Utf8Path) -> Result<()> {
+        fs::rename(&self.path, destination)
+            .await
+            .context(format!("Failed to move received file to `{}`", destination))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.persisted && std::fs::remove_file(&self.path).is_ok() {
+            log::debug!("Removed partial file `{}`", self.path);
+        }
+    }
+}
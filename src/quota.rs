@@ -0,0 +1,571 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::ErrorKind,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, Error, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::fs;
+use walkdir::WalkDir;
+
+use crate::{
+    server::{self, Usage},
+    server_settings::{ServerSettings, User},
+    utils::ByteSize,
+};
+
+/// Index of who uploaded each file, kept in the metadata directory.
+const OWNERS_FILE: &str = "owners.json";
+
+/// A part of the storage a quota applies to.
+#[derive(Debug, Clone)]
+pub enum Scope {
+    Storage,
+    User(String),
+    Path(Utf8PathBuf),
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scope::Storage => f.write_str("storage"),
+            Scope::User(name) => write!(f, "user `{}`", name),
+            Scope::Path(path) => write!(f, "`{}`", path),
+        }
+    }
+}
+
+/// Space claimed by an upload that is still running.
+struct Reservation {
+    path: Utf8PathBuf,
+    user: Option<String>,
+    size: u64,
+}
+
+/// Size of every stored file, so quotas can be checked without walking the
+/// storage on each upload.
+#[derive(Default)]
+struct StoredSizes {
+    files: BTreeMap<String, u64>,
+    total: u64,
+}
+
+impl StoredSizes {
+    /// Sizes of `path` and the files below it.
+    fn below(&self, path: &Utf8Path) -> impl Iterator<Item = (&String, &u64)> {
+        let prefix = match path.as_str() {
+            "" => String::new(),
+            path => format!("{}/", path),
+        };
+        self.files.get_key_value(path.as_str()).into_iter().chain(
+            self.files
+                .range(prefix.clone()..)
+                .take_while(move |(file, _)| file.starts_with(&prefix)),
+        )
+    }
+
+    /// Replaces the sizes of `path` and everything below it with `files`.
+    fn replace(&mut self, path: &Utf8Path, files: Vec<(String, u64)>) {
+        let stale: Vec<_> = self.below(path).map(|(file, _)| file.clone()).collect();
+        for file in stale {
+            self.total -= self.files.remove(&file).unwrap_or(0);
+        }
+        for (file, size) in files {
+            self.total += size;
+            self.files.insert(file, size);
+        }
+    }
+}
+
+/// Tracks storage usage against the configured quotas. Paths are relative to
+/// the storage root.
+///
+/// The storage is scanned once at startup, after which file sizes are kept up
+/// to date through the `record_*` methods. Files changed outside the server
+/// aren't noticed until it restarts.
+pub struct QuotaTracker {
+    root: Utf8PathBuf,
+    /// Uploader of each file.
+    owners: tokio::sync::Mutex<BTreeMap<String, String>>,
+    sizes: tokio::sync::Mutex<StoredSizes>,
+    reservations: Mutex<HashMap<u64, Reservation>>,
+    next_id: AtomicU64,
+    /// Held while checking quotas so concurrent uploads can't claim the same space.
+    check: tokio::sync::Mutex<()>,
+}
+
+/// Releases the space claimed for an upload once it finishes or fails.
+pub struct QuotaReservation<'a> {
+    tracker: &'a QuotaTracker,
+    id: u64,
+}
+
+impl Drop for QuotaReservation<'_> {
+    fn drop(&mut self) {
+        self.tracker.reservations.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl QuotaTracker {
+    pub async fn load(root: &Utf8Path) -> Result<Self> {
+        let path = owners_path(root);
+        let owners = match fs::read(&path).await {
+            Ok(bytes) => {
+                serde_json::from_slice(&bytes).context(format!("Invalid owner index `{}`", path))?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(Error::new(e).context(format!("Failed to read `{}`", path)));
+            }
+        };
+
+        let mut sizes = StoredSizes::default();
+        sizes.replace(Utf8Path::new(""), scan(root, Utf8Path::new("")).await);
+        log::debug!(
+            "Found {} files ({}) in storage",
+            sizes.files.len(),
+            ByteSize(sizes.total)
+        );
+
+        Ok(Self {
+            root: root.to_owned(),
+            owners: tokio::sync::Mutex::new(owners),
+            sizes: tokio::sync::Mutex::new(sizes),
+            reservations: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            check: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Claims `size` bytes for an upload by `user` to `path`, or fails with the
+    /// quota it would exceed. Space used by a file being replaced is not counted.
+    pub async fn reserve(
+        &self,
+        settings: &ServerSettings,
+        path: &Utf8Path,
+        user: Option<&User>,
+        size: u64,
+    ) -> Result<QuotaReservation<'_>> {
+        let _check = self.check.lock().await;
+
+        let replaced = self
+            .sizes
+            .lock()
+            .await
+            .files
+            .get(path.as_str())
+            .copied()
+            .unwrap_or(0);
+        let owner = self.owners.lock().await.get(path.as_str()).cloned();
+
+        for (scope, limit) in applicable_quotas(settings, path, user) {
+            let replaced_in_scope = match &scope {
+                Scope::User(name) if owner.as_ref() != Some(name) => 0,
+                _ => replaced,
+            };
+            let used = self.usage(&scope).await.saturating_sub(replaced_in_scope);
+            if used + size > limit {
+                bail!(
+                    "Quota exceeded for {} (limit is {})",
+                    scope,
+                    ByteSize(limit)
+                );
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.reservations.lock().unwrap().insert(
+            id,
+            Reservation {
+                path: path.to_owned(),
+                user: user.map(|u| u.name.clone()),
+                size,
+            },
+        );
+
+        Ok(QuotaReservation { tracker: self, id })
+    }
+
+    /// Bytes stored in `scope`, including uploads still in progress.
+    pub async fn usage(&self, scope: &Scope) -> u64 {
+        let stored = match scope {
+            Scope::Storage => self.sizes.lock().await.total,
+            Scope::Path(path) => self
+                .sizes
+                .lock()
+                .await
+                .below(path)
+                .map(|(_, size)| size)
+                .sum(),
+            Scope::User(name) => {
+                let owners = self.owners.lock().await;
+                let sizes = self.sizes.lock().await;
+                owners
+                    .iter()
+                    .filter(|(_, owner)| *owner == name)
+                    .filter_map(|(path, _)| sizes.files.get(path))
+                    .sum()
+            }
+        };
+
+        let reserved: u64 = self
+            .reservations
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|reservation| match scope {
+                Scope::Storage => true,
+                Scope::Path(path) => reservation.path.starts_with(path),
+                Scope::User(name) => reservation.user.as_ref() == Some(name),
+            })
+            .map(|reservation| reservation.size)
+            .sum();
+
+        stored + reserved
+    }
+
+    /// Usage of the whole storage, the given user and every path with a quota.
+    pub async fn report(&self, settings: &ServerSettings, user: Option<&User>) -> Vec<Usage> {
+        let mut scopes = vec![(Scope::Storage, settings.quota.max_storage)];
+        if let Some(user) = user {
+            scopes.push((Scope::User(user.name.clone()), user.quota));
+        }
+        for quota in &settings.quota.paths {
+            scopes.push((Scope::Path(quota.path.clone()), Some(quota.max_storage)));
+        }
+
+        let mut report = Vec::new();
+        for (scope, limit) in scopes {
+            report.push(Usage {
+                scope: scope.to_string(),
+                used: self.usage(&scope).await,
+                limit: limit.map(|limit| limit.0),
+            });
+        }
+        report
+    }
+
+    /// Records `user` as the owner of the file uploaded to `path`.
+    pub async fn record_upload(&self, path: &Utf8Path, user: Option<&str>) -> Result<()> {
+        self.refresh(path).await;
+
+        let mut owners = self.owners.lock().await;
+        let changed = match user {
+            Some(user) => {
+                owners.insert(path.to_string(), user.to_string()).as_deref() != Some(user)
+            }
+            None => owners.remove(path.as_str()).is_some(),
+        };
+
+        if changed {
+            self.save(&owners).await?;
+        }
+        Ok(())
+    }
+
+    /// Fails with the path quota moving `size` bytes from `from` to `to` would
+    /// exceed. Storage and user usage don't change when files move.
+    pub async fn check_move(
+        &self,
+        settings: &ServerSettings,
+        from: &Utf8Path,
+        to: &Utf8Path,
+        size: u64,
+    ) -> Result<()> {
+        let _check = self.check.lock().await;
+
+        for quota in &settings.quota.paths {
+            if !to.starts_with(&quota.path) || from.starts_with(&quota.path) {
+                continue;
+            }
+            let scope = Scope::Path(quota.path.clone());
+            if self.usage(&scope).await + size > quota.max_storage.0 {
+                bail!(
+                    "Quota exceeded for {} (limit is {})",
+                    scope,
+                    quota.max_storage
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the owners of the files below `from` after they moved to `to`.
+    pub async fn record_move(&self, from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+        self.refresh(from).await;
+        self.refresh(to).await;
+
+        let mut owners = self.owners.lock().await;
+        owners.retain(|owned, _| !Utf8Path::new(owned).starts_with(to));
+
+        let moved: Vec<_> = owners
+            .keys()
+            .filter(|owned| Utf8Path::new(owned).starts_with(from))
+            .cloned()
+            .collect();
+        for owned in moved {
+            let owner = owners.remove(&owned).unwrap();
+            let relative = Utf8Path::new(&owned).strip_prefix(from).unwrap();
+            let path = if relative.as_str().is_empty() {
+                to.to_owned()
+            } else {
+                to.join(relative)
+            };
+            owners.insert(path.to_string(), owner);
+        }
+
+        self.save(&owners).await
+    }
+
+    /// Charges `user` for every file copied to `path`.
+    pub async fn record_copy(&self, path: &Utf8Path, user: Option<&str>) -> Result<()> {
+        let files = scan(&self.root, path).await;
+
+        let mut owners = self.owners.lock().await;
+        owners.retain(|owned, _| !Utf8Path::new(owned).starts_with(path));
+        if let Some(user) = user {
+            for (file, _) in &files {
+                owners.insert(file.clone(), user.to_string());
+            }
+        }
+        self.sizes.lock().await.replace(path, files);
+
+        self.save(&owners).await
+    }
+
+    /// Forgets the owners of `path` and everything below it.
+    pub async fn record_remove(&self, path: &Utf8Path) -> Result<()> {
+        self.refresh(path).await;
+
+        let mut owners = self.owners.lock().await;
+        let before = owners.len();
+        owners.retain(|owned, _| !Utf8Path::new(owned).starts_with(path));
+
+        if owners.len() != before {
+            self.save(&owners).await?;
+        }
+        Ok(())
+    }
+
+    /// Rereads the sizes of `path` and everything below it from disk.
+    async fn refresh(&self, path: &Utf8Path) {
+        let files = scan(&self.root, path).await;
+        self.sizes.lock().await.replace(path, files);
+    }
+
+    async fn save(&self, owners: &BTreeMap<String, String>) -> Result<()> {
+        let path = owners_path(&self.root);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .context(format!("Failed to create `{}`", parent))?;
+        }
+
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(owners)?)
+            .await
+            .context(format!("Failed to write `{}`", temp))?;
+        fs::rename(&temp, &path)
+            .await
+            .context(format!("Failed to write `{}`", path))
+    }
+}
+
+fn owners_path(root: &Utf8Path) -> Utf8PathBuf {
+    root.join(server::META_DIR).join(OWNERS_FILE)
+}
+
+/// Every quota that limits an upload by `user` to `path`.
+fn applicable_quotas(
+    settings: &ServerSettings,
+    path: &Utf8Path,
+    user: Option<&User>,
+) -> Vec<(Scope, u64)> {
+    let mut quotas = Vec::new();
+    if let Some(max) = settings.quota.max_storage {
+        quotas.push((Scope::Storage, max.0));
+    }
+    if let Some(user) = user
+        && let Some(max) = user.quota
+    {
+        quotas.push((Scope::User(user.name.clone()), max.0));
+    }
+    for quota in &settings.quota.paths {
+        if path.starts_with(&quota.path) {
+            quotas.push((Scope::Path(quota.path.clone()), quota.max_storage.0));
+        }
+    }
+    quotas
+}
+
+/// Sizes of the files at or below `path`, keyed by their path relative to
+/// `root`, skipping metadata and partial uploads.
+async fn scan(root: &Utf8Path, path: &Utf8Path) -> Vec<(String, u64)> {
+    let root = root.to_owned();
+    let dir = root.join(path);
+    tokio::task::spawn_blocking(move || {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0
+                    || !server::is_reserved_name(&entry.file_name().to_string_lossy())
+            })
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| {
+                let size = entry.metadata().ok()?.len();
+                let path = entry.path().strip_prefix(&root).ok()?.to_str()?;
+                Some((path.to_string(), size))
+            })
+            .collect()
+    })
+    .await
+    .unwrap_or_default()
+}
+
+/// Total size of the files below `dir`, skipping metadata and partial uploads.
+pub async fn dir_size(dir: &Utf8Path) -> u64 {
+    let dir = dir.to_owned();
+    tokio::task::spawn_blocking(move || {
+        WalkDir::new(dir)
+            .into_iter()
+            .filter_entry(|entry| {
+                entry.depth() == 0
+                    || !server::is_reserved_name(&entry.file_name().to_string_lossy())
+            })
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    })
+    .await
+    .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_settings::PathQuota;
+
+    fn scopes(quotas: Vec<(Scope, u64)>) -> Vec<(String, u64)> {
+        quotas
+            .into_iter()
+            .map(|(scope, max)| (scope.to_string(), max))
+            .collect()
+    }
+
+    #[test]
+    fn applies_the_quotas_containing_a_path() {
+        let mut settings = ServerSettings::default();
+        settings.quota.max_storage = Some(ByteSize(1000));
+        settings.quota.paths = vec![
+            PathQuota {
+                path: "shared".into(),
+                max_storage: ByteSize(500),
+            },
+            PathQuota {
+                path: "shared/team".into(),
+                max_storage: ByteSize(100),
+            },
+        ];
+        let user = User {
+            name: "alice".to_string(),
+            token: "secret".to_string(),
+            read_only: false,
+            quota: Some(ByteSize(200)),
+        };
+
+        assert_eq!(
+            scopes(applicable_quotas(
+                &settings,
+                Utf8Path::new("shared/team/a.txt"),
+                Some(&user)
+            )),
+            [
+                ("storage".to_string(), 1000),
+                ("user `alice`".to_string(), 200),
+                ("`shared`".to_string(), 500),
+                ("`shared/team`".to_string(), 100),
+            ]
+        );
+        // Path quotas match whole components, not name prefixes.
+        assert_eq!(
+            scopes(applicable_quotas(
+                &settings,
+                Utf8Path::new("shared-old/a.txt"),
+                None
+            )),
+            [("storage".to_string(), 1000)]
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_usage_up_to_date_without_rescanning() {
+        let root = std::env::temp_dir().join(format!("lud-quota-{}", std::process::id()));
+        let root = Utf8PathBuf::from_path_buf(root).unwrap();
+        for dir in ["shared/team", "shared-old", ".lud"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for (file, size) in [
+            ("shared/a", 10),
+            ("shared/team/b", 20),
+            ("shared-old/c", 40),
+            ("shared.txt", 80),
+            (".lud/x", 100),
+        ] {
+            std::fs::write(root.join(file), vec![0; size]).unwrap();
+        }
+        let tracker = QuotaTracker::load(&root).await.unwrap();
+        let shared = Scope::Path("shared".into());
+        let alice = Scope::User("alice".to_string());
+
+        assert_eq!(tracker.usage(&Scope::Storage).await, 150);
+        assert_eq!(tracker.usage(&shared).await, 30);
+
+        // Changes outside the server aren't seen until they're recorded.
+        std::fs::write(root.join("shared/team/b"), vec![0; 25]).unwrap();
+        assert_eq!(tracker.usage(&shared).await, 30);
+        let path = Utf8Path::new("shared/team/b");
+        tracker.record_upload(path, Some("alice")).await.unwrap();
+        assert_eq!(tracker.usage(&Scope::Storage).await, 155);
+        assert_eq!(tracker.usage(&shared).await, 35);
+        assert_eq!(tracker.usage(&alice).await, 25);
+
+        std::fs::rename(root.join("shared/team"), root.join("shared-old/team")).unwrap();
+        let (from, to) = (
+            Utf8Path::new("shared/team"),
+            Utf8Path::new("shared-old/team"),
+        );
+        tracker.record_move(from, to).await.unwrap();
+        assert_eq!(tracker.usage(&shared).await, 10);
+        assert_eq!(tracker.usage(&alice).await, 25);
+
+        std::fs::remove_dir_all(root.join("shared-old")).unwrap();
+        tracker
+            .record_remove(Utf8Path::new("shared-old"))
+            .await
+            .unwrap();
+        assert_eq!(tracker.usage(&Scope::Storage).await, 90);
+        assert_eq!(tracker.usage(&alice).await, 0);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn applies_no_quotas_when_none_are_set() {
+        let settings = ServerSettings::default();
+        let user = User {
+            name: "ci".to_string(),
+            token: "secret".to_string(),
+            read_only: false,
+            quota: None,
+        };
+        assert!(applicable_quotas(&settings, Utf8Path::new("a.txt"), Some(&user)).is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::utils::MAX_CHUNK_SIZE;
+
+/// A token bucket refilled at `rate` bytes per second. Taking more tokens than
+/// are available puts the bucket into debt, so callers sharing one bucket are
+/// slowed down together.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec.max(1) as f64;
+        // Always allow at least one full chunk so slow limits still make progress.
+        let burst = rate.max(MAX_CHUNK_SIZE as f64);
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` tokens, sleeping until the bucket is out of debt.
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst) - bytes as f64;
+            bucket.updated = now;
+
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// All rate limits that apply to one transfer, such as a server-wide and a
+/// per-connection limit.
+#[derive(Clone, Default)]
+pub struct Throttle {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self::default().with(bytes_per_sec.map(|rate| Arc::new(RateLimiter::new(rate))))
+    }
+
+    pub fn with(mut self, limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.limiters.extend(limiter);
+        self
+    }
+
+    pub async fn consume(&self, bytes: u64) {
+        for limiter in &self.limiters {
+            limiter.consume(bytes).await;
+        }
+    }
+}
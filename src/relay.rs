@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use tokio::{net::TcpListener, sync::oneshot};
+
+use lud::server::{Connection, ErrorCode, Packet};
+
+pub const DEFAULT_ADDR: &str = "0.0.0.0:4900";
+
+/// How long a sender waits on its nameplate for the receiver to show up.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How long a new connection has to ask for a nameplate.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Senders waiting for a receiver, keyed by nameplate.
+type Waiting = Arc<Mutex<HashMap<String, oneshot::Sender<Connection>>>>;
+
+/// Pairs senders and receivers by nameplate, then forwards bytes between
+/// them. Peers encrypt end to end, so the relay never sees file contents.
+pub async fn start(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .context(format!("Failed to start relay on {}", addr))?;
+    log::info!("Relay started on {}", addr);
+
+    let waiting: Waiting = Arc::default();
+    loop {
+        let (stream, peer) = listener
+            .accept()
+            .await
+            .context("Failed to accept connection")?;
+        let waiting = waiting.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(Connection::new(stream), peer, waiting).await {
+                log::warn!("Relay connection from {}: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut conn: Connection, peer: SocketAddr, waiting: Waiting) -> Result<()> {
+    let nameplate = match tokio::time::timeout(HANDSHAKE_TIMEOUT, conn.read_packet()).await {
+        Ok(Ok(Packet::Rendezvous(nameplate))) => nameplate,
+        Ok(Ok(other)) => bail!("Unexpected packet: {:?}", other),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => bail!("Timed out waiting for a nameplate"),
+    };
+
+    if nameplate.is_empty() {
+        wait_for_receiver(conn, peer, waiting).await
+    } else {
+        join_sender(conn, peer, &nameplate, waiting).await
+    }
+}
+
+/// Allocates a nameplate for a sender and relays once a receiver claims it.
+async fn wait_for_receiver(mut conn: Connection, peer: SocketAddr, waiting: Waiting) -> Result<()> {
+    let (tx, rx) = oneshot::channel();
+    let nameplate = {
+        let mut waiting = waiting.lock().unwrap();
+        let nameplate = (1..)
+            .map(|n: u32| n.to_string())
+            .find(|n| !waiting.contains_key(n))
+            .unwrap();
+        waiting.insert(nameplate.clone(), tx);
+        nameplate
+    };
+
+    let result = async {
+        conn.write_packet(&Packet::Rendezvous(nameplate.clone()))
+            .await?;
+        log::info!("{} waiting on nameplate {}", peer, nameplate);
+
+        match tokio::time::timeout(WAIT_TIMEOUT, rx).await {
+            Ok(Ok(other)) => Ok(other),
+            Ok(Err(_)) => Err(anyhow!("Receiver left nameplate {}", nameplate)),
+            Err(_) => Err(anyhow!("No receiver joined nameplate {}", nameplate)),
+        }
+    }
+    .await;
+
+    let mut other = match result {
+        Ok(other) => other,
+        Err(e) => {
+            waiting.lock().unwrap().remove(&nameplate);
+            return Err(e);
+        }
+    };
+
+    conn.write_packet(&Packet::Ok).await?;
+    other.write_packet(&Packet::Ok).await?;
+
+    // Relay connections are plain streams, never multiplexed.
+    let (Some(mut sender), Some(mut receiver)) = (conn.into_inner(), other.into_inner()) else {
+        bail!("Relay connections can't be multiplexed");
+    };
+    let (sent, received) = tokio::io::copy_bidirectional(&mut sender, &mut receiver)
+        .await
+        .context(format!("Relay on nameplate {} failed", nameplate))?;
+    log::info!(
+        "Closed nameplate {} ({} bytes relayed)",
+        nameplate,
+        sent + received
+    );
+    Ok(())
+}
+
+/// Hands a receiver over to the sender waiting on `nameplate`.
+async fn join_sender(
+    mut conn: Connection,
+    peer: SocketAddr,
+    nameplate: &str,
+    waiting: Waiting,
+) -> Result<()> {
+    let tx = waiting.lock().unwrap().remove(nameplate);
+    let Some(tx) = tx else {
+        conn.write_packet(&Packet::Error(
+            ErrorCode::NotFound,
+            Some(format!("No sender is waiting on nameplate {}", nameplate)),
+        ))
+        .await?;
+        bail!("Unknown nameplate {}", nameplate);
+    };
+
+    conn.write_packet(&Packet::Rendezvous(nameplate.to_string()))
+        .await?;
+    log::info!("{} joined nameplate {}", peer, nameplate);
+
+    tx.send(conn)
+        .map_err(|_| anyhow!("Sender left nameplate {}", nameplate))
+}
@@ -1,29 +1,66 @@
 use std::{
-    fmt::Display,
+    cell::Cell,
+    collections::HashMap,
+    fmt,
     fs::Permissions,
-    io::ErrorKind,
-    net::SocketAddr,
+    future::Future,
+    io::{self, ErrorKind, SeekFrom},
+    net::{IpAddr, SocketAddr},
     os::unix::fs::{MetadataExt, PermissionsExt},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{Context, Error, Result};
+use anyhow::{Context, Error, Result, anyhow};
 use camino::{Utf8Path, Utf8PathBuf};
-use serde::{Deserialize, Serialize};
+use globset::Glob;
+use serde::{Deserialize, Deserializer, Serialize, de};
 use strum_macros::Display;
 use tokio::{
     fs::{self, OpenOptions},
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream, ToSocketAddrs},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
 };
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use walkdir::WalkDir;
 
-use crate::utils;
+use metrics::{counter, gauge};
+
+use crate::{
+    audit::{AuditEntry, AuditLog},
+    discovery,
+    hooks::{self, HookEvent},
+    mux,
+    quota::{QuotaTracker, Scope},
+    rate_limit::{RateLimiter, Throttle},
+    server_settings::{LimitSettings, ServerSettings, User},
+    share::ShareStore,
+    telemetry::{self, ActiveTransfer},
+    tls, utils,
+};
+
+mod builder;
+mod http;
+mod webdav;
+
+pub use builder::Server;
 
-#[derive(Debug, Display, Serialize, Deserialize)]
+/// How often the storage usage metric is refreshed.
+const STORAGE_METRIC_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Display, Serialize, Deserialize)]
 pub enum Packet {
     Ok,
-    Error(String),
-    DownloadStart(String, u64, u32),
+    Error(ErrorCode, Option<String>),
+    /// Requests carry the path and the offset to start from. Responses carry
+    /// the name, size, permissions and modification time of the file, in
+    /// nanoseconds since the Unix epoch or 0 when unknown.
+    DownloadStart(String, u64, u32, u64),
     DownloadChunk(Vec<u8>),
     DownloadEnd,
     UploadStart(String, u64, u32, bool),
@@ -32,159 +69,1161 @@ pub enum Packet {
     List(String, Vec<File>),
     Remove(String, bool, bool),
     Ping,
+    Auth(String, String),
+    Usage(Vec<Usage>),
+    Share(String, Option<u64>, Option<u32>),
+    ShareToken(String),
+    SharedDownload(String),
+    Rendezvous(String),
+    Pake(Vec<u8>),
+    Encrypted(Vec<u8>),
+    Transit(Vec<String>),
+    /// Asks to run several requests over this connection at once.
+    Multiplex,
+    /// A packet belonging to the multiplexed stream with this id.
+    Frame(u32, Framed),
+    /// Lets the peer send this many more frames on a stream.
+    Credit(u32, u32),
+    /// Ends a stream, abandoning its request if it was still running.
+    Cancel(u32),
+    /// Sent by the client in place of the next chunk to give up on an upload,
+    /// discarding what the server received so far.
+    Abort,
+    /// Expands a glob pattern into the paths matching it, including
+    /// directories when the flag is set.
+    Glob(String, bool, Vec<File>),
+    /// Searches the tree below a path on the server.
+    Find(FindQuery),
+    /// A batch of entries matching a `Find`, followed by more until the
+    /// trailing `Ok`.
+    Found(Vec<File>),
+    /// The total size of every directory below a path, down to the given
+    /// depth below it.
+    DiskUsage(String, Option<u32>, Vec<File>),
+    /// Asks for the space on the storage volume, answered with it filled in.
+    Space(Option<Space>),
+    /// Follows a `ShareToken` when the server runs an HTTP gateway, giving
+    /// its base URL so the client can print a link.
+    HttpGateway(String),
+}
+
+/// The packet inside a `Packet::Frame`. It can't be a frame itself or ask to
+/// multiplex again, which also keeps decoding from recursing without bound
+/// on packets nesting frames.
+#[derive(Debug, Clone, Serialize)]
+#[serde(transparent)]
+pub struct Framed(pub Box<Packet>);
+
+thread_local! {
+    /// Whether a framed packet is being decoded on this thread.
+    static DECODING_FRAME: Cell<bool> = const { Cell::new(false) };
+}
+
+impl<'de> Deserialize<'de> for Framed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if DECODING_FRAME.replace(true) {
+            return Err(de::Error::custom("frames can't be nested"));
+        }
+        let packet = Box::<Packet>::deserialize(deserializer);
+        DECODING_FRAME.set(false);
+
+        match packet? {
+            packet if matches!(*packet, Packet::Multiplex) => {
+                Err(de::Error::custom("can't multiplex within a frame"))
+            }
+            packet => Ok(Framed(packet)),
+        }
+    }
+}
+
+/// Why the server turned a request down. New codes are only ever appended,
+/// since they are sent by index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    /// Credentials are missing or wrong.
+    Unauthenticated,
+    InvalidPath,
+    NotAFile,
+    DirectoryNotEmpty,
+    QuotaExceeded,
+    /// The upload is larger than the server accepts.
+    TooLarge,
+    /// The share token is unknown, expired or used up.
+    InvalidToken,
+    /// The request was malformed or unexpected.
+    Protocol,
+    Internal,
+    /// The server is too busy or shutting down.
+    Unavailable,
+}
+
+impl ErrorCode {
+    /// The code for an I/O error hit while handling a request.
+    pub fn from_io(e: &io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::NotFound => ErrorCode::NotFound,
+            ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            ErrorKind::DirectoryNotEmpty => ErrorCode::DirectoryNotEmpty,
+            ErrorKind::IsADirectory => ErrorCode::NotAFile,
+            _ => ErrorCode::Internal,
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorCode::NotFound => "Path does not exist",
+            ErrorCode::AlreadyExists => "File already exists",
+            ErrorCode::PermissionDenied => "Permission denied",
+            ErrorCode::Unauthenticated => "Authentication failed",
+            ErrorCode::InvalidPath => "Invalid path",
+            ErrorCode::NotAFile => "Not a file",
+            ErrorCode::DirectoryNotEmpty => "Directory not empty",
+            ErrorCode::QuotaExceeded => "Quota exceeded",
+            ErrorCode::TooLarge => "File too large",
+            ErrorCode::InvalidToken => "Invalid or expired share token",
+            ErrorCode::Protocol => "Invalid request",
+            ErrorCode::Internal => "Internal server error",
+            ErrorCode::Unavailable => "Server unavailable",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct File {
     pub path: String,
     pub size: u64,
 }
 
+/// What to search for below `path`. An entry is found when it matches every
+/// criterion given.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FindQuery {
+    pub path: String,
+    /// Glob the entry's name must match, such as `*.log`.
+    pub name: Option<String>,
+    /// Smallest file size in bytes. Size limits never match directories.
+    pub min_size: Option<u64>,
+    /// Largest file size in bytes.
+    pub max_size: Option<u64>,
+    /// Only entries modified less than this many seconds ago, so the
+    /// server's clock decides what is recent.
+    pub newer: Option<u64>,
+    pub kind: Option<FileKind>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileKind {
+    File,
+    Directory,
+}
+
+/// Size of the volume holding the storage root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Space {
+    pub total: u64,
+    /// Bytes the server can still write.
+    pub available: u64,
+}
+
+/// Storage used within one quota scope.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Usage {
+    pub scope: String,
+    pub used: u64,
+    pub limit: Option<u64>,
+}
+
+/// A byte stream packets can be exchanged over, either plain TCP or TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Largest packet a client accepts. Listings of big trees can be large, so
+/// this is well above what servers accept by default.
+const DEFAULT_MAX_PACKET_SIZE: usize = 256 * 1024 * 1024;
+
 pub struct Connection {
-    stream: TcpStream,
+    transport: Transport,
+    max_packet_size: usize,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+}
+
+enum Transport {
+    Stream(Box<dyn Stream>),
+    /// One of several requests sharing a connection.
+    Multiplexed(mux::Stream),
 }
 
 impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
-        Self { stream }
+    pub fn new<S: Stream + 'static>(stream: S) -> Self {
+        Self::with_transport(Transport::Stream(Box::new(stream)))
     }
 
-    pub async fn read_packet(&mut self) -> Result<Packet> {
-        let mut len_bytes = [0u8; 4];
-        self.stream
-            .read_exact(&mut len_bytes)
-            .await
-            .context("Failed to read length prefix")?;
-
-        let len = u32::from_be_bytes(len_bytes) as usize;
+    pub(crate) fn multiplexed(stream: mux::Stream) -> Self {
+        Self::with_transport(Transport::Multiplexed(stream))
+    }
 
-        let mut buffer = vec![0u8; len];
-        self.stream
-            .read_exact(&mut buffer)
-            .await
-            .context("Failed to read packet data")?;
+    fn with_transport(transport: Transport) -> Self {
+        Self {
+            transport,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            read_timeout: None,
+            write_timeout: None,
+            idle_timeout: None,
+        }
+    }
 
-        let packet: Packet =
-            bincode::deserialize(&buffer).context("Failed to deserialize packet")?;
+    fn with_limits(mut self, limits: &LimitSettings) -> Self {
+        self.max_packet_size = usize::try_from(limits.max_packet_size.0).unwrap_or(usize::MAX);
+        self.read_timeout = Some(limits.read_timeout.0);
+        self.write_timeout = Some(limits.write_timeout.0);
+        self.idle_timeout = Some(limits.idle_timeout.0);
+        self
+    }
 
-        Ok(packet)
+    pub async fn read_packet(&mut self) -> Result<Packet> {
+        match &mut self.transport {
+            Transport::Stream(stream) => {
+                read_packet(
+                    stream,
+                    self.max_packet_size,
+                    self.idle_timeout,
+                    self.read_timeout,
+                )
+                .await
+            }
+            Transport::Multiplexed(stream) => match self.idle_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, stream.read_packet())
+                    .await
+                    .map_err(|_| io::Error::from(ErrorKind::TimedOut))
+                    .context("Failed to read packet")?,
+                None => stream.read_packet().await,
+            },
+        }
     }
 
     pub async fn write_packet(&mut self, packet: &Packet) -> Result<()> {
-        let bytes = bincode::serialize(packet).context("Failed to serialize packet")?;
+        match &mut self.transport {
+            Transport::Stream(stream) => write_packet(stream, packet, self.write_timeout).await,
+            Transport::Multiplexed(stream) => match self.write_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, stream.write_packet(packet))
+                    .await
+                    .map_err(|_| io::Error::from(ErrorKind::TimedOut))
+                    .context("Failed to write packet")?,
+                None => stream.write_packet(packet).await,
+            },
+        }
+    }
 
-        let len = bytes.len() as u32;
-        self.stream
-            .write_all(&len.to_be_bytes())
-            .await
-            .context("Failed to write length prefix")?;
+    /// Reads whatever is left of the answer to a multiplexed request, so the
+    /// request ends instead of being cancelled. Other connections close once
+    /// the request is done anyway.
+    pub async fn finish(&mut self) {
+        if let Transport::Multiplexed(stream) = &mut self.transport {
+            stream.finish().await;
+        }
+    }
 
-        self.stream
-            .write_all(&bytes)
-            .await
-            .context("Failed to write packet data")?;
+    /// Cancelled once the client cancels a multiplexed request.
+    fn cancel_token(&self) -> CancellationToken {
+        match &self.transport {
+            Transport::Stream(_) => CancellationToken::new(),
+            Transport::Multiplexed(stream) => stream.cancel_token(),
+        }
+    }
 
-        Ok(())
+    /// Splits the connection so packets can be read and written at once.
+    pub(crate) fn split(self) -> Result<(PacketReader, PacketWriter)> {
+        let Transport::Stream(stream) = self.transport else {
+            anyhow::bail!("Connection is already multiplexed");
+        };
+        let (reader, writer) = tokio::io::split(stream);
+        Ok((
+            PacketReader {
+                stream: reader,
+                max_packet_size: self.max_packet_size,
+                read_timeout: self.read_timeout,
+            },
+            PacketWriter {
+                stream: writer,
+                write_timeout: self.write_timeout,
+            },
+        ))
+    }
+
+    /// The underlying stream, for forwarding raw bytes once packets are done.
+    /// Multiplexed requests share theirs, so they have none.
+    pub fn into_inner(self) -> Option<Box<dyn Stream>> {
+        match self.transport {
+            Transport::Stream(stream) => Some(stream),
+            Transport::Multiplexed(_) => None,
+        }
     }
 
     pub async fn shutdown(&mut self) {
+        if let Transport::Stream(stream) = &mut self.transport {
+            let _ = stream.shutdown().await;
+        }
+    }
+}
+
+/// The reading half of a split connection.
+pub(crate) struct PacketReader {
+    stream: ReadHalf<Box<dyn Stream>>,
+    max_packet_size: usize,
+    read_timeout: Option<Duration>,
+}
+
+impl PacketReader {
+    pub(crate) async fn read_packet(&mut self, idle_timeout: Option<Duration>) -> Result<Packet> {
+        read_packet(
+            &mut self.stream,
+            self.max_packet_size,
+            idle_timeout,
+            self.read_timeout,
+        )
+        .await
+    }
+}
+
+/// The writing half of a split connection.
+pub(crate) struct PacketWriter {
+    stream: WriteHalf<Box<dyn Stream>>,
+    write_timeout: Option<Duration>,
+}
+
+impl PacketWriter {
+    pub(crate) async fn write_packet(&mut self, packet: &Packet) -> Result<()> {
+        write_packet(&mut self.stream, packet, self.write_timeout).await
+    }
+
+    pub(crate) async fn shutdown(&mut self) {
         let _ = self.stream.shutdown().await;
     }
 }
 
-pub async fn start<A: ToSocketAddrs + Display>(addr: A, output_path: Utf8PathBuf) -> Result<()> {
-    match fs::create_dir_all(&output_path).await {
+async fn read_packet<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    max_packet_size: usize,
+    idle_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+) -> Result<Packet> {
+    let mut len_bytes = [0u8; 4];
+    with_timeout(idle_timeout, stream.read_exact(&mut len_bytes))
+        .await
+        .context("Failed to read length prefix")?;
+
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if len > max_packet_size {
+        anyhow::bail!(
+            "Packet of {} bytes exceeds limit of {} bytes",
+            len,
+            max_packet_size
+        );
+    }
+
+    let mut buffer = vec![0u8; len];
+    with_timeout(read_timeout, stream.read_exact(&mut buffer))
+        .await
+        .context("Failed to read packet data")?;
+
+    let packet: Packet = bincode::deserialize(&buffer).context("Failed to deserialize packet")?;
+
+    Ok(packet)
+}
+
+async fn write_packet<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    packet: &Packet,
+    write_timeout: Option<Duration>,
+) -> Result<()> {
+    let len = bincode::serialized_size(packet).context("Failed to serialize packet")? as u32;
+
+    // One write per packet, so small packets aren't held back waiting for the
+    // peer to acknowledge their length prefix.
+    let mut bytes = Vec::with_capacity(4 + len as usize);
+    bytes.extend_from_slice(&len.to_be_bytes());
+    bincode::serialize_into(&mut bytes, packet).context("Failed to serialize packet")?;
+
+    with_timeout(write_timeout, stream.write_all(&bytes))
+        .await
+        .context("Failed to write packet")?;
+
+    Ok(())
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into())),
+        None => fut.await,
+    }
+}
+
+struct ServerState {
+    settings: ServerSettings,
+    tls: Option<TlsAcceptor>,
+    /// Cancelled on the first shutdown signal to stop accepting connections.
+    shutdown: CancellationToken,
+    /// Cancelled once the grace period is over to abort remaining requests.
+    abort: CancellationToken,
+    connections: TaskTracker,
+    counts: Mutex<ConnectionCounts>,
+    /// Shared by all connections when `limits.rate_limit` is set.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    quota: QuotaTracker,
+    shares: Arc<ShareStore>,
+    audit: Option<AuditLog>,
+}
+
+impl ServerState {
+    /// State for tests that drive the handlers without binding listeners.
+    #[cfg(test)]
+    async fn for_tests(settings: ServerSettings) -> Arc<Self> {
+        Arc::new(ServerState {
+            tls: None,
+            shutdown: CancellationToken::new(),
+            abort: CancellationToken::new(),
+            connections: TaskTracker::new(),
+            counts: Mutex::new(ConnectionCounts::default()),
+            rate_limiter: None,
+            quota: QuotaTracker::load(&settings.storage).await.unwrap(),
+            shares: Arc::new(ShareStore::load(&settings.storage).await.unwrap()),
+            audit: None,
+            settings,
+        })
+    }
+}
+
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts an accepted connection toward the server limits until dropped.
+struct ConnectionSlot {
+    state: Arc<ServerState>,
+    ip: IpAddr,
+}
+
+impl ConnectionSlot {
+    /// Reserves a slot for a connection from `ip`, or returns why it was refused.
+    fn acquire(state: &Arc<ServerState>, ip: IpAddr) -> Result<Self, &'static str> {
+        let limits = &state.settings.limits;
+        let mut counts = state.counts.lock().unwrap();
+
+        if counts.total >= limits.max_connections {
+            return Err("connection limit reached");
+        }
+        let per_ip = counts.per_ip.entry(ip).or_default();
+        if *per_ip >= limits.max_connections_per_ip {
+            return Err("per-IP connection limit reached");
+        }
+
+        *per_ip += 1;
+        counts.total += 1;
+        gauge!("lud_connections_active").increment(1.0);
+        Ok(Self {
+            state: state.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.state.counts.lock().unwrap();
+        counts.total -= 1;
+        gauge!("lud_connections_active").decrement(1.0);
+        if let Some(per_ip) = counts.per_ip.get_mut(&self.ip) {
+            *per_ip -= 1;
+            if *per_ip == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Runs the server until `shutdown` completes, then drains connections. With
+/// `signals`, a shutdown signal during the drain exits the process.
+async fn serve<F>(settings: ServerSettings, shutdown: F, signals: bool) -> Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let tls = match &settings.tls {
+        Some(tls) => Some(tls::acceptor(&tls.cert, &tls.key)?),
+        None => None,
+    };
+
+    match fs::create_dir_all(&settings.storage).await {
         Ok(()) => {}
         Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
         Err(e) => return Err(Error::new(e).context("Failed to create output path")),
     }
 
-    let listener = TcpListener::bind(&addr)
-        .await
-        .context("Failed to start server")?;
+    let mut listeners = Vec::new();
+    for addr in &settings.listen {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context(format!("Failed to start server on {}", addr))?;
+        listeners.push((addr.clone(), listener));
+    }
 
-    log::info!("Server started on {}", addr);
+    if let Some(addr) = &settings.metrics {
+        telemetry::install(addr)?;
+    }
 
-    loop {
-        let (stream, addr) = listener
-            .accept()
+    let quota = QuotaTracker::load(&settings.storage).await?;
+    let shares = Arc::new(ShareStore::load(&settings.storage).await?);
+    let audit = match &settings.audit.file {
+        Some(path) => Some(AuditLog::open(path, &settings.audit).await?),
+        None => None,
+    };
+
+    let state = Arc::new(ServerState {
+        tls,
+        shutdown: CancellationToken::new(),
+        abort: CancellationToken::new(),
+        connections: TaskTracker::new(),
+        counts: Mutex::new(ConnectionCounts::default()),
+        rate_limiter: settings
+            .limits
+            .rate_limit
+            .map(|rate| Arc::new(RateLimiter::new(rate.0))),
+        quota,
+        shares,
+        audit,
+        settings,
+    });
+
+    if state.settings.metrics.is_some() {
+        tokio::spawn(update_storage_metric(state.clone()));
+    }
+
+    if state.settings.announce {
+        let addr = &state.settings.listen[0];
+        let (host, port) = addr.rsplit_once(':').unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let loopback_only =
+            host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+        if loopback_only {
+            log::warn!(
+                "Announcing {}, which other machines can't reach. Listen on 0.0.0.0 to be discoverable",
+                addr
+            );
+        }
+        let name = state
+            .settings
+            .name
+            .clone()
+            .unwrap_or_else(discovery::hostname);
+        tokio::spawn(discovery::announce(
+            name,
+            port.parse().unwrap_or_default(),
+            state.tls.is_some(),
+            loopback_only,
+            state.shutdown.clone(),
+        ));
+    }
+
+    let mut tasks = JoinSet::new();
+    for (addr, listener) in listeners {
+        if state.tls.is_some() {
+            log::info!("Server started on {} (TLS)", addr);
+        } else {
+            log::info!("Server started on {}", addr);
+        }
+        tasks.spawn(accept_connections(
+            listener,
+            state.clone(),
+            Protocol::Native,
+        ));
+    }
+
+    if let Some(addr) = &state.settings.http.listen {
+        let listener = TcpListener::bind(addr)
             .await
-            .context("Failed to accept connection")?;
+            .context(format!("Failed to start HTTP gateway on {}", addr))?;
+        let scheme = if state.tls.is_some() { "https" } else { "http" };
+        log::info!("Serving HTTP on {}://{}", scheme, addr);
+        tasks.spawn(accept_connections(listener, state.clone(), Protocol::Http));
+    }
 
-        log::info!("Accepted connection from {}", addr);
+    if let Some(addr) = &state.settings.webdav {
+        let listener = TcpListener::bind(addr)
+            .await
+            .context(format!("Failed to start WebDAV server on {}", addr))?;
+        let scheme = if state.tls.is_some() { "https" } else { "http" };
+        log::info!("Serving WebDAV on {}://{}", scheme, addr);
+        tasks.spawn(accept_connections(
+            listener,
+            state.clone(),
+            Protocol::WebDav,
+        ));
+    }
+
+    tokio::select! {
+        Some(result) = tasks.join_next() => {
+            result.context("Listener task failed")??;
+        }
+        _ = shutdown => {}
+    }
+
+    drain_connections(&state, signals).await;
+    Ok(())
+}
 
-        let output_path = output_path.clone();
-        tokio::spawn(async move {
-            handle_connection(stream, addr, output_path).await;
+/// Stops accepting connections and waits for active ones to finish. Requests
+/// still running after the grace period are aborted, and with `signals` a
+/// second signal exits immediately.
+async fn drain_connections(state: &ServerState, signals: bool) {
+    state.shutdown.cancel();
+    state.connections.close();
+
+    let grace_period = state.settings.shutdown.grace_period.0;
+    if !state.connections.is_empty() {
+        log::info!(
+            "Shutting down, waiting up to {:?} for {} active connection(s) (signal again to force)",
+            grace_period,
+            state.connections.len()
+        );
+    }
+
+    tokio::select! {
+        _ = state.connections.wait() => {}
+        _ = tokio::time::sleep(grace_period) => {
+            log::warn!(
+                "Grace period elapsed, aborting {} active connection(s)",
+                state.connections.len()
+            );
+            state.abort.cancel();
+            state.connections.wait().await;
+        }
+        _ = shutdown_signal(), if signals => {
+            log::warn!("Forcing shutdown with {} active connection(s)", state.connections.len());
+            std::process::exit(130);
+        }
+    }
+
+    log::info!("Server stopped");
+}
+
+async fn update_storage_metric(state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(STORAGE_METRIC_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = state.shutdown.cancelled() => return,
+        }
+        let used = state.quota.usage(&Scope::Storage).await;
+        gauge!("lud_storage_used_bytes").set(used as f64);
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to listen for SIGTERM: {:#}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// What an accepted connection speaks.
+#[derive(Clone, Copy)]
+enum Protocol {
+    Native,
+    Http,
+    WebDav,
+}
+
+async fn accept_connections(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+    protocol: Protocol,
+) -> Result<()> {
+    loop {
+        let (stream, addr) = tokio::select! {
+            result = listener.accept() => result.context("Failed to accept connection")?,
+            _ = state.shutdown.cancelled() => return Ok(()),
+        };
+
+        let slot = match ConnectionSlot::acquire(&state, addr.ip()) {
+            Ok(slot) => slot,
+            Err(reason) => {
+                log::warn!("Refused connection from {}: {}", addr, reason);
+                counter!("lud_connections_refused_total").increment(1);
+                continue;
+            }
+        };
+
+        log::info!("Accepted connection from {}", addr);
+        counter!("lud_connections_total").increment(1);
+
+        let state = state.clone();
+        state.connections.clone().spawn(async move {
+            if let Some(stream) = accept_stream(&state, stream, addr).await {
+                match protocol {
+                    Protocol::Native => handle_connection(stream, addr, state).await,
+                    Protocol::Http => http::handle_connection(stream, addr, state, false).await,
+                    Protocol::WebDav => http::handle_connection(stream, addr, state, true).await,
+                }
+            }
+            drop(slot);
         });
     }
 }
 
-async fn handle_connection(stream: TcpStream, addr: SocketAddr, output_path: Utf8PathBuf) {
-    let mut conn = Connection::new(stream);
+/// Performs the TLS handshake when TLS is configured.
+async fn accept_stream(
+    state: &ServerState,
+    stream: TcpStream,
+    addr: SocketAddr,
+) -> Option<Box<dyn Stream>> {
+    // Answers are often several small packets in a row, which shouldn't wait
+    // for the client to acknowledge the first.
+    if let Err(e) = stream.set_nodelay(true) {
+        log::debug!("Failed to disable Nagle's algorithm for {}: {}", addr, e);
+    }
+
+    let Some(acceptor) = &state.tls else {
+        return Some(Box::new(stream));
+    };
+
+    let handshake_timeout = Some(state.settings.limits.read_timeout.0);
+    match with_timeout(handshake_timeout, acceptor.accept(stream)).await {
+        Ok(stream) => Some(Box::new(stream)),
+        Err(e) => {
+            log::error!("TLS handshake with {} failed: {:#}", addr, e);
+            counter!("lud_errors_total", "kind" => "tls").increment(1);
+            None
+        }
+    }
+}
+
+async fn handle_connection(stream: Box<dyn Stream>, addr: SocketAddr, state: Arc<ServerState>) {
+    let mut conn = Connection::new(stream).with_limits(&state.settings.limits);
 
-    async {
-        let packet = match conn.read_packet().await {
-            Ok(p) => p,
+    let first = tokio::select! {
+        first = read_authenticated(&mut conn, addr, &state) => first,
+        _ = state.abort.cancelled() => None,
+    };
+
+    if let Some((packet, user)) = first {
+        if let Packet::Multiplex = packet {
+            send_ok(&mut conn).await;
+            serve_session(conn, addr, state.clone(), user.cloned()).await;
+            return;
+        }
+        let throttle = connection_throttle(&state);
+        handle_request(&mut conn, packet, addr, &state, user, &throttle).await;
+    }
+
+    shutdown_connection(&mut conn, &addr).await;
+}
+
+/// Reads the first request, checking the credentials sent ahead of it.
+/// Returns the request and the user it comes from.
+async fn read_authenticated<'a>(
+    conn: &mut Connection,
+    addr: SocketAddr,
+    state: &'a ServerState,
+) -> Option<(Packet, Option<&'a User>)> {
+    let settings = &state.settings;
+    let mut packet = read_request(conn).await?;
+
+    let mut user: Option<&User> = None;
+    if let Packet::Auth(name, token) = &packet {
+        if settings.users.is_empty() {
+            log::debug!("Ignoring credentials from {}, no users configured", addr);
+        } else if let Some(authenticated) = settings.authenticate(name, token) {
+            user = Some(authenticated);
+        } else {
+            send_error(
+                conn,
+                ErrorCode::Unauthenticated,
+                Some("Invalid credentials"),
+            )
+            .await;
+            log::warn!("Failed login as `{}` from {}", name, addr);
+            counter!("lud_errors_total", "kind" => "auth").increment(1);
+            let entry = AuditEntry::new(addr, Some(name), "auth", None);
+            audit(state, entry, None, Some("Invalid credentials")).await;
+            return None;
+        }
+
+        send_ok(conn).await;
+        packet = read_request(conn).await?;
+    }
+
+    Some((packet, user))
+}
+
+/// Serves requests multiplexed over one connection until the client closes
+/// it, running up to `limits.max_streams` of them at once.
+async fn serve_session(
+    conn: Connection,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    user: Option<User>,
+) {
+    let limits = &state.settings.limits;
+    let (session, mut incoming) =
+        match mux::Session::server(conn, limits.idle_timeout.0, limits.max_streams) {
+            Ok(session) => session,
             Err(e) => {
-                send_error(&mut conn, "Failed to read packet").await;
-                log::error!("Failed to read packet: {:#}", e);
+                log::error!("Failed to multiplex connection from {}: {:#}", addr, e);
                 return;
             }
         };
+    log::debug!("Multiplexing requests from {}", addr);
+
+    let user = user.map(Arc::new);
+    let throttle = connection_throttle(&state);
+    let mut requests = JoinSet::new();
+    loop {
+        let stream = tokio::select! {
+            stream = incoming.recv() => match stream {
+                Some(stream) => stream,
+                None => break,
+            },
+            Some(_) = requests.join_next() => continue,
+            // Let running requests finish, then close the connection.
+            _ = state.shutdown.cancelled(), if requests.is_empty() => break,
+        };
+
+        let rejection = if state.shutdown.is_cancelled() {
+            Some("Server is shutting down")
+        } else if requests.len() >= limits.max_streams {
+            Some("Too many requests at once")
+        } else {
+            None
+        };
+
+        let mut conn = Connection::multiplexed(stream).with_limits(limits);
+        let state = state.clone();
+        let user = user.clone();
+        let throttle = throttle.clone();
+        requests.spawn(async move {
+            let Some(packet) = read_request(&mut conn).await else {
+                return;
+            };
+            if let Some(reason) = rejection {
+                send_error(&mut conn, ErrorCode::Unavailable, Some(reason)).await;
+                return;
+            }
+            handle_request(&mut conn, packet, addr, &state, user.as_deref(), &throttle).await;
+        });
+    }
+
+    while requests.join_next().await.is_some() {}
+    drop(session);
+    log::info!("Closed connection from {}", addr);
+}
+
+/// Limits shared by all transfers on one connection.
+fn connection_throttle(state: &ServerState) -> Throttle {
+    Throttle::new(
+        state
+            .settings
+            .limits
+            .connection_rate_limit
+            .map(|rate| rate.0),
+    )
+    .with(state.rate_limiter.clone())
+}
+
+async fn handle_request(
+    conn: &mut Connection,
+    packet: Packet,
+    addr: SocketAddr,
+    state: &ServerState,
+    user: Option<&User>,
+    throttle: &Throttle,
+) {
+    let settings = &state.settings;
+    let output_path = &settings.storage;
+    let cancelled = conn.cancel_token();
+
+    // The request in progress, kept outside the request future so it can still
+    // be audited if the request is aborted.
+    let mut pending: Option<AuditEntry> = None;
+
+    let request = async {
+        let (operation, path) = describe(&packet);
+        let user_name = user.map(|u| u.name.as_str());
+        let entry = AuditEntry::new(addr, user_name, operation, path);
+        counter!("lud_requests_total", "operation" => operation).increment(1);
+
+        let public = matches!(packet, Packet::Ping | Packet::SharedDownload(_));
+        if !settings.users.is_empty() && user.is_none() && !public {
+            send_error(
+                conn,
+                ErrorCode::Unauthenticated,
+                Some("Authentication required"),
+            )
+            .await;
+            log::warn!("Rejected unauthenticated `{}` from {}", packet, addr);
+            counter!("lud_errors_total", "kind" => "auth").increment(1);
+            audit(state, entry, None, Some("Authentication required")).await;
+            return;
+        }
+
+        pending = Some(entry);
+
+        let session = Session {
+            addr,
+            user,
+            throttle: throttle.clone(),
+        };
+        let read_only = session.user.is_some_and(|u| u.read_only);
         let packet_name = format!("{}", packet);
 
-        match packet {
-            Packet::DownloadStart(file_path, _, _) => {
-                if let Err(e) = handle_download(&mut conn, &output_path, &file_path, &addr).await {
-                    log::error!("Download failed: {:#}", e);
-                } else {
-                    send_ok(&mut conn).await;
+        let outcome: Result<Option<u64>> = match packet {
+            Packet::DownloadStart(file_path, offset, ..) => {
+                match handle_download(conn, output_path, &file_path, offset, &session).await {
+                    Ok(size) => {
+                        send_ok(conn).await;
+                        hooks::run(
+                            settings.hooks.on_download.as_deref(),
+                            HookEvent {
+                                name: "download",
+                                path: &file_path,
+                                peer: &addr,
+                                user: user_name,
+                                size: None,
+                            },
+                        );
+                        Ok(Some(size))
+                    }
+                    Err(e) => {
+                        log::error!("Download failed: {:#}", e);
+                        counter!("lud_errors_total", "kind" => operation).increment(1);
+                        Err(e)
+                    }
+                }
+            }
+
+            Packet::UploadStart(..) | Packet::Remove(..) if read_only => {
+                send_error(conn, ErrorCode::PermissionDenied, None).await;
+                log::warn!("Denied `{}` for read-only user from {}", packet_name, addr);
+                counter!("lud_errors_total", "kind" => "permission").increment(1);
+                Err(anyhow!("Permission denied"))
+            }
+
+            Packet::UploadStart(file_path, total_size, mode, force) => {
+                match handle_upload(conn, state, &session, &file_path, total_size, mode, force)
+                    .await
+                {
+                    Ok(()) => {
+                        send_ok(conn).await;
+                        hooks::run(
+                            settings.hooks.on_upload.as_deref(),
+                            HookEvent {
+                                name: "upload",
+                                path: &file_path,
+                                peer: &addr,
+                                user: user_name,
+                                size: Some(total_size),
+                            },
+                        );
+                        Ok(Some(total_size))
+                    }
+                    Err(e) => {
+                        log::error!("Upload failed: {:#}", e);
+                        counter!("lud_errors_total", "kind" => operation).increment(1);
+                        Err(e)
+                    }
+                }
+            }
+
+            Packet::List(path, _) => match handle_list(conn, output_path, path).await {
+                Ok(()) => {
+                    send_ok(conn).await;
+                    Ok(None)
+                }
+                Err(e) => {
+                    log::error!("List failed: {:#}", e);
+                    counter!("lud_errors_total", "kind" => operation).increment(1);
+                    Err(e)
+                }
+            },
+
+            Packet::Glob(pattern, directories, _) => {
+                match handle_glob(conn, output_path, pattern, directories).await {
+                    Ok(()) => {
+                        send_ok(conn).await;
+                        Ok(None)
+                    }
+                    Err(e) => {
+                        log::error!("Glob failed: {:#}", e);
+                        counter!("lud_errors_total", "kind" => operation).increment(1);
+                        Err(e)
+                    }
+                }
+            }
+
+            Packet::Find(query) => match handle_find(conn, output_path, query).await {
+                Ok(found) => {
+                    log::debug!("Found {} entries", found);
+                    send_ok(conn).await;
+                    Ok(None)
+                }
+                Err(e) => {
+                    log::error!("Find failed: {:#}", e);
+                    counter!("lud_errors_total", "kind" => operation).increment(1);
+                    Err(e)
+                }
+            },
+
+            Packet::DiskUsage(path, max_depth, _) => {
+                match handle_disk_usage(conn, output_path, path, max_depth).await {
+                    Ok(()) => {
+                        send_ok(conn).await;
+                        Ok(None)
+                    }
+                    Err(e) => {
+                        log::error!("Disk usage failed: {:#}", e);
+                        counter!("lud_errors_total", "kind" => operation).increment(1);
+                        Err(e)
+                    }
                 }
             }
 
-            Packet::UploadStart(file_path, total_size, mode, force) => {
-                if let Err(e) = handle_upload(
-                    &mut conn,
-                    &output_path,
-                    file_path,
-                    total_size,
-                    mode,
-                    force,
-                    &addr,
-                )
-                .await
-                {
-                    log::error!("Upload failed: {:#}", e);
-                } else {
-                    send_ok(&mut conn).await;
+            Packet::Space(_) => match handle_space(conn, output_path).await {
+                Ok(()) => {
+                    send_ok(conn).await;
+                    Ok(None)
+                }
+                Err(e) => {
+                    log::error!("Space failed: {:#}", e);
+                    counter!("lud_errors_total", "kind" => operation).increment(1);
+                    Err(e)
+                }
+            },
+
+            Packet::Remove(path, force, recursive) => {
+                match handle_remove(conn, state, path.clone(), force, recursive).await {
+                    Ok(()) => {
+                        send_ok(conn).await;
+                        hooks::run(
+                            settings.hooks.on_remove.as_deref(),
+                            HookEvent {
+                                name: "remove",
+                                path: &path,
+                                peer: &addr,
+                                user: user_name,
+                                size: None,
+                            },
+                        );
+                        Ok(None)
+                    }
+                    Err(e) => {
+                        log::error!("Remove failed: {:#}", e);
+                        counter!("lud_errors_total", "kind" => operation).increment(1);
+                        Err(e)
+                    }
                 }
             }
 
-            Packet::List(path, _) => {
-                if let Err(e) = handle_list(&mut conn, &output_path, path).await {
-                    log::error!("List failed: {:#}", e);
-                } else {
-                    send_ok(&mut conn).await;
+            Packet::Usage(_) => match handle_usage(conn, state, &session).await {
+                Ok(()) => {
+                    send_ok(conn).await;
+                    Ok(None)
+                }
+                Err(e) => {
+                    log::error!("Usage failed: {:#}", e);
+                    counter!("lud_errors_total", "kind" => operation).increment(1);
+                    Err(e)
+                }
+            },
+
+            Packet::Share(path, expires_in, max_downloads) => {
+                match handle_share(conn, state, &session, &path, expires_in, max_downloads).await {
+                    Ok(()) => {
+                        send_ok(conn).await;
+                        Ok(None)
+                    }
+                    Err(e) => {
+                        log::error!("Share failed: {:#}", e);
+                        counter!("lud_errors_total", "kind" => operation).increment(1);
+                        Err(e)
+                    }
                 }
             }
 
-            Packet::Remove(path, force, recursive) => {
-                if let Err(e) = handle_remove(&mut conn, &output_path, path, force, recursive).await
-                {
-                    log::error!("Remove failed: {:#}", e);
-                } else {
-                    send_ok(&mut conn).await;
+            Packet::SharedDownload(token) => {
+                match handle_shared_download(conn, state, &session, &token).await {
+                    Ok((file_path, size)) => {
+                        send_ok(conn).await;
+                        hooks::run(
+                            settings.hooks.on_download.as_deref(),
+                            HookEvent {
+                                name: "download",
+                                path: &file_path,
+                                peer: &addr,
+                                user: user_name,
+                                size: None,
+                            },
+                        );
+                        Ok(Some(size))
+                    }
+                    Err(e) => {
+                        log::error!("Shared download failed: {:#}", e);
+                        counter!("lud_errors_total", "kind" => operation).increment(1);
+                        Err(e)
+                    }
                 }
             }
 
             Packet::Ping => {
-                send_ok(&mut conn).await;
+                send_ok(conn).await;
+                Ok(None)
             }
 
             _ => {
-                send_error(&mut conn, &format!("Unsupported packet `{}`", packet)).await;
+                let message = format!("Unsupported packet `{}`", packet);
+                send_error(conn, ErrorCode::Protocol, Some(&message)).await;
+                counter!("lud_errors_total", "kind" => "protocol").increment(1);
+                Err(anyhow!(message))
             }
+        };
+
+        if let Some(entry) = pending.take() {
+            let (bytes, error) = match &outcome {
+                Ok(bytes) => (*bytes, None),
+                Err(e) => (None, Some(format!("{:#}", e))),
+            };
+            audit(state, entry, bytes, error.as_deref()).await;
         }
 
         log::info!(
@@ -192,48 +1231,137 @@ async fn handle_connection(stream: TcpStream, addr: SocketAddr, output_path: Utf
             packet_name,
             addr
         );
+    };
+
+    tokio::select! {
+        _ = request => {}
+        _ = state.abort.cancelled() => {
+            log::warn!("Aborted request from {} during shutdown", addr);
+            if let Some(entry) = pending.take() {
+                audit(state, entry, None, Some("Aborted during shutdown")).await;
+            }
+        }
+        _ = cancelled.cancelled() => {
+            log::info!("Request from {} was cancelled", addr);
+            if let Some(entry) = pending.take() {
+                audit(state, entry, None, Some("Cancelled by client")).await;
+            }
+        }
+    }
+}
+
+/// The audited operation name and path of a request.
+fn describe(packet: &Packet) -> (&'static str, Option<&str>) {
+    match packet {
+        Packet::DownloadStart(path, ..) => ("download", Some(path)),
+        Packet::UploadStart(path, ..) => ("upload", Some(path)),
+        Packet::List(path, _) => ("list", Some(path)),
+        Packet::Glob(pattern, ..) => ("glob", Some(pattern)),
+        Packet::Find(query) => ("find", Some(&query.path)),
+        Packet::DiskUsage(path, ..) => ("disk_usage", Some(path)),
+        Packet::Space(_) => ("space", None),
+        Packet::Remove(path, ..) => ("remove", Some(path)),
+        Packet::Usage(_) => ("usage", None),
+        Packet::Share(path, ..) => ("share", Some(path)),
+        Packet::SharedDownload(_) => ("shared_download", None),
+        Packet::Ping => ("ping", None),
+        _ => ("unknown", None),
     }
-    .await;
+}
 
-    shutdown_connection(&mut conn, &addr).await;
+async fn audit(state: &ServerState, entry: AuditEntry, bytes: Option<u64>, error: Option<&str>) {
+    if let Some(audit) = &state.audit {
+        audit.record(entry, bytes, error).await;
+    }
+}
+
+/// Who a request comes from and the limits that apply to it.
+struct Session<'a> {
+    addr: SocketAddr,
+    user: Option<&'a User>,
+    throttle: Throttle,
+}
+
+async fn read_request(conn: &mut Connection) -> Option<Packet> {
+    match conn.read_packet().await {
+        Ok(p) => Some(p),
+        Err(e) => {
+            send_error(conn, ErrorCode::Protocol, Some("Failed to read packet")).await;
+            log::error!("Failed to read packet: {:#}", e);
+            counter!("lud_errors_total", "kind" => "protocol").increment(1);
+            None
+        }
+    }
 }
 
+/// Sends the file at `file_path` from `offset` on, so an interrupted download
+/// can resume. Returns the number of bytes sent.
 async fn handle_download(
     conn: &mut Connection,
     output_path: &Utf8Path,
     file_path: &str,
-    addr: &SocketAddr,
-) -> Result<()> {
-    let full_path = match utils::safe_join(output_path, file_path) {
+    offset: u64,
+    session: &Session<'_>,
+) -> Result<u64> {
+    let full_path = match resolve_path(output_path, file_path) {
         Some(p) => p,
         None => {
-            send_error(conn, "Invalid file path").await;
+            send_error(conn, ErrorCode::InvalidPath, None).await;
             anyhow::bail!("Invalid file path provided: {}", file_path);
         }
     };
 
-    let metadata = fs::metadata(&full_path)
-        .await
-        .context("Failed to get file metadata")?;
+    let metadata = match fs::metadata(&full_path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => {
+            send_error(conn, ErrorCode::NotAFile, None).await;
+            anyhow::bail!("Cannot download `{}`, not a file", full_path);
+        }
+        Err(e) => {
+            send_error(conn, ErrorCode::from_io(&e), None).await;
+            return Err(Error::new(e).context("Failed to get file metadata"));
+        }
+    };
     let file_size = metadata.len();
+    if offset > file_size {
+        send_error(
+            conn,
+            ErrorCode::Protocol,
+            Some("Offset is past the end of the file"),
+        )
+        .await;
+        anyhow::bail!("Offset {} is past the end of `{}`", offset, full_path);
+    }
 
     #[cfg(unix)]
     let mode = metadata.mode();
     #[cfg(not(unix))]
     let mode = 0;
+    // Lets a client resuming the download tell whether the file was
+    // replaced, even by one of the same size.
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_nanos() as u64);
 
     conn.write_packet(&Packet::DownloadStart(
         file_path.to_string(),
         file_size,
         mode,
+        modified,
     ))
     .await
     .context("Failed to send download start packet")?;
 
+    let _transfer = ActiveTransfer::start("download");
     let chunk_size = utils::optimal_chunk_size(file_size);
     let mut file = fs::File::open(&full_path)
         .await
         .context("Failed to open file")?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .context("Failed to seek to offset")?;
     let mut buffer = vec![0u8; chunk_size];
 
     loop {
@@ -245,37 +1373,165 @@ async fn handle_download(
             break;
         }
 
+        session.throttle.consume(bytes_read as u64).await;
         let chunk = buffer[..bytes_read].to_vec();
         conn.write_packet(&Packet::DownloadChunk(chunk))
             .await
             .context("Failed to send file chunk")?;
+        counter!("lud_bytes_sent_total").increment(bytes_read as u64);
     }
 
     conn.write_packet(&Packet::DownloadEnd)
         .await
         .context("Failed to send download end packet")?;
 
-    log::debug!("Sent file `{}` to {} in chunks", full_path, addr);
+    log::debug!("Sent file `{}` to {} in chunks", full_path, session.addr);
+    Ok(file_size - offset)
+}
+
+async fn handle_share(
+    conn: &mut Connection,
+    state: &ServerState,
+    session: &Session<'_>,
+    path: &str,
+    expires_in: Option<u64>,
+    max_downloads: Option<u32>,
+) -> Result<()> {
+    let storage = &state.settings.storage;
+    let full_path = match resolve_path(storage, path) {
+        Some(p) if p.is_file() => p,
+        resolved => {
+            let code = match resolved {
+                None => ErrorCode::InvalidPath,
+                Some(p) if !p.exists() => ErrorCode::NotFound,
+                Some(_) => ErrorCode::NotAFile,
+            };
+            send_error(conn, code, None).await;
+            anyhow::bail!("Cannot share `{}`, not a file", path);
+        }
+    };
+
+    let token = match state
+        .shares
+        .create(
+            relative_path(storage, &full_path),
+            session.user.map(|u| u.name.as_str()),
+            expires_in.map(Duration::from_secs),
+            max_downloads,
+        )
+        .await
+    {
+        Ok(token) => token,
+        Err(e) => {
+            send_error(
+                conn,
+                ErrorCode::Internal,
+                Some("Failed to create share token"),
+            )
+            .await;
+            return Err(e);
+        }
+    };
+
+    log::info!(
+        "Created share token for `{}` from {}",
+        full_path,
+        session.addr
+    );
+    conn.write_packet(&Packet::ShareToken(token))
+        .await
+        .context("Failed to send share token")?;
+
+    if let Some(listen) = &state.settings.http.listen {
+        let scheme = if state.settings.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        conn.write_packet(&Packet::HttpGateway(format!("{}://{}", scheme, listen)))
+            .await
+            .context("Failed to send HTTP gateway")?;
+    }
     Ok(())
 }
 
+/// Sends the file behind a share token. The download only counts toward the
+/// token's limit once the whole file was sent. Returns the shared path and
+/// its size.
+async fn handle_shared_download(
+    conn: &mut Connection,
+    state: &ServerState,
+    session: &Session<'_>,
+    token: &str,
+) -> Result<(String, u64)> {
+    let claim = match state.shares.claim(token).await {
+        Ok(claim) => claim,
+        Err(e) => {
+            send_error(conn, ErrorCode::InvalidToken, Some(&e.to_string())).await;
+            return Err(e);
+        }
+    };
+
+    // Count the download once every byte is out, as the client may simply
+    // disconnect afterwards.
+    let size = handle_download(conn, &state.settings.storage, &claim.path, 0, session).await?;
+
+    let path = claim.path.clone();
+    claim.complete().await?;
+    log::debug!("Sent shared file `{}` to {}", path, session.addr);
+    Ok((path, size))
+}
+
 async fn handle_upload(
     conn: &mut Connection,
-    output_path: &Utf8Path,
-    file_path: String,
+    state: &ServerState,
+    session: &Session<'_>,
+    file_path: &str,
     total_size: u64,
     mode: u32,
     force: bool,
-    addr: &SocketAddr,
 ) -> Result<()> {
-    let full_path = match utils::safe_join(output_path, &file_path) {
+    let settings = &state.settings;
+    let full_path = match resolve_path(&settings.storage, file_path) {
         Some(p) => p,
         None => {
-            send_error(conn, "Invalid file path").await;
+            send_error(conn, ErrorCode::InvalidPath, None).await;
             anyhow::bail!("Invalid file path provided: {}", file_path);
         }
     };
 
+    if let Some(max) = settings.limits.max_upload_size
+        && total_size > max.0
+    {
+        send_error(
+            conn,
+            ErrorCode::TooLarge,
+            Some(&format!("File too large (limit is {})", max)),
+        )
+        .await;
+        anyhow::bail!("Upload of {} bytes exceeds limit of {}", total_size, max);
+    }
+
+    let relative_path = relative_path(&settings.storage, &full_path);
+    let _reservation = match state
+        .quota
+        .reserve(settings, relative_path, session.user, total_size)
+        .await
+    {
+        Ok(reservation) => reservation,
+        Err(e) => {
+            send_error(conn, ErrorCode::QuotaExceeded, Some(&e.to_string())).await;
+            return Err(e.context(format!("Upload of {} bytes rejected", total_size)));
+        }
+    };
+
+    // Checked before accepting the upload, so the client hears about it
+    // instead of finding the connection closed mid-transfer.
+    if !force && fs::try_exists(&full_path).await.unwrap_or(false) {
+        send_error(conn, ErrorCode::AlreadyExists, None).await;
+        anyhow::bail!("File already exists: {}", full_path);
+    }
+
     send_ok(conn).await;
 
     if let Some(parent) = full_path.parent() {
@@ -284,16 +1540,13 @@ async fn handle_upload(
             .context("Failed to create directories")?;
     }
 
-    if !force && fs::try_exists(&full_path).await.unwrap_or(false) {
-        send_error(conn, "File already exists").await;
-        anyhow::bail!("File already exists: {}", full_path);
-    }
-
+    let _transfer = ActiveTransfer::start("upload");
+    let partial = PartialFile::new(&full_path);
     let mut file = OpenOptions::new()
         .create(true)
         .write(true)
         .truncate(true)
-        .open(&full_path)
+        .open(&partial.path)
         .await
         .context("Failed to create file")?;
 
@@ -310,21 +1563,42 @@ async fn handle_upload(
 
         match packet {
             Packet::UploadChunk(data) => {
+                session.throttle.consume(data.len() as u64).await;
                 received_bytes += data.len() as u64;
+                counter!("lud_bytes_received_total").increment(data.len() as u64);
+                // Quotas were checked against the announced size.
+                if received_bytes > total_size {
+                    send_error(
+                        conn,
+                        ErrorCode::Protocol,
+                        Some("Upload is larger than announced"),
+                    )
+                    .await;
+                    anyhow::bail!("Received more than the announced {} bytes", total_size);
+                }
                 file.write_all(&data)
                     .await
                     .context("Failed to write file chunk")?;
             }
             Packet::UploadEnd => break,
+            Packet::Abort => {
+                log::info!("Upload of `{}` aborted by {}", full_path, session.addr);
+                anyhow::bail!("Aborted by client");
+            }
             _ => {
-                send_error(conn, "Unexpected packet during upload").await;
+                send_error(
+                    conn,
+                    ErrorCode::Protocol,
+                    Some("Unexpected packet during upload"),
+                )
+                .await;
                 anyhow::bail!("Unexpected packet during upload");
             }
         }
     }
 
     if received_bytes != total_size {
-        send_error(conn, "File size mismatch").await;
+        send_error(conn, ErrorCode::Protocol, Some("File size mismatch")).await;
         anyhow::bail!(
             "Received file size {} doesn't match expected size {}",
             received_bytes,
@@ -332,21 +1606,33 @@ async fn handle_upload(
         );
     }
 
-    log::debug!("Saved file `{}` from {} in chunks", full_path, addr);
+    file.flush().await.context("Failed to flush file")?;
+    partial.persist(&full_path).await?;
+
+    if let Err(e) = state
+        .quota
+        .record_upload(relative_path, session.user.map(|u| u.name.as_str()))
+        .await
+    {
+        log::warn!("Failed to record owner of `{}`: {:#}", full_path, e);
+    }
+
+    log::debug!("Saved file `{}` from {} in chunks", full_path, session.addr);
     Ok(())
 }
 
 async fn handle_remove(
     conn: &mut Connection,
-    output_path: &Utf8Path,
+    state: &ServerState,
     path: String,
     force: bool,
     recursive: bool,
 ) -> Result<()> {
-    let full_path = match utils::safe_join(&output_path, &path) {
+    let output_path = &state.settings.storage;
+    let full_path = match resolve_path(output_path, &path) {
         Some(p) => p,
         None => {
-            send_error(conn, "Invalid path").await;
+            send_error(conn, ErrorCode::InvalidPath, None).await;
             anyhow::bail!("Invalid path provided: {}", path);
         }
     };
@@ -357,12 +1643,17 @@ async fn handle_remove(
                 send_ok(conn).await;
                 return Ok(());
             } else {
-                send_error(conn, "Path does not exist").await;
+                send_error(conn, ErrorCode::NotFound, None).await;
                 anyhow::bail!("Path `{}` does not exist", full_path);
             }
         }
         Err(e) => {
-            send_error(conn, "Failed to check path existence").await;
+            send_error(
+                conn,
+                ErrorCode::from_io(&e),
+                Some("Failed to check path existence"),
+            )
+            .await;
             anyhow::bail!("Failed to check path `{}`: {:#}", full_path, e);
         }
         _ => {}
@@ -371,20 +1662,30 @@ async fn handle_remove(
     let metadata = match fs::metadata(&full_path).await {
         Ok(m) => m,
         Err(e) => {
-            send_error(conn, "Failed to get path metadata").await;
+            send_error(
+                conn,
+                ErrorCode::from_io(&e),
+                Some("Failed to get path metadata"),
+            )
+            .await;
             anyhow::bail!("Failed to get metadata for `{}`: {:#}", full_path, e);
         }
     };
 
     if metadata.is_file() {
         if let Err(e) = fs::remove_file(&full_path).await {
-            send_error(conn, "Failed to delete file").await;
+            send_error(conn, ErrorCode::from_io(&e), Some("Failed to delete file")).await;
             anyhow::bail!("Failed to delete file `{}`: {:#}", full_path, e);
         }
     } else if metadata.is_dir() {
         if recursive {
             if let Err(e) = fs::remove_dir_all(&full_path).await {
-                send_error(conn, "Failed to delete directory recursively").await;
+                send_error(
+                    conn,
+                    ErrorCode::from_io(&e),
+                    Some("Failed to delete directory recursively"),
+                )
+                .await;
                 anyhow::bail!(
                     "Failed to delete directory `{}` recursively: {:#}",
                     full_path,
@@ -395,17 +1696,35 @@ async fn handle_remove(
             match fs::remove_dir(&full_path).await {
                 Ok(()) => {}
                 Err(e) if e.kind() == ErrorKind::DirectoryNotEmpty => {
-                    send_error(conn, "Directory not empty (use recursive flag)").await;
+                    send_error(
+                        conn,
+                        ErrorCode::DirectoryNotEmpty,
+                        Some("Directory not empty (use recursive flag)"),
+                    )
+                    .await;
                     anyhow::bail!("Directory `{}` not empty", full_path);
                 }
                 Err(e) => {
-                    send_error(conn, "Failed to delete directory").await;
+                    send_error(
+                        conn,
+                        ErrorCode::from_io(&e),
+                        Some("Failed to delete directory"),
+                    )
+                    .await;
                     anyhow::bail!("Failed to delete directory `{}`: {:#}", full_path, e);
                 }
             }
         }
     }
 
+    if let Err(e) = state
+        .quota
+        .record_remove(relative_path(output_path, &full_path))
+        .await
+    {
+        log::warn!("Failed to update owners of `{}`: {:#}", full_path, e);
+    }
+
     log::debug!(
         "Deleted path `{}` (force: {}, recursive: {})",
         full_path,
@@ -419,28 +1738,33 @@ async fn handle_remove(
 }
 
 async fn handle_list(conn: &mut Connection, output_path: &Utf8Path, path: String) -> Result<()> {
-    let full_path = match utils::safe_join(&output_path, &path) {
+    let full_path = match resolve_path(output_path, &path) {
         Some(p) => p,
         None => {
-            send_error(conn, "Invalid path").await;
+            send_error(conn, ErrorCode::InvalidPath, None).await;
             anyhow::bail!("Invalid path provided: {}", path);
         }
     };
 
     let mut files = Vec::new();
 
-    for entry in WalkDir::new(&full_path).into_iter().filter_map(Result::ok) {
-        if !entry.file_type().is_dir() {
-            if let Ok(metadata) = entry.metadata() {
-                if let Ok(stripped_path) = entry.path().strip_prefix(&output_path) {
-                    if let Some(path_str) = stripped_path.to_str() {
-                        files.push(File {
-                            path: path_str.to_string(),
-                            size: metadata.size(),
-                        });
-                    }
-                }
-            }
+    let entries = WalkDir::new(&full_path)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !is_reserved_name(&entry.file_name().to_string_lossy())
+        })
+        .filter_map(Result::ok);
+
+    for entry in entries {
+        if !entry.file_type().is_dir()
+            && let Ok(metadata) = entry.metadata()
+            && let Ok(stripped_path) = entry.path().strip_prefix(output_path)
+            && let Some(path_str) = stripped_path.to_str()
+        {
+            files.push(File {
+                path: path_str.to_string(),
+                size: metadata.size(),
+            });
         }
     }
 
@@ -453,6 +1777,336 @@ async fn handle_list(conn: &mut Connection, output_path: &Utf8Path, path: String
     Ok(())
 }
 
+/// Sends the paths matching `pattern`, relative to the storage root. The
+/// pattern may not leave the root or look into reserved directories.
+async fn handle_glob(
+    conn: &mut Connection,
+    output_path: &Utf8Path,
+    pattern: String,
+    directories: bool,
+) -> Result<()> {
+    let mut parts = Vec::new();
+    for part in pattern.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                send_error(conn, ErrorCode::InvalidPath, None).await;
+                anyhow::bail!("Invalid pattern provided: {}", pattern);
+            }
+            part => parts.push(part),
+        }
+    }
+    let normalized = parts.join("/");
+
+    if resolve_path(output_path, utils::glob_base(&normalized).as_str()).is_none() {
+        send_error(conn, ErrorCode::InvalidPath, None).await;
+        anyhow::bail!("Invalid pattern provided: {}", pattern);
+    }
+
+    let files = match utils::glob(output_path, &normalized, directories, is_reserved_name) {
+        Ok(files) => files,
+        Err(e) => {
+            send_error(conn, ErrorCode::InvalidPath, Some(&e.to_string())).await;
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = conn
+        .write_packet(&Packet::Glob(pattern, directories, files))
+        .await
+    {
+        anyhow::bail!("Failed to send packet: {:#}", e);
+    }
+    Ok(())
+}
+
+/// Entries sent per `Packet::Found`, so results arrive while a big tree is
+/// still being searched.
+const FIND_BATCH_SIZE: usize = 512;
+
+/// Walks the tree below `query.path` and streams the entries matching it,
+/// relative to the storage root. Returns how many were found.
+async fn handle_find(
+    conn: &mut Connection,
+    output_path: &Utf8Path,
+    query: FindQuery,
+) -> Result<usize> {
+    let full_path = match resolve_path(output_path, &query.path) {
+        Some(p) => p,
+        None => {
+            send_error(conn, ErrorCode::InvalidPath, None).await;
+            anyhow::bail!("Invalid path provided: {}", query.path);
+        }
+    };
+
+    let name = match query.name.as_deref().map(Glob::new).transpose() {
+        Ok(glob) => glob.map(|glob| glob.compile_matcher()),
+        Err(e) => {
+            let message = format!("Invalid pattern: {}", e.kind());
+            send_error(conn, ErrorCode::InvalidPath, Some(&message)).await;
+            anyhow::bail!(message);
+        }
+    };
+    let cutoff = query
+        .newer
+        .and_then(|secs| SystemTime::now().checked_sub(Duration::from_secs(secs)));
+    let sized = query.min_size.is_some() || query.max_size.is_some();
+
+    let mut found = 0;
+    let mut batch = Vec::new();
+    let entries = WalkDir::new(&full_path)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !is_reserved_name(&entry.file_name().to_string_lossy())
+        })
+        .filter_map(Result::ok);
+
+    for entry in entries {
+        let is_dir = entry.file_type().is_dir();
+        // Like find(1), the starting point is only an answer when it's a file.
+        if entry.depth() == 0 && is_dir {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let kind = if is_dir {
+            FileKind::Directory
+        } else {
+            FileKind::File
+        };
+        let size = if is_dir { 0 } else { metadata.size() };
+
+        let matches = query.kind.is_none_or(|wanted| wanted == kind)
+            && name
+                .as_ref()
+                .is_none_or(|name| name.is_match(entry.file_name()))
+            && (!sized
+                || !is_dir
+                    && query.min_size.is_none_or(|min| size >= min)
+                    && query.max_size.is_none_or(|max| size <= max))
+            && cutoff
+                .is_none_or(|cutoff| metadata.modified().is_ok_and(|modified| modified >= cutoff));
+        if !matches {
+            continue;
+        }
+
+        if let Ok(stripped_path) = entry.path().strip_prefix(output_path)
+            && let Some(path_str) = stripped_path.to_str()
+        {
+            batch.push(File {
+                path: path_str.to_string(),
+                size,
+            });
+        }
+        if batch.len() >= FIND_BATCH_SIZE {
+            found += batch.len();
+            if let Err(e) = conn.write_packet(&Packet::Found(batch.split_off(0))).await {
+                anyhow::bail!("Failed to send packet: {:#}", e);
+            }
+        }
+    }
+
+    if !batch.is_empty() {
+        found += batch.len();
+        if let Err(e) = conn.write_packet(&Packet::Found(batch)).await {
+            anyhow::bail!("Failed to send packet: {:#}", e);
+        }
+    }
+
+    Ok(found)
+}
+
+/// Sends the total size of `path` and every directory below it, down to
+/// `max_depth` levels, children before their parents as du(1) lists them.
+async fn handle_disk_usage(
+    conn: &mut Connection,
+    output_path: &Utf8Path,
+    path: String,
+    max_depth: Option<u32>,
+) -> Result<()> {
+    let full_path = match resolve_path(output_path, &path) {
+        Some(p) => p,
+        None => {
+            send_error(conn, ErrorCode::InvalidPath, None).await;
+            anyhow::bail!("Invalid path provided: {}", path);
+        }
+    };
+    if !fs::try_exists(&full_path).await.unwrap_or(false) {
+        send_error(conn, ErrorCode::NotFound, None).await;
+        anyhow::bail!("Path `{}` does not exist", full_path);
+    }
+
+    let files = disk_usage(output_path, &full_path, max_depth);
+    if let Err(e) = conn
+        .write_packet(&Packet::DiskUsage(path, max_depth, files))
+        .await
+    {
+        anyhow::bail!("Failed to send packet: {:#}", e);
+    }
+    Ok(())
+}
+
+/// The total size of `full_path` and of each directory below it, up to
+/// `max_depth` levels down, relative to `output_path`.
+fn disk_usage(output_path: &Utf8Path, full_path: &Utf8Path, max_depth: Option<u32>) -> Vec<File> {
+    let mut files = Vec::new();
+    // The size gathered so far for the directory open at each depth. With
+    // contents first, a directory comes after everything below it, so its
+    // total is complete by then.
+    let mut totals: Vec<u64> = Vec::new();
+
+    // Pruning with `filter_entry` skips the wrong directory when contents
+    // come first, so reserved entries are dropped one by one instead.
+    let entries = WalkDir::new(full_path)
+        .contents_first(true)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            !entry
+                .path()
+                .strip_prefix(full_path)
+                .into_iter()
+                .flat_map(|relative| relative.components())
+                .any(|component| is_reserved_name(&component.as_os_str().to_string_lossy()))
+        });
+
+    for entry in entries {
+        let depth = entry.depth();
+        let is_dir = entry.file_type().is_dir();
+        let size = if is_dir {
+            totals.get_mut(depth).map(std::mem::take).unwrap_or(0)
+        } else {
+            entry
+                .metadata()
+                .map(|metadata| metadata.size())
+                .unwrap_or(0)
+        };
+        if depth > 0 {
+            if totals.len() < depth {
+                totals.resize(depth, 0);
+            }
+            totals[depth - 1] += size;
+        }
+
+        // Only directories are listed, unless the path itself is a file.
+        if (is_dir || depth == 0)
+            && max_depth.is_none_or(|max_depth| depth <= max_depth as usize)
+            && let Ok(stripped_path) = entry.path().strip_prefix(output_path)
+            && let Some(path_str) = stripped_path.to_str()
+        {
+            files.push(File {
+                path: path_str.to_string(),
+                size,
+            });
+        }
+    }
+    files
+}
+
+async fn handle_space(conn: &mut Connection, output_path: &Utf8Path) -> Result<()> {
+    let stats = match fs4::statvfs(output_path) {
+        Ok(stats) => stats,
+        Err(e) => {
+            send_error(conn, ErrorCode::Internal, None).await;
+            anyhow::bail!("Failed to get space of `{}`: {}", output_path, e);
+        }
+    };
+    let space = Space {
+        total: stats.total_space(),
+        available: stats.available_space(),
+    };
+
+    if let Err(e) = conn.write_packet(&Packet::Space(Some(space))).await {
+        anyhow::bail!("Failed to send packet: {:#}", e);
+    }
+    Ok(())
+}
+
+async fn handle_usage(
+    conn: &mut Connection,
+    state: &ServerState,
+    session: &Session<'_>,
+) -> Result<()> {
+    let usage = state.quota.report(&state.settings, session.user).await;
+
+    if let Err(e) = conn.write_packet(&Packet::Usage(usage)).await {
+        anyhow::bail!("Failed to send packet: {:#}", e);
+    }
+
+    Ok(())
+}
+
+/// Directory in the storage root holding server metadata.
+pub const META_DIR: &str = ".lud";
+
+/// Suffix of files that are still being uploaded.
+const PARTIAL_SUFFIX: &str = ".lud-partial";
+
+/// Whether a file or directory name is reserved for the server and hidden
+/// from clients.
+pub fn is_reserved_name(name: &str) -> bool {
+    name == META_DIR || name.ends_with(PARTIAL_SUFFIX)
+}
+
+/// Joins a client supplied path onto the storage root, refusing paths that
+/// escape it or touch reserved names.
+fn resolve_path(root: &Utf8Path, path: &str) -> Option<Utf8PathBuf> {
+    let full_path = utils::safe_join(root, path)?;
+    let reserved = relative_path(root, &full_path)
+        .components()
+        .any(|component| is_reserved_name(component.as_str()));
+    (!reserved).then_some(full_path)
+}
+
+fn relative_path<'a>(root: &Utf8Path, full_path: &'a Utf8Path) -> &'a Utf8Path {
+    full_path.strip_prefix(root).unwrap_or(full_path)
+}
+
+/// Numbers partial files, so a retried upload never shares one with an
+/// attempt the server hasn't noticed is gone yet.
+static NEXT_PARTIAL: AtomicU64 = AtomicU64::new(0);
+
+/// An upload in progress, written next to its destination and renamed into
+/// place once complete. Dropping it before then, because the upload failed or
+/// was aborted, removes the partial file.
+struct PartialFile {
+    path: Utf8PathBuf,
+    persisted: bool,
+}
+
+impl PartialFile {
+    fn new(destination: &Utf8Path) -> Self {
+        let name = destination.file_name().unwrap_or_default();
+        Self {
+            path: destination.with_file_name(format!(
+                ".{}.{}{}",
+                name,
+                NEXT_PARTIAL.fetch_add(1, Ordering::Relaxed),
+                PARTIAL_SUFFIX
+            )),
+            persisted: false,
+        }
+    }
+
+    async fn persist(mut self, destination: &Utf8Path) -> Result<()> {
+        fs::rename(&self.path, destination)
+            .await
+            .context("Failed to move uploaded file into place")?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.persisted && std::fs::remove_file(&self.path).is_ok() {
+            log::debug!("Removed partial file `{}`", self.path);
+        }
+    }
+}
+
 async fn shutdown_connection(conn: &mut Connection, addr: &SocketAddr) {
     conn.shutdown().await;
     log::info!("Closed connection from {}", addr);
@@ -464,8 +2118,101 @@ async fn send_ok(conn: &mut Connection) {
     }
 }
 
-async fn send_error(conn: &mut Connection, msg: &str) {
-    if let Err(e) = conn.write_packet(&Packet::Error(msg.into())).await {
+/// Turns the request down with `code`, and `message` when the code alone
+/// doesn't say enough.
+async fn send_error(conn: &mut Connection, code: ErrorCode, message: Option<&str>) {
+    let packet = Packet::Error(code, message.map(str::to_string));
+    if let Err(e) = conn.write_packet(&packet).await {
         log::error!("Failed to send packet: {:#}", e);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sizes(files: Vec<File>) -> Vec<(String, u64)> {
+        files
+            .into_iter()
+            .map(|file| (file.path, file.size))
+            .collect()
+    }
+
+    #[test]
+    fn totals_disk_usage_per_directory() {
+        let root = std::env::temp_dir().join(format!("lud-du-{}", std::process::id()));
+        let root = Utf8PathBuf::from_path_buf(root).unwrap();
+        for dir in ["data/sub/deep", "data/empty", "data/.lud"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for (file, size) in [
+            ("data/a", 10),
+            ("data/sub/b", 20),
+            ("data/sub/deep/c", 30),
+            ("data/.lud/x", 100),
+        ] {
+            std::fs::write(root.join(file), vec![0; size]).unwrap();
+        }
+        let data = root.join("data");
+        let entry = |path: &str, size| (path.to_string(), size);
+
+        assert_eq!(
+            sizes(disk_usage(&root, &data, None)),
+            [
+                entry("data/empty", 0),
+                entry("data/sub/deep", 30),
+                entry("data/sub", 50),
+                entry("data", 60),
+            ]
+        );
+        // Directories past the limit still count towards their parents.
+        assert_eq!(
+            sizes(disk_usage(&root, &data, Some(0))),
+            [entry("data", 60)]
+        );
+        assert_eq!(
+            sizes(disk_usage(&root, &root.join("data/sub/b"), None)),
+            [entry("data/sub/b", 20)]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn decodes_a_frame() {
+        let packet = Packet::Frame(7, Framed(Box::new(Packet::Ping)));
+        let bytes = bincode::serialize(&packet).unwrap();
+        let decoded: Packet = bincode::deserialize(&bytes).unwrap();
+        assert!(
+            matches!(decoded, Packet::Frame(7, Framed(inner)) if matches!(*inner, Packet::Ping))
+        );
+    }
+
+    #[test]
+    fn rejects_nested_frames_without_overflowing() {
+        // A frame's header is its tag and stream id, followed by the packet.
+        let frame = bincode::serialize(&Packet::Frame(1, Framed(Box::new(Packet::Ok)))).unwrap();
+        let ok = bincode::serialize(&Packet::Ok).unwrap();
+        let header = &frame[..frame.len() - ok.len()];
+
+        let mut bytes = header.repeat(200_000);
+        bytes.extend_from_slice(&ok);
+        assert!(bincode::deserialize::<Packet>(&bytes).is_err());
+
+        let mut bytes = header.repeat(2);
+        bytes.extend_from_slice(&ok);
+        assert!(bincode::deserialize::<Packet>(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_multiplex_within_a_frame() {
+        let packet = Packet::Frame(1, Framed(Box::new(Packet::Multiplex)));
+        let bytes = bincode::serialize(&packet).unwrap();
+        assert!(bincode::deserialize::<Packet>(&bytes).is_err());
+
+        // A rejected frame doesn't leave the next one looking nested.
+        let packet = Packet::Frame(1, Framed(Box::new(Packet::Ping)));
+        let bytes = bincode::serialize(&packet).unwrap();
+        assert!(bincode::deserialize::<Packet>(&bytes).is_ok());
+    }
+}
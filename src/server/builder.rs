@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use camino::Utf8PathBuf;
+
+use crate::{
+    error::{Error, Result},
+    server_settings::{ServerSettings, TlsSettings, User},
+    utils::ByteSize,
+};
+
+/// A lud server to embed in another program. Settings not covered by the
+/// builder methods can be changed through `settings_mut`.
+pub struct Server {
+    settings: ServerSettings,
+    /// Whether `listen` was called, replacing the default address.
+    listen_set: bool,
+}
+
+impl Server {
+    /// A server storing files in `storage`, listening on the default address.
+    pub fn new(storage: impl Into<Utf8PathBuf>) -> Self {
+        Self::from_settings(ServerSettings {
+            storage: storage.into(),
+            ..Default::default()
+        })
+    }
+
+    /// A server using settings loaded from a config file.
+    pub fn from_settings(settings: ServerSettings) -> Self {
+        Self {
+            settings,
+            listen_set: false,
+        }
+    }
+
+    /// Listens on `addr`. Call it again to listen on several addresses.
+    pub fn listen(mut self, addr: impl Into<String>) -> Self {
+        if !self.listen_set {
+            self.settings.listen.clear();
+            self.listen_set = true;
+        }
+        self.settings.listen.push(addr.into());
+        self
+    }
+
+    /// Serves TLS with the PEM certificate chain and key at these paths.
+    pub fn tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.settings.tls = Some(TlsSettings {
+            cert: cert.into(),
+            key: key.into(),
+        });
+        self
+    }
+
+    /// Adds a user. Once a user exists, requests need credentials.
+    pub fn user(
+        mut self,
+        name: impl Into<String>,
+        token: impl Into<String>,
+        read_only: bool,
+    ) -> Self {
+        self.settings.users.push(User {
+            name: name.into(),
+            token: token.into(),
+            read_only,
+            quota: None,
+        });
+        self
+    }
+
+    /// Caps the bytes all stored files may take up.
+    pub fn max_storage(mut self, bytes: u64) -> Self {
+        self.settings.quota.max_storage = Some(ByteSize(bytes));
+        self
+    }
+
+    /// Also serves the storage root over HTTP on `addr`.
+    pub fn http(mut self, addr: impl Into<String>) -> Self {
+        self.settings.http.listen = Some(addr.into());
+        self
+    }
+
+    /// Also serves the storage root over WebDAV on `addr`.
+    pub fn webdav(mut self, addr: impl Into<String>) -> Self {
+        self.settings.webdav = Some(addr.into());
+        self
+    }
+
+    /// Answers discovery queries from clients on the local network.
+    pub fn announce(mut self) -> Self {
+        self.settings.announce = true;
+        self
+    }
+
+    pub fn settings(&self) -> &ServerSettings {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut ServerSettings {
+        &mut self.settings
+    }
+
+    /// Runs the server until Ctrl-C or SIGTERM. A second signal while active
+    /// transfers finish exits the process.
+    pub async fn run(self) -> Result<()> {
+        let settings = self.prepare()?;
+        super::serve(settings, super::shutdown_signal(), true)
+            .await
+            .map_err(Error::Server)
+    }
+
+    /// Runs the server until `shutdown` completes, then lets active transfers
+    /// finish within the grace period.
+    pub async fn run_until<F>(self, shutdown: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let settings = self.prepare()?;
+        super::serve(settings, shutdown, false)
+            .await
+            .map_err(Error::Server)
+    }
+
+    fn prepare(self) -> Result<ServerSettings> {
+        let mut settings = self.settings;
+        settings.prepare().map_err(Error::Config)?;
+        Ok(settings)
+    }
+}
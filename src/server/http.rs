@@ -0,0 +1,860 @@
+use std::{convert::Infallible, fmt::Write as _, io::ErrorKind, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use bytes::Bytes;
+use camino::Utf8Path;
+use futures_util::stream;
+use http_body_util::{BodyExt, Empty, Full, StreamBody, combinators::BoxBody};
+use humansize::{BINARY, format_size};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Frame, Incoming},
+    header::{self, HeaderValue},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use metrics::counter;
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use super::{
+    PartialFile, ServerState, Session, audit, is_reserved_name, relative_path, resolve_path, webdav,
+};
+use crate::{
+    audit::AuditEntry,
+    hooks::{self, HookEvent},
+    rate_limit::Throttle,
+    server::Stream,
+    server_settings::User,
+    share::ShareClaim,
+    telemetry::ActiveTransfer,
+    utils,
+};
+
+pub(super) type Body = BoxBody<Bytes, std::io::Error>;
+
+/// Methods accepted by the WebDAV listener.
+pub(super) const WEBDAV_METHODS: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+
+/// Serves HTTP requests on an accepted connection until the client hangs up
+/// or the server shuts down. With `webdav`, the WebDAV methods are served too.
+pub async fn handle_connection(
+    stream: Box<dyn Stream>,
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    webdav: bool,
+) {
+    let limits = &state.settings.limits;
+    let throttle = Throttle::new(limits.connection_rate_limit.map(|rate| rate.0))
+        .with(state.rate_limiter.clone());
+
+    let service = {
+        let state = state.clone();
+        service_fn(move |request| {
+            let state = state.clone();
+            let throttle = throttle.clone();
+            async move {
+                let response = handle_request(request, &state, addr, throttle, webdav).await;
+                Ok::<_, Infallible>(response)
+            }
+        })
+    };
+
+    let mut builder = http1::Builder::new();
+    builder
+        .timer(TokioTimer::new())
+        .header_read_timeout(limits.idle_timeout.0);
+    let conn = builder.serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(conn);
+
+    tokio::select! {
+        result = conn.as_mut() => {
+            if let Err(e) = result {
+                log::debug!("HTTP connection from {} failed: {:#}", addr, e);
+            }
+            log::info!("Closed HTTP connection from {}", addr);
+            return;
+        }
+        _ = state.shutdown.cancelled() => {}
+    }
+
+    conn.as_mut().graceful_shutdown();
+    tokio::select! {
+        _ = conn.as_mut() => {}
+        _ = state.abort.cancelled() => {
+            log::warn!("Aborted HTTP request from {} during shutdown", addr);
+        }
+    }
+    log::info!("Closed HTTP connection from {}", addr);
+}
+
+async fn handle_request(
+    request: Request<Incoming>,
+    state: &Arc<ServerState>,
+    addr: SocketAddr,
+    throttle: Throttle,
+    webdav: bool,
+) -> Response<Body> {
+    let settings = &state.settings;
+    log::debug!(
+        "{} {} from {}",
+        request.method(),
+        request.uri().path(),
+        addr
+    );
+
+    if let Some(token) = share_token(&request) {
+        let session = Session {
+            addr,
+            user: None,
+            throttle,
+        };
+        return serve_shared(&request, state, &session, &token).await;
+    }
+
+    let user = match authenticate(&request, state, addr).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+    let session = Session {
+        addr,
+        user,
+        throttle,
+    };
+
+    let Ok(path) = percent_decode_str(request.uri().path()).decode_utf8() else {
+        return text(StatusCode::BAD_REQUEST, "Invalid path");
+    };
+    let path = path.trim_matches('/').to_string();
+    let Some(full_path) = resolve_path(&settings.storage, &path) else {
+        return text(StatusCode::NOT_FOUND, "Not found");
+    };
+
+    match *request.method() {
+        Method::GET | Method::HEAD => {
+            let head = request.method() == Method::HEAD;
+            match fs::metadata(&full_path).await {
+                Ok(metadata) if metadata.is_dir() => {
+                    if !request.uri().path().ends_with('/') {
+                        let location = format!("{}/", request.uri().path());
+                        return redirect(&location);
+                    }
+                    serve_index(state, &session, &full_path, &path, head).await
+                }
+                Ok(_) => serve_file(&request, state, &session, &full_path, &path, head, None).await,
+                Err(e) => io_error(&e),
+            }
+        }
+
+        Method::PUT if webdav || settings.http.read_write => {
+            if let Err(response) = check_writable(state, &session, "upload", &path).await {
+                return response;
+            }
+            receive_file(request, state, &session, &full_path, &path).await
+        }
+
+        _ if webdav => webdav::handle_request(request, state, &session, &full_path, &path).await,
+
+        _ => {
+            let allow = if settings.http.read_write {
+                "GET, HEAD, PUT"
+            } else {
+                "GET, HEAD"
+            };
+            method_not_allowed(allow)
+        }
+    }
+}
+
+/// Refuses writes from read-only users, auditing the denied `operation`.
+pub(super) async fn check_writable(
+    state: &ServerState,
+    session: &Session<'_>,
+    operation: &'static str,
+    path: &str,
+) -> Result<(), Response<Body>> {
+    let Some(user) = session.user.filter(|u| u.read_only) else {
+        return Ok(());
+    };
+
+    log::warn!(
+        "Denied HTTP {} for read-only user from {}",
+        operation,
+        session.addr
+    );
+    counter!("lud_errors_total", "kind" => "permission").increment(1);
+    let entry = AuditEntry::new(session.addr, Some(&user.name), operation, Some(path));
+    audit(state, entry, None, Some("Permission denied")).await;
+    Err(text(StatusCode::FORBIDDEN, "Permission denied"))
+}
+
+/// The `token` query parameter of a share link.
+fn share_token(request: &Request<Incoming>) -> Option<String> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .map(|token| percent_decode_str(token).decode_utf8_lossy().into_owned())
+}
+
+/// Serves the file behind a share link without asking for credentials. The
+/// link only works for the shared path.
+async fn serve_shared(
+    request: &Request<Incoming>,
+    state: &Arc<ServerState>,
+    session: &Session<'_>,
+    token: &str,
+) -> Response<Body> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return method_not_allowed("GET, HEAD");
+    }
+
+    let claim = match state.shares.claim(token).await {
+        Ok(claim) => claim,
+        Err(e) => {
+            log::warn!("Rejected share link from {}: {:#}", session.addr, e);
+            counter!("lud_errors_total", "kind" => "auth").increment(1);
+            return text(StatusCode::NOT_FOUND, &e.to_string());
+        }
+    };
+
+    let path = percent_decode_str(request.uri().path()).decode_utf8_lossy();
+    let path = path.trim_matches('/');
+    let full_path = match resolve_path(&state.settings.storage, path) {
+        Some(full_path) if path == claim.path => full_path,
+        _ => return text(StatusCode::NOT_FOUND, "Invalid or expired share token"),
+    };
+
+    let head = request.method() == Method::HEAD;
+    let path = claim.path.clone();
+    serve_file(
+        request,
+        state,
+        session,
+        &full_path,
+        &path,
+        head,
+        Some(claim),
+    )
+    .await
+}
+
+/// Checks HTTP basic credentials against the configured users. Anyone may
+/// read when no users are configured.
+async fn authenticate<'a>(
+    request: &Request<Incoming>,
+    state: &'a ServerState,
+    addr: SocketAddr,
+) -> Result<Option<&'a User>, Response<Body>> {
+    let settings = &state.settings;
+    if settings.users.is_empty() {
+        return Ok(None);
+    }
+
+    let credentials = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| BASE64.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+
+    let unauthorized = || {
+        let mut response = text(StatusCode::UNAUTHORIZED, "Authentication required");
+        response.headers_mut().insert(
+            header::WWW_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"lud\""),
+        );
+        response
+    };
+
+    let Some((name, token)) = credentials.as_deref().and_then(|c| c.split_once(':')) else {
+        return Err(unauthorized());
+    };
+
+    match settings.authenticate(name, token) {
+        Some(user) => Ok(Some(user)),
+        None => {
+            log::warn!("Failed HTTP login as `{}` from {}", name, addr);
+            counter!("lud_errors_total", "kind" => "auth").increment(1);
+            let entry = AuditEntry::new(addr, Some(name), "auth", None);
+            audit(state, entry, None, Some("Invalid credentials")).await;
+            Err(unauthorized())
+        }
+    }
+}
+
+async fn serve_index(
+    state: &ServerState,
+    session: &Session<'_>,
+    full_path: &Utf8Path,
+    path: &str,
+    head: bool,
+) -> Response<Body> {
+    let user_name = session.user.map(|u| u.name.as_str());
+    let entry = AuditEntry::new(session.addr, user_name, "list", Some(path));
+    counter!("lud_requests_total", "operation" => "list").increment(1);
+
+    let mut entries = Vec::new();
+    let mut dir = match fs::read_dir(full_path).await {
+        Ok(dir) => dir,
+        Err(e) => {
+            audit(state, entry, None, Some(&e.to_string())).await;
+            return io_error(&e);
+        }
+    };
+    while let Ok(Some(child)) = dir.next_entry().await {
+        let name = child.file_name().to_string_lossy().into_owned();
+        if is_reserved_name(&name) {
+            continue;
+        }
+        if let Ok(metadata) = child.metadata().await {
+            entries.push((name, metadata.is_dir(), metadata.len()));
+        }
+    }
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let title = escape(&format!("Index of /{}", path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n<table>\n<tr><th align=\"left\">Name</th><th align=\"right\">Size</th></tr>\n"
+    );
+    if !path.is_empty() {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td></tr>\n");
+    }
+    for (name, is_dir, size) in &entries {
+        let suffix = if *is_dir { "/" } else { "" };
+        let size = if *is_dir {
+            String::new()
+        } else {
+            format_size(*size, BINARY)
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{suffix}\">{}{suffix}</a></td><td align=\"right\">{}</td></tr>",
+            utf8_percent_encode(name, utils::URL_PATH_SEGMENT),
+            escape(name),
+            size
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    audit(state, entry, None, None).await;
+
+    let length = html.len();
+    let body = if head { empty() } else { full(html) };
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CONTENT_LENGTH, length)
+        .body(body)
+        .unwrap()
+}
+
+async fn serve_file(
+    request: &Request<Incoming>,
+    state: &Arc<ServerState>,
+    session: &Session<'_>,
+    full_path: &Utf8Path,
+    path: &str,
+    head: bool,
+    share: Option<ShareClaim>,
+) -> Response<Body> {
+    let mut file = match fs::File::open(full_path).await {
+        Ok(file) => file,
+        Err(e) => return io_error(&e),
+    };
+    let size = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return io_error(&e),
+    };
+
+    let range = request
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| parse_range(value, size))
+        .unwrap_or(Ok(None));
+
+    let (status, start, length) = match range {
+        Ok(Some((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+        Ok(None) => (StatusCode::OK, 0, size),
+        Err(()) => {
+            let mut response = text(StatusCode::RANGE_NOT_SATISFIABLE, "Range not satisfiable");
+            response.headers_mut().insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{}", size)).unwrap(),
+            );
+            return response;
+        }
+    };
+
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_LENGTH, length)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, start + length - 1, size),
+        );
+    }
+
+    if head {
+        return response.body(empty()).unwrap();
+    }
+
+    if start > 0
+        && let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await
+    {
+        return io_error(&e);
+    }
+
+    counter!("lud_requests_total", "operation" => "download").increment(1);
+    let user_name = session.user.map(|u| u.name.as_str());
+    let download = Download {
+        state: state.clone(),
+        entry: Some(AuditEntry::new(
+            session.addr,
+            user_name,
+            "download",
+            Some(path),
+        )),
+        path: path.to_string(),
+        peer: session.addr,
+        user: user_name.map(Into::into),
+        sent: 0,
+        length,
+        whole: start == 0 && length == size,
+        share,
+        _transfer: ActiveTransfer::start("download"),
+    };
+
+    let chunk_size = utils::optimal_chunk_size(length);
+    let throttle = session.throttle.clone();
+    let body = stream::unfold(
+        (file, download, throttle, vec![0u8; chunk_size]),
+        |(mut file, mut download, throttle, mut buffer)| async move {
+            let remaining = download.length - download.sent;
+            if remaining == 0 {
+                return None;
+            }
+            let limit = buffer.len().min(remaining as usize);
+            match file.read(&mut buffer[..limit]).await {
+                Ok(0) => None,
+                Ok(read) => {
+                    throttle.consume(read as u64).await;
+                    download.sent += read as u64;
+                    counter!("lud_bytes_sent_total").increment(read as u64);
+                    let frame = Frame::data(Bytes::copy_from_slice(&buffer[..read]));
+                    Some((Ok(frame), (file, download, throttle, buffer)))
+                }
+                Err(e) => Some((Err(e), (file, download, throttle, buffer))),
+            }
+        },
+    );
+
+    response
+        .body(BodyExt::boxed(StreamBody::new(body)))
+        .unwrap()
+}
+
+/// A download being streamed to a client. Audited once the body has been
+/// sent or dropped because the client went away.
+struct Download {
+    state: Arc<ServerState>,
+    entry: Option<AuditEntry>,
+    path: String,
+    peer: SocketAddr,
+    user: Option<String>,
+    sent: u64,
+    length: u64,
+    /// Whether the whole file is sent rather than a range of it.
+    whole: bool,
+    /// The share token the download was made through, used up once the
+    /// whole file was sent. Ranges don't count, so probing or resuming with
+    /// them doesn't burn a use.
+    share: Option<ShareClaim>,
+    _transfer: ActiveTransfer,
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        let Some(entry) = self.entry.take() else {
+            return;
+        };
+
+        let complete = self.sent == self.length;
+        if complete {
+            hooks::run(
+                self.state.settings.hooks.on_download.as_deref(),
+                HookEvent {
+                    name: "download",
+                    path: &self.path,
+                    peer: &self.peer,
+                    user: self.user.as_deref(),
+                    size: None,
+                },
+            );
+        } else {
+            counter!("lud_errors_total", "kind" => "download").increment(1);
+        }
+
+        let state = self.state.clone();
+        let sent = self.sent;
+        let share = self.share.take().filter(|_| complete && self.whole);
+        tokio::spawn(async move {
+            if let Some(share) = share
+                && let Err(e) = share.complete().await
+            {
+                log::warn!("Failed to record shared download: {:#}", e);
+            }
+            let error = (!complete).then_some("Client disconnected");
+            audit(&state, entry, Some(sent), error).await;
+        });
+    }
+}
+
+async fn receive_file(
+    request: Request<Incoming>,
+    state: &ServerState,
+    session: &Session<'_>,
+    full_path: &Utf8Path,
+    path: &str,
+) -> Response<Body> {
+    let user_name = session.user.map(|u| u.name.as_str());
+    let entry = AuditEntry::new(session.addr, user_name, "upload", Some(path));
+    counter!("lud_requests_total", "operation" => "upload").increment(1);
+
+    let (status, message, size) = match write_upload(request, state, session, full_path).await {
+        Ok((status, size)) => (status, None, Some(size)),
+        Err((status, message)) => {
+            log::error!("HTTP upload of `{}` failed: {}", full_path, message);
+            counter!("lud_errors_total", "kind" => "upload").increment(1);
+            (status, Some(message), None)
+        }
+    };
+
+    audit(state, entry, size, message.as_deref()).await;
+
+    match message {
+        Some(message) => text(status, &message),
+        None => {
+            hooks::run(
+                state.settings.hooks.on_upload.as_deref(),
+                HookEvent {
+                    name: "upload",
+                    path,
+                    peer: &session.addr,
+                    user: user_name,
+                    size,
+                },
+            );
+            empty_response(status)
+        }
+    }
+}
+
+/// Streams a `PUT` body into place. Returns the response status and size on
+/// success, or the status and message to reply with.
+async fn write_upload(
+    request: Request<Incoming>,
+    state: &ServerState,
+    session: &Session<'_>,
+    full_path: &Utf8Path,
+) -> Result<(StatusCode, u64), (StatusCode, String)> {
+    let settings = &state.settings;
+    let internal = |e: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+
+    if full_path == settings.storage || request.uri().path().ends_with('/') {
+        return Err((StatusCode::CONFLICT, "Cannot upload to a directory".into()));
+    }
+
+    let Some(total_size) = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+    else {
+        return Err((
+            StatusCode::LENGTH_REQUIRED,
+            "Content-Length required".into(),
+        ));
+    };
+
+    if let Some(max) = settings.limits.max_upload_size
+        && total_size > max.0
+    {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("File too large (limit is {})", max),
+        ));
+    }
+
+    let existing = fs::metadata(full_path).await.ok();
+    if existing.as_ref().is_some_and(|m| m.is_dir()) {
+        return Err((StatusCode::CONFLICT, "Path is a directory".into()));
+    }
+    let if_none_match = request.headers().get(header::IF_NONE_MATCH);
+    if existing.is_some() && if_none_match.is_some_and(|value| value == "*") {
+        return Err((
+            StatusCode::PRECONDITION_FAILED,
+            "File already exists".into(),
+        ));
+    }
+
+    let relative_path = relative_path(&settings.storage, full_path);
+    let _reservation = state
+        .quota
+        .reserve(settings, relative_path, session.user, total_size)
+        .await
+        .map_err(|e| (StatusCode::INSUFFICIENT_STORAGE, e.to_string()))?;
+
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent).await.map_err(internal)?;
+    }
+
+    let _transfer = ActiveTransfer::start("upload");
+    let partial = PartialFile::new(full_path);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&partial.path)
+        .await
+        .map_err(internal)?;
+
+    let mut body = request.into_body();
+    let mut received_bytes = 0;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        let Ok(data) = frame.into_data() else {
+            continue;
+        };
+
+        session.throttle.consume(data.len() as u64).await;
+        received_bytes += data.len() as u64;
+        counter!("lud_bytes_received_total").increment(data.len() as u64);
+        if received_bytes > total_size {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Body is larger than Content-Length".into(),
+            ));
+        }
+        file.write_all(&data).await.map_err(internal)?;
+    }
+
+    if received_bytes != total_size {
+        return Err((StatusCode::BAD_REQUEST, "File size mismatch".into()));
+    }
+
+    file.flush().await.map_err(internal)?;
+    partial
+        .persist(full_path)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+
+    if let Err(e) = state
+        .quota
+        .record_upload(relative_path, session.user.map(|u| u.name.as_str()))
+        .await
+    {
+        log::warn!("Failed to record owner of `{}`: {:#}", full_path, e);
+    }
+
+    log::debug!("Saved file `{}` from {} over HTTP", full_path, session.addr);
+
+    let status = if existing.is_some() {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    Ok((status, total_size))
+}
+
+/// Parses a single `bytes=` range into inclusive start and end offsets.
+/// Returns `Ok(None)` for ranges that should be ignored, such as multiple
+/// ranges, and `Err` for ranges outside the file.
+fn parse_range(value: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let (start, end) = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        (Some(start), Some(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Some(start), None) if end.is_empty() => (start, size.saturating_sub(1)),
+        (None, Some(suffix)) if start.is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return Ok(None),
+    };
+
+    if size == 0 || start >= size {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Escapes text for HTML and XML documents.
+pub(super) fn escape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' => output.push_str("&quot;"),
+            '\'' => output.push_str("&#39;"),
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+pub(super) fn io_error(e: &std::io::Error) -> Response<Body> {
+    match e.kind() {
+        ErrorKind::NotFound => text(StatusCode::NOT_FOUND, "Not found"),
+        ErrorKind::PermissionDenied => text(StatusCode::FORBIDDEN, "Permission denied"),
+        _ => text(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+pub(super) fn method_not_allowed(allow: &'static str) -> Response<Body> {
+    let mut response = text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    response
+        .headers_mut()
+        .insert(header::ALLOW, HeaderValue::from_static(allow));
+    response
+}
+
+fn redirect(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, location)
+        .body(empty())
+        .unwrap()
+}
+
+pub(super) fn text(status: StatusCode, message: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(full(format!("{}\n", message)))
+        .unwrap()
+}
+
+pub(super) fn full(body: String) -> Body {
+    Full::new(Bytes::from(body))
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+pub(super) fn empty_response(status: StatusCode) -> Response<Body> {
+    Response::builder().status(status).body(empty()).unwrap()
+}
+
+pub(super) fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server_settings::ServerSettings;
+    use tokio::io::AsyncReadExt;
+
+    /// Sends one raw HTTP request through `handle_connection` and returns
+    /// the raw response.
+    async fn send(state: &Arc<ServerState>, request: &str) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let addr = "127.0.0.1:5000".parse().unwrap();
+        let connection = tokio::spawn(handle_connection(
+            Box::new(server),
+            addr,
+            state.clone(),
+            false,
+        ));
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        connection.await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn ranged_share_downloads_keep_the_token() {
+        let root = std::env::temp_dir().join(format!("lud-share-{}", std::process::id()));
+        let root = camino::Utf8PathBuf::from_path_buf(root).unwrap();
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("report.txt"), "quarterly").unwrap();
+        let state = ServerState::for_tests(ServerSettings {
+            storage: root.clone(),
+            ..Default::default()
+        })
+        .await;
+        let token = state
+            .shares
+            .create(Utf8Path::new("report.txt"), None, None, Some(1))
+            .await
+            .unwrap();
+        let get = |range: &str| {
+            format!(
+                "GET /report.txt?token={} HTTP/1.1\r\nHost: lud\r\n{}Connection: close\r\n\r\n",
+                token, range
+            )
+        };
+
+        let response = send(&state, &get("Range: bytes=0-0\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 206"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nq"), "{}", response);
+
+        let response = send(&state, &get("")).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("quarterly"), "{}", response);
+
+        // Only the whole download used up the token.
+        let response = send(&state, &get("")).await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=500-", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        // Ends and suffixes past the file are clamped to it.
+        assert_eq!(parse_range("bytes=900-5000", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+    }
+
+    #[test]
+    fn ignores_ranges_it_does_not_serve() {
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-9", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=9-0", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=-0", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=abc", 1000), Ok(None));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=1000-1999", 1000), Err(()));
+        assert_eq!(parse_range("bytes=0-", 0), Err(()));
+        assert_eq!(parse_range("bytes=-10", 0), Err(()));
+    }
+}
@@ -0,0 +1,522 @@
+use std::{
+    fmt::Write as _,
+    fs::Metadata,
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use camino::Utf8Path;
+use chrono::{DateTime, Utc};
+use hyper::{
+    Request, Response, StatusCode,
+    body::Incoming,
+    header::{self, HeaderValue},
+};
+use metrics::counter;
+use percent_encoding::percent_decode_str;
+use tokio::fs;
+use walkdir::WalkDir;
+
+use super::{
+    ServerState, Session, audit, http,
+    http::{Body, escape, io_error, text},
+    is_reserved_name, relative_path, resolve_path,
+};
+use crate::{
+    audit::AuditEntry,
+    hooks::{self, HookEvent},
+    quota, utils,
+};
+
+/// How long clients are told their locks last.
+const LOCK_TIMEOUT: &str = "Second-3600";
+
+static NEXT_LOCK: AtomicU64 = AtomicU64::new(0);
+
+/// Serves the WebDAV methods the plain HTTP gateway doesn't know.
+pub async fn handle_request(
+    request: Request<Incoming>,
+    state: &ServerState,
+    session: &Session<'_>,
+    full_path: &Utf8Path,
+    path: &str,
+) -> Response<Body> {
+    let operation = match request.method().as_str() {
+        "OPTIONS" => return options(),
+        "PROPFIND" => return propfind(&request, state, session, full_path, path).await,
+        "LOCK" => return lock(&request, path),
+        "UNLOCK" => return http::empty_response(StatusCode::NO_CONTENT),
+        "MKCOL" => "mkdir",
+        "DELETE" => "remove",
+        "MOVE" => "move",
+        "COPY" => "copy",
+        _ => return http::method_not_allowed(http::WEBDAV_METHODS),
+    };
+
+    counter!("lud_requests_total", "operation" => operation).increment(1);
+    if let Err(response) = http::check_writable(state, session, operation, path).await {
+        return response;
+    }
+    // A move or copy writes to its destination too, which may be elsewhere.
+    if matches!(operation, "move" | "copy")
+        && let Some(destination) = destination(&request)
+        && let Err(response) = http::check_writable(state, session, operation, &destination).await
+    {
+        return response;
+    }
+
+    let user_name = session.user.map(|u| u.name.as_str());
+    let entry = AuditEntry::new(session.addr, user_name, operation, Some(path));
+    let result = match operation {
+        "mkdir" => make_collection(&request, full_path, path).await,
+        "remove" => remove(state, session, full_path, path).await,
+        _ => {
+            transfer(
+                &request,
+                state,
+                session,
+                full_path,
+                path,
+                operation == "copy",
+            )
+            .await
+        }
+    };
+
+    match result {
+        Ok(status) => {
+            audit(state, entry, None, None).await;
+            http::empty_response(status)
+        }
+        Err((status, message)) => {
+            log::error!("WebDAV {} of `{}` failed: {}", operation, path, message);
+            counter!("lud_errors_total", "kind" => operation).increment(1);
+            audit(state, entry, None, Some(&message)).await;
+            text(status, &message)
+        }
+    }
+}
+
+fn options() -> Response<Body> {
+    Response::builder()
+        .header("DAV", "1, 2")
+        .header("MS-Author-Via", "DAV")
+        .header(header::ALLOW, http::WEBDAV_METHODS)
+        .header(header::CONTENT_LENGTH, 0)
+        .body(http::empty())
+        .unwrap()
+}
+
+async fn propfind(
+    request: &Request<Incoming>,
+    state: &ServerState,
+    session: &Session<'_>,
+    full_path: &Utf8Path,
+    path: &str,
+) -> Response<Body> {
+    let children = match request.headers().get("Depth").map(HeaderValue::as_bytes) {
+        Some(b"0") => false,
+        Some(b"infinity") => {
+            return text(StatusCode::FORBIDDEN, "Depth infinity is not supported");
+        }
+        _ => true,
+    };
+
+    let metadata = match fs::metadata(full_path).await {
+        Ok(metadata) => metadata,
+        Err(e) => return io_error(&e),
+    };
+
+    let user_name = session.user.map(|u| u.name.as_str());
+    let entry = AuditEntry::new(session.addr, user_name, "list", Some(path));
+    counter!("lud_requests_total", "operation" => "list").increment(1);
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<D:multistatus xmlns:D=\"DAV:\">\n");
+    write_properties(&mut xml, path, &metadata);
+
+    if children && metadata.is_dir() {
+        let mut entries = Vec::new();
+        if let Ok(mut dir) = fs::read_dir(full_path).await {
+            while let Ok(Some(child)) = dir.next_entry().await {
+                let name = child.file_name().to_string_lossy().into_owned();
+                if is_reserved_name(&name) {
+                    continue;
+                }
+                if let Ok(metadata) = child.metadata().await {
+                    entries.push((name, metadata));
+                }
+            }
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (name, metadata) in entries {
+            let child_path = if path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            };
+            write_properties(&mut xml, &child_path, &metadata);
+        }
+    }
+    xml.push_str("</D:multistatus>\n");
+
+    audit(state, entry, None, None).await;
+    xml_response(StatusCode::MULTI_STATUS, xml)
+}
+
+/// Appends the `response` element describing `path` to a multistatus body.
+fn write_properties(xml: &mut String, path: &str, metadata: &Metadata) {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let modified_secs = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let _ = write!(
+        xml,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}</D:displayname>\
+         <D:getlastmodified>{}</D:getlastmodified>",
+        href(path, metadata.is_dir()),
+        escape(name),
+        http_date(modified)
+    );
+    if metadata.is_dir() {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let _ = write!(
+            xml,
+            "<D:resourcetype/>\
+             <D:getcontentlength>{}</D:getcontentlength>\
+             <D:getcontenttype>application/octet-stream</D:getcontenttype>\
+             <D:getetag>\"{:x}-{:x}\"</D:getetag>",
+            metadata.len(),
+            metadata.len(),
+            modified_secs
+        );
+    }
+    xml.push_str(
+        "<D:supportedlock><D:lockentry>\
+         <D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype>\
+         </D:lockentry></D:supportedlock>\
+         </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+    );
+}
+
+/// Grants every lock request without enforcing it, which is enough for clients
+/// that refuse to write without one.
+fn lock(request: &Request<Incoming>, path: &str) -> Response<Body> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let token = format!(
+        "opaquelocktoken:{:x}-{:x}",
+        nanos,
+        NEXT_LOCK.fetch_add(1, Ordering::Relaxed)
+    );
+    let depth = match request.headers().get("Depth").map(HeaderValue::as_bytes) {
+        Some(b"0") => "0",
+        _ => "infinity",
+    };
+
+    let xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <D:prop xmlns:D=\"DAV:\"><D:lockdiscovery><D:activelock>\
+         <D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:lockscope>\
+         <D:depth>{depth}</D:depth><D:timeout>{LOCK_TIMEOUT}</D:timeout>\
+         <D:locktoken><D:href>{token}</D:href></D:locktoken>\
+         <D:lockroot><D:href>{}</D:href></D:lockroot>\
+         </D:activelock></D:lockdiscovery></D:prop>\n",
+        href(path, false)
+    );
+
+    let mut response = xml_response(StatusCode::OK, xml);
+    let headers = response.headers_mut();
+    headers.insert(
+        "Lock-Token",
+        HeaderValue::from_str(&format!("<{}>", token)).unwrap(),
+    );
+    headers.insert("Timeout", HeaderValue::from_static(LOCK_TIMEOUT));
+    response
+}
+
+async fn make_collection(
+    request: &Request<Incoming>,
+    full_path: &Utf8Path,
+    path: &str,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let has_body = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .is_some_and(|value| value != "0");
+    if has_body {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "MKCOL bodies are not supported".into(),
+        ));
+    }
+
+    if path.is_empty() || fs::try_exists(full_path).await.unwrap_or(false) {
+        return Err((StatusCode::METHOD_NOT_ALLOWED, "Path already exists".into()));
+    }
+
+    match fs::create_dir(full_path).await {
+        Ok(()) => {
+            log::debug!("Created directory `{}` over WebDAV", full_path);
+            Ok(StatusCode::CREATED)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err((
+            StatusCode::CONFLICT,
+            "Parent directory does not exist".into(),
+        )),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+async fn remove(
+    state: &ServerState,
+    session: &Session<'_>,
+    full_path: &Utf8Path,
+    path: &str,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if path.is_empty() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Cannot delete the storage root".into(),
+        ));
+    }
+
+    remove_existing(full_path).await?;
+
+    let storage = &state.settings.storage;
+    if let Err(e) = state
+        .quota
+        .record_remove(relative_path(storage, full_path))
+        .await
+    {
+        log::warn!("Failed to update owners of `{}`: {:#}", full_path, e);
+    }
+
+    log::debug!("Deleted path `{}` over WebDAV", full_path);
+    hooks::run(
+        state.settings.hooks.on_remove.as_deref(),
+        HookEvent {
+            name: "remove",
+            path,
+            peer: &session.addr,
+            user: session.user.map(|u| u.name.as_str()),
+            size: None,
+        },
+    );
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Moves or copies `full_path` to the request's `Destination`.
+async fn transfer(
+    request: &Request<Incoming>,
+    state: &ServerState,
+    session: &Session<'_>,
+    full_path: &Utf8Path,
+    path: &str,
+    copy: bool,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let settings = &state.settings;
+    let internal = |e: io::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+
+    let Some(destination) = destination(request) else {
+        return Err((StatusCode::BAD_REQUEST, "Missing Destination header".into()));
+    };
+    if destination.is_empty() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Cannot overwrite the storage root".into(),
+        ));
+    }
+    let Some(target) = resolve_path(&settings.storage, &destination) else {
+        return Err((StatusCode::FORBIDDEN, "Invalid destination".into()));
+    };
+
+    let source = match fs::metadata(full_path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err((StatusCode::NOT_FOUND, "Not found".into()));
+        }
+        Err(e) => return Err(internal(e)),
+    };
+    if path.is_empty() && !copy {
+        return Err((StatusCode::FORBIDDEN, "Cannot move the storage root".into()));
+    }
+    if target.starts_with(full_path) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Destination is inside the source".into(),
+        ));
+    }
+    // Overwriting a directory containing the source would delete the source
+    // along with it.
+    if target == settings.storage || full_path.starts_with(&target) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Destination contains the source".into(),
+        ));
+    }
+    if !target.parent().is_some_and(|parent| parent.is_dir()) {
+        return Err((
+            StatusCode::CONFLICT,
+            "Parent directory does not exist".into(),
+        ));
+    }
+
+    let exists = fs::try_exists(&target).await.unwrap_or(false);
+    let overwrite = request
+        .headers()
+        .get("Overwrite")
+        .is_none_or(|value| value != "F");
+    if exists && !overwrite {
+        return Err((
+            StatusCode::PRECONDITION_FAILED,
+            "Destination already exists".into(),
+        ));
+    }
+
+    let storage = &settings.storage;
+    let from = relative_path(storage, full_path);
+    let to = relative_path(storage, &target);
+    let size = quota::dir_size(full_path).await;
+    let quota_error = |e: anyhow::Error| (StatusCode::INSUFFICIENT_STORAGE, e.to_string());
+
+    if copy {
+        let _reservation = state
+            .quota
+            .reserve(settings, to, session.user, size)
+            .await
+            .map_err(quota_error)?;
+
+        if exists {
+            remove_existing(&target).await?;
+        }
+        let shallow = request
+            .headers()
+            .get("Depth")
+            .is_some_and(|value| value == "0");
+        let (from_path, to_path) = (full_path.to_owned(), target.clone());
+        tokio::task::spawn_blocking(move || copy_tree(&from_path, &to_path, shallow))
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .map_err(internal)?;
+
+        let user_name = session.user.map(|u| u.name.as_str());
+        if let Err(e) = state.quota.record_copy(to, user_name).await {
+            log::warn!("Failed to record owners of `{}`: {:#}", target, e);
+        }
+    } else {
+        state
+            .quota
+            .check_move(settings, from, to, size)
+            .await
+            .map_err(quota_error)?;
+
+        if exists {
+            remove_existing(&target).await?;
+        }
+        fs::rename(full_path, &target).await.map_err(internal)?;
+
+        if let Err(e) = state.quota.record_move(from, to).await {
+            log::warn!("Failed to update owners of `{}`: {:#}", target, e);
+        }
+    }
+
+    log::debug!(
+        "{} `{}` to `{}` over WebDAV ({} bytes, directory: {})",
+        if copy { "Copied" } else { "Moved" },
+        full_path,
+        target,
+        size,
+        source.is_dir()
+    );
+
+    Ok(if exists {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    })
+}
+
+/// The storage path named by the `Destination` header, which may be an
+/// absolute URL or just a path.
+fn destination(request: &Request<Incoming>) -> Option<String> {
+    let value = request.headers().get("Destination")?.to_str().ok()?;
+    let path = match value.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |index| &rest[index..]),
+        None => value,
+    };
+    let path = path.split(['?', '#']).next()?;
+    let decoded = percent_decode_str(path).decode_utf8().ok()?;
+    Some(decoded.trim_matches('/').to_string())
+}
+
+async fn remove_existing(full_path: &Utf8Path) -> Result<(), (StatusCode, String)> {
+    let result = match fs::metadata(full_path).await {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(full_path).await,
+        Ok(_) => fs::remove_file(full_path).await,
+        Err(e) => Err(e),
+    };
+
+    result.map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => (StatusCode::NOT_FOUND, "Not found".into()),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    })
+}
+
+/// Copies a file, or a directory with everything below it unless `shallow`,
+/// skipping metadata and partial uploads.
+fn copy_tree(from: &Utf8Path, to: &Utf8Path, shallow: bool) -> io::Result<()> {
+    let max_depth = if shallow { 0 } else { usize::MAX };
+    let entries = WalkDir::new(from)
+        .max_depth(max_depth)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !is_reserved_name(&entry.file_name().to_string_lossy())
+        });
+
+    for entry in entries {
+        let entry = entry?;
+        let target = match entry.path().strip_prefix(from) {
+            Ok(relative) if !relative.as_os_str().is_empty() => to.as_std_path().join(relative),
+            _ => to.as_std_path().to_owned(),
+        };
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_file() {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// The URL path of `path`, with a trailing slash for collections.
+fn href(path: &str, is_dir: bool) -> String {
+    let mut href = utils::url_path(path);
+    if is_dir && !href.ends_with('/') {
+        href.push('/');
+    }
+    href
+}
+
+fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+fn xml_response(status: StatusCode, xml: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+        .body(http::full(xml))
+        .unwrap()
+}
@@ -0,0 +1,487 @@
+use std::{collections::HashSet, path::Path, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use camino::Utf8PathBuf;
+use config::{Config, File};
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::{
+    settings,
+    utils::{self, ByteSize, HumanDuration, MAX_CHUNK_SIZE},
+};
+
+const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:4899";
+const DEFAULT_STORAGE: &str = "./storage";
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_CONNECTIONS: usize = 512;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 32;
+const DEFAULT_MAX_STREAMS: usize = 32;
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_PACKET_SIZE: u64 = 4 * 1024 * 1024;
+const DEFAULT_AUDIT_MAX_FILES: usize = 5;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub listen: Vec<String>,
+    pub storage: Utf8PathBuf,
+    /// Address to serve Prometheus metrics on.
+    pub metrics: Option<String>,
+    pub http: HttpSettings,
+    /// Address to serve the storage root over WebDAV on.
+    pub webdav: Option<String>,
+    /// Answer discovery queries from clients on the local network.
+    pub announce: bool,
+    /// Name the server announces itself under, the hostname by default.
+    pub name: Option<String>,
+    pub tls: Option<TlsSettings>,
+    pub users: Vec<User>,
+    pub quota: QuotaSettings,
+    pub limits: LimitSettings,
+    pub logging: LoggingSettings,
+    pub audit: AuditSettings,
+    pub hooks: HookSettings,
+    pub shutdown: ShutdownSettings,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            listen: vec![DEFAULT_LISTEN_ADDR.to_string()],
+            storage: DEFAULT_STORAGE.into(),
+            metrics: None,
+            http: HttpSettings::default(),
+            webdav: None,
+            announce: false,
+            name: None,
+            tls: None,
+            users: Vec::new(),
+            quota: QuotaSettings::default(),
+            limits: LimitSettings::default(),
+            logging: LoggingSettings::default(),
+            audit: AuditSettings::default(),
+            hooks: HookSettings::default(),
+            shutdown: ShutdownSettings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSettings {
+    /// Address to serve the storage root over HTTP on.
+    pub listen: Option<String>,
+    /// Accept `PUT` uploads from users that aren't read-only.
+    pub read_write: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub read_only: bool,
+    /// Most bytes the files uploaded by this user may take up.
+    #[serde(default)]
+    pub quota: Option<ByteSize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaSettings {
+    pub max_storage: Option<ByteSize>,
+    pub paths: Vec<PathQuota>,
+}
+
+/// A quota on everything below `path`, relative to the storage root.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathQuota {
+    pub path: Utf8PathBuf,
+    pub max_storage: ByteSize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    pub max_upload_size: Option<ByteSize>,
+    /// Connections handled at once. Further ones are refused.
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    /// Requests one multiplexed connection may run at once.
+    pub max_streams: usize,
+    /// How long a packet may take to arrive once it has started.
+    pub read_timeout: HumanDuration,
+    pub write_timeout: HumanDuration,
+    /// How long a connection may wait for its next packet.
+    pub idle_timeout: HumanDuration,
+    /// Largest packet accepted, checked against the length prefix before
+    /// anything is allocated.
+    pub max_packet_size: ByteSize,
+    /// Bytes per second shared by all transfers.
+    pub rate_limit: Option<ByteSize>,
+    /// Bytes per second for each connection.
+    pub connection_rate_limit: Option<ByteSize>,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            max_upload_size: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            max_streams: DEFAULT_MAX_STREAMS,
+            read_timeout: HumanDuration(DEFAULT_READ_TIMEOUT),
+            write_timeout: HumanDuration(DEFAULT_WRITE_TIMEOUT),
+            idle_timeout: HumanDuration(DEFAULT_IDLE_TIMEOUT),
+            max_packet_size: ByteSize(DEFAULT_MAX_PACKET_SIZE),
+            rate_limit: None,
+            connection_rate_limit: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSettings {
+    pub level: Option<String>,
+    pub file: Option<Utf8PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditSettings {
+    pub file: Option<Utf8PathBuf>,
+    /// Size at which the file is rotated. Never rotated if unset.
+    pub max_size: Option<ByteSize>,
+    /// Rotated files kept next to the current one.
+    pub max_files: usize,
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self {
+            file: None,
+            max_size: None,
+            max_files: DEFAULT_AUDIT_MAX_FILES,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookSettings {
+    pub on_upload: Option<String>,
+    pub on_download: Option<String>,
+    pub on_remove: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSettings {
+    /// How long active transfers may keep running after a shutdown signal.
+    pub grace_period: HumanDuration,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            grace_period: HumanDuration(DEFAULT_GRACE_PERIOD),
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Checks settings that would otherwise only fail once the server is running.
+    pub fn validate(&self) -> Result<()> {
+        if self.listen.is_empty() {
+            bail!("`listen` must contain at least one address");
+        }
+        for addr in &self.listen {
+            if !settings::is_server_addr(addr) {
+                bail!("Invalid listen address `{}` (expected `host:port`)", addr);
+            }
+        }
+
+        if let Some(addr) = &self.metrics
+            && !settings::is_server_addr(addr)
+        {
+            bail!("Invalid metrics address `{}` (expected `host:port`)", addr);
+        }
+
+        if let Some(addr) = &self.http.listen
+            && !settings::is_server_addr(addr)
+        {
+            bail!("Invalid HTTP address `{}` (expected `host:port`)", addr);
+        }
+        if self.http.read_write && self.users.is_empty() {
+            bail!("`http.read_write` requires `users` so uploads are authenticated");
+        }
+
+        if let Some(addr) = &self.webdav
+            && !settings::is_server_addr(addr)
+        {
+            bail!("Invalid WebDAV address `{}` (expected `host:port`)", addr);
+        }
+        if self.webdav.is_some() && self.users.is_empty() {
+            bail!("`webdav` requires `users` so writes are authenticated");
+        }
+
+        if self
+            .name
+            .as_deref()
+            .is_some_and(|name| name.trim().is_empty())
+        {
+            bail!("`name` must not be empty");
+        }
+
+        if self.storage.as_str().is_empty() {
+            bail!("`storage` must not be empty");
+        }
+
+        if let Some(tls) = &self.tls {
+            for path in [&tls.cert, &tls.key] {
+                if !path.is_file() {
+                    bail!("TLS file `{}` does not exist", path.display());
+                }
+            }
+        }
+
+        let mut names = HashSet::new();
+        for user in &self.users {
+            if user.name.is_empty() {
+                bail!("User names must not be empty");
+            }
+            if user.token.is_empty() {
+                bail!("User `{}` has an empty token", user.name);
+            }
+            if !names.insert(&user.name) {
+                bail!("User `{}` is defined more than once", user.name);
+            }
+            if user.quota == Some(ByteSize(0)) {
+                bail!("Quota of user `{}` must be greater than zero", user.name);
+            }
+        }
+
+        if self.quota.max_storage == Some(ByteSize(0)) {
+            bail!("`quota.max_storage` must be greater than zero");
+        }
+        let mut paths = HashSet::new();
+        for quota in &self.quota.paths {
+            let path = utils::safe_join(&self.storage, quota.path.as_str());
+            if quota.path.as_str().is_empty() || path.as_deref() == Some(&self.storage) {
+                bail!(
+                    "Quota path must not be empty, use `quota.max_storage` for the whole storage"
+                );
+            }
+            let Some(path) = path else {
+                bail!(
+                    "Quota path `{}` is outside the storage directory",
+                    quota.path
+                );
+            };
+            if !paths.insert(path) {
+                bail!("Quota path `{}` is defined more than once", quota.path);
+            }
+            if quota.max_storage == ByteSize(0) {
+                bail!("Quota of `{}` must be greater than zero", quota.path);
+            }
+        }
+        if self.limits.max_upload_size == Some(ByteSize(0)) {
+            bail!("`limits.max_upload_size` must be greater than zero");
+        }
+        for (name, value) in [
+            ("rate_limit", self.limits.rate_limit),
+            ("connection_rate_limit", self.limits.connection_rate_limit),
+        ] {
+            if value == Some(ByteSize(0)) {
+                bail!("`limits.{}` must be greater than zero", name);
+            }
+        }
+        for (name, value) in [
+            ("max_connections", self.limits.max_connections),
+            ("max_connections_per_ip", self.limits.max_connections_per_ip),
+            ("max_streams", self.limits.max_streams),
+        ] {
+            if value == 0 {
+                bail!("`limits.{}` must be greater than zero", name);
+            }
+        }
+        for (name, value) in [
+            ("read_timeout", self.limits.read_timeout),
+            ("write_timeout", self.limits.write_timeout),
+            ("idle_timeout", self.limits.idle_timeout),
+        ] {
+            if value.0.is_zero() {
+                bail!("`limits.{}` must be greater than zero", name);
+            }
+        }
+        // Leave room for the packet header around a full chunk.
+        let min_packet_size = ByteSize(2 * MAX_CHUNK_SIZE as u64);
+        if self.limits.max_packet_size < min_packet_size {
+            bail!(
+                "`limits.max_packet_size` must be at least {}",
+                min_packet_size
+            );
+        }
+
+        if let Some(level) = &self.logging.level
+            && level.parse::<LevelFilter>().is_err()
+        {
+            bail!("Invalid log level `{}`", level);
+        }
+
+        if self.audit.max_size == Some(ByteSize(0)) {
+            bail!("`audit.max_size` must be greater than zero");
+        }
+        if self.audit.max_files == 0 {
+            bail!("`audit.max_files` must be greater than zero");
+        }
+
+        for (name, hook) in [
+            ("on_upload", &self.hooks.on_upload),
+            ("on_download", &self.hooks.on_download),
+            ("on_remove", &self.hooks.on_remove),
+        ] {
+            if hook.as_deref().is_some_and(|cmd| cmd.trim().is_empty()) {
+                bail!("Hook `{}` must not be empty", name);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validates the settings and brings them into the form the server uses.
+    pub(crate) fn prepare(&mut self) -> Result<()> {
+        self.validate()?;
+
+        // Compare quota paths in the same form as the paths clients send.
+        for quota in &mut self.quota.paths {
+            if let Some(path) = utils::safe_join(&self.storage, quota.path.as_str())
+                && let Ok(relative) = path.strip_prefix(&self.storage)
+            {
+                quota.path = relative.to_owned();
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.logging
+            .level
+            .as_deref()
+            .and_then(|level| level.parse().ok())
+    }
+
+    /// Returns the user matching `name` if `token` is correct.
+    pub fn authenticate(&self, name: &str, token: &str) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|user| user.name == name)
+            .filter(|user| constant_time_eq(user.token.as_bytes(), token.as_bytes()))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Settings given on the `lud ln` command line, which take precedence over the
+/// config file.
+#[derive(Debug, Default)]
+pub struct ServerOverrides {
+    pub addr: Option<String>,
+    pub output: Option<Utf8PathBuf>,
+    pub metrics: Option<String>,
+    pub http: Option<String>,
+    pub webdav: Option<String>,
+    pub announce: bool,
+}
+
+/// Loads the server config file, if any, and applies command line overrides.
+pub fn load_server_settings(
+    path: Option<&Path>,
+    overrides: ServerOverrides,
+) -> Result<ServerSettings> {
+    let mut settings = match path {
+        Some(path) => Config::builder()
+            .add_source(File::from(path))
+            .build()?
+            .try_deserialize()
+            .context(format!("Invalid server config `{}`", path.display()))?,
+        None => ServerSettings::default(),
+    };
+
+    if let Some(addr) = overrides.addr {
+        settings.listen = vec![addr];
+    }
+    if let Some(output) = overrides.output {
+        settings.storage = output;
+    }
+    if let Some(metrics) = overrides.metrics {
+        settings.metrics = Some(metrics);
+    }
+    if let Some(http) = overrides.http {
+        settings.http.listen = Some(http);
+    }
+    if let Some(webdav) = overrides.webdav {
+        settings.webdav = Some(webdav);
+    }
+    if overrides.announce {
+        settings.announce = true;
+    }
+
+    settings.prepare()?;
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            token: "secret".to_string(),
+            read_only: false,
+            quota: None,
+        }
+    }
+
+    #[test]
+    fn requires_users_for_writes_over_http() {
+        let settings = ServerSettings {
+            webdav: Some("127.0.0.1:8081".to_string()),
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+
+        let settings = ServerSettings {
+            http: HttpSettings {
+                listen: Some("127.0.0.1:8080".to_string()),
+                read_write: true,
+            },
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+
+        let settings = ServerSettings {
+            webdav: Some("127.0.0.1:8081".to_string()),
+            users: vec![user("alice")],
+            ..Default::default()
+        };
+        settings.validate().unwrap();
+    }
+}
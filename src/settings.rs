@@ -1,60 +1,537 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use config::{Config, File};
 use serde::Deserialize;
 use std::{
-    env,
+    env, fs,
     path::{Path, PathBuf},
 };
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table, value};
 
-#[derive(Debug, Deserialize)]
+use crate::utils::{self, HumanDuration};
+
+const APP_NAME: &str = env!("CARGO_PKG_NAME");
+const CONFIG_FILE_NAME: &str = "lud.toml";
+const SERVER_ENV_PREFIX: &str = "LUD_SERVERS__";
+
+const CONFIG_TEMPLATE: &str = r#"# Servers available to lud. Add more with `lud config add <name> <addr>`.
+# The server marked `default = true` is used unless `--server` is given.
+#
+# [[servers]]
+# name = "local"
+# addr = "127.0.0.1:4899"
+# default = true
+# retries = 3 # retry requests failing on a network error
+
+# Relay used by `lud send` and `lud receive` to pair peers.
+#
+# relay = "relay.example.com:4900"
+"#;
+
+#[derive(Debug, Default, Deserialize)]
 pub struct Settings {
+    #[serde(default)]
     pub servers: Vec<Server>,
+    pub relay: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Server {
     pub name: String,
     pub addr: String,
     #[serde(default)]
     pub default: bool,
+    #[serde(default)]
+    pub tls: bool,
+    pub ca_cert: Option<PathBuf>,
+    pub user: Option<String>,
+    pub token: Option<String>,
+    /// How often a request failing on a network error is retried.
+    pub retries: Option<u32>,
+    /// Delay before the first retry, doubled for each one after it.
+    pub retry_delay: Option<HumanDuration>,
+}
+
+impl Settings {
+    /// Looks up a configured server by name, falling back to its address.
+    pub fn find_server(&self, name_or_addr: &str) -> Option<&Server> {
+        self.servers
+            .iter()
+            .find(|server| server.name == name_or_addr)
+            .or_else(|| {
+                self.servers
+                    .iter()
+                    .find(|server| server.addr == name_or_addr)
+            })
+    }
+
+    pub fn server_names(&self) -> String {
+        self.servers
+            .iter()
+            .map(|server| server.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Whether `addr` has the `host:port` shape expected for a server address.
+pub fn is_server_addr(addr: &str) -> bool {
+    match addr.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
 }
 
-fn get_search_paths() -> Result<Vec<PathBuf>> {
-    let mut search_paths: Vec<PathBuf> = vec![env::current_dir()?];
+impl Settings {
+    /// Overlays `other` on top of these settings. Servers are merged by name so a
+    /// later layer can add or replace servers without hiding the others, while a
+    /// later relay replaces an earlier one.
+    fn merge(&mut self, other: Settings) {
+        if other.relay.is_some() {
+            self.relay = other.relay;
+        }
+        for server in other.servers {
+            if server.default {
+                self.servers.iter_mut().for_each(|s| s.default = false);
+            }
+            match self.servers.iter_mut().find(|s| s.name == server.name) {
+                Some(existing) => *existing = server,
+                None => self.servers.push(server),
+            }
+        }
+    }
+
+    /// Applies `LUD_SERVERS__<NAME>__<FIELD>` overrides (e.g. `ADDR`, `DEFAULT`
+    /// or `TOKEN`), adding the server if no layer defines it.
+    fn apply_env_overrides(&mut self) -> Result<()> {
+        let mut overrides: Vec<(String, String)> = env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .filter(|(key, _)| key.starts_with(SERVER_ENV_PREFIX))
+            .collect();
+        overrides.sort();
+
+        for (key, value) in overrides {
+            let Some((name, field)) = key[SERVER_ENV_PREFIX.len()..].rsplit_once("__") else {
+                bail!(
+                    "Invalid override `{}` (expected `{}<NAME>__<FIELD>`)",
+                    key,
+                    SERVER_ENV_PREFIX
+                );
+            };
+
+            let index = match self
+                .servers
+                .iter()
+                .position(|s| s.name.replace('-', "_").eq_ignore_ascii_case(name))
+            {
+                Some(index) => index,
+                None => {
+                    self.servers.push(Server {
+                        name: name.to_lowercase(),
+                        ..Default::default()
+                    });
+                    self.servers.len() - 1
+                }
+            };
+
+            match field {
+                "ADDR" => self.servers[index].addr = value,
+                "DEFAULT" => {
+                    let default = value
+                        .parse()
+                        .context(format!("Invalid boolean `{}` in `{}`", value, key))?;
+                    if default {
+                        self.servers.iter_mut().for_each(|s| s.default = false);
+                    }
+                    self.servers[index].default = default;
+                }
+                "TLS" => {
+                    self.servers[index].tls = value
+                        .parse()
+                        .context(format!("Invalid boolean `{}` in `{}`", value, key))?;
+                }
+                "CA_CERT" => self.servers[index].ca_cert = Some(value.into()),
+                "USER" => self.servers[index].user = Some(value),
+                "TOKEN" => self.servers[index].token = Some(value),
+                "RETRIES" => {
+                    let retries = value
+                        .parse()
+                        .context(format!("Invalid number `{}` in `{}`", value, key))?;
+                    self.servers[index].retries = Some(retries);
+                }
+                "RETRY_DELAY" => {
+                    let delay = utils::parse_duration(&value)
+                        .context(format!("Invalid duration `{}` in `{}`", value, key))?;
+                    self.servers[index].retry_delay = Some(HumanDuration(delay));
+                }
+                _ => bail!("Unknown server field `{}` in `{}`", field, key),
+            }
+        }
+
+        if let Some(server) = self.servers.iter().find(|s| s.addr.is_empty()) {
+            bail!(
+                "Server `{}` has no address (set `{}{}__ADDR`)",
+                server.name,
+                SERVER_ENV_PREFIX,
+                server.name.to_uppercase().replace('-', "_")
+            );
+        }
+
+        Ok(())
+    }
+}
 
+fn system_config_file() -> Option<PathBuf> {
     #[cfg(unix)]
     {
-        if let Some(home) = env::var_os("HOME") {
-            let config_path = Path::new(&home).join(".config");
-            search_paths.push(config_path);
-        }
+        Some(Path::new("/etc").join(APP_NAME).join(CONFIG_FILE_NAME))
+    }
+
+    #[cfg(windows)]
+    {
+        env::var_os("PROGRAMDATA")
+            .map(|data| Path::new(&data).join(APP_NAME).join(CONFIG_FILE_NAME))
+    }
+}
+
+/// Where a new user-level config file is created.
+fn user_config_file() -> Option<PathBuf> {
+    #[cfg(unix)]
+    {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| Path::new(dir).is_absolute())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config_home.join(APP_NAME).join(CONFIG_FILE_NAME))
     }
 
     #[cfg(windows)]
     {
-        if let Some(appdata) = env::var_os("APPDATA") {
-            let config_path = Path::new(&appdata)
-                .join(env!("CARGO_PKG_NAME"))
-                .join("Config");
-            search_paths.push(config_path);
+        env::var_os("APPDATA").map(|appdata| {
+            Path::new(&appdata)
+                .join(APP_NAME)
+                .join("Config")
+                .join(CONFIG_FILE_NAME)
+        })
+    }
+}
+
+/// User-level config files in merge order, including the pre-XDG location.
+fn user_config_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+
+    #[cfg(unix)]
+    if let Some(home) = env::var_os("HOME") {
+        files.push(Path::new(&home).join(".config").join(CONFIG_FILE_NAME));
+    }
+
+    files.extend(user_config_file());
+    files
+}
+
+/// The nearest `lud.toml` in the current directory or one of its parents.
+fn project_config_file() -> Result<Option<PathBuf>> {
+    let cwd = env::current_dir()?;
+    Ok(cwd
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE_NAME))
+        .find(|file| file.is_file()))
+}
+
+/// Existing config files in the order they are merged, lowest priority first.
+fn config_layers(explicit: Option<&Path>) -> Result<Vec<PathBuf>> {
+    let mut candidates: Vec<PathBuf> = Vec::new();
+    candidates.extend(system_config_file());
+    candidates.extend(user_config_files());
+    candidates.extend(project_config_file()?);
+
+    if let Some(explicit) = explicit {
+        if !explicit.is_file() {
+            bail!("Config file `{}` does not exist", explicit.display());
+        }
+        candidates.push(explicit.to_path_buf());
+    }
+
+    let mut layers: Vec<PathBuf> = Vec::new();
+    let mut seen = Vec::new();
+    for file in candidates.into_iter().filter(|file| file.is_file()) {
+        let canonical = fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+        if !seen.contains(&canonical) {
+            seen.push(canonical);
+            layers.push(file);
         }
     }
 
-    Ok(search_paths)
+    Ok(layers)
+}
+
+fn load_layer(path: &Path) -> Result<Settings> {
+    Config::builder()
+        .add_source(File::from(path))
+        .build()?
+        .try_deserialize()
+        .context(format!("Invalid config file `{}`", path.display()))
+}
+
+/// Loads the system, user, project and explicit config files, then applies
+/// environment overrides.
+pub fn load_settings(explicit: Option<&Path>) -> Result<Settings> {
+    let mut settings = Settings::default();
+
+    for layer in config_layers(explicit)? {
+        log::debug!("Loading config file `{}`", layer.display());
+        settings.merge(load_layer(&layer)?);
+    }
+
+    settings.apply_env_overrides()?;
+    Ok(settings)
 }
 
-pub fn try_load_config_file() -> Result<Config> {
-    let paths = get_search_paths()?;
+/// The config file `lud config` edits: the explicit file, the project file, the
+/// existing user file, or a new user file, in that order.
+pub fn config_file_path(explicit: Option<&Path>) -> Result<PathBuf> {
+    if let Some(explicit) = explicit {
+        return Ok(explicit.to_path_buf());
+    }
+
+    if let Some(file) = project_config_file()? {
+        return Ok(file);
+    }
 
-    let mut builder = Config::builder();
+    let mut user_files = user_config_files();
+    user_files.reverse();
+    if let Some(file) = user_files.iter().find(|file| file.is_file()) {
+        return Ok(file.clone());
+    }
 
-    for path in paths {
-        let file = path.join("lud.toml");
-        if file.exists() {
-            builder = builder.add_source(File::from(file));
-            break;
+    user_config_file().context("Failed to determine config file location")
+}
+
+fn read_document(path: &Path) -> Result<DocumentMut> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).context(format!("Failed to read `{}`", path.display())),
+    };
+
+    contents
+        .parse()
+        .context(format!("Failed to parse `{}`", path.display()))
+}
+
+fn write_document(path: &Path, doc: &DocumentMut) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .context(format!("Failed to create directory `{}`", parent.display()))?;
+    }
+
+    fs::write(path, doc.to_string()).context(format!("Failed to write `{}`", path.display()))
+}
+
+fn servers_mut(doc: &mut DocumentMut) -> Result<&mut ArrayOfTables> {
+    doc.entry("servers")
+        .or_insert(Item::ArrayOfTables(ArrayOfTables::new()))
+        .as_array_of_tables_mut()
+        .context("`servers` must be an array of tables")
+}
+
+fn server_name(table: &Table) -> Option<&str> {
+    table.get("name").and_then(Item::as_str)
+}
+
+pub fn init_config_file(config: Option<&Path>) -> Result<()> {
+    let path = config_file_path(config)?;
+    if path.exists() {
+        bail!("Config file `{}` already exists", path.display());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .context(format!("Failed to create directory `{}`", parent.display()))?;
+    }
+    fs::write(&path, CONFIG_TEMPLATE).context(format!("Failed to write `{}`", path.display()))?;
+
+    log::info!("Created config file `{}`", path.display());
+    Ok(())
+}
+
+pub fn add_server(
+    config: Option<&Path>,
+    name: String,
+    addr: String,
+    default: bool,
+    tls: bool,
+) -> Result<()> {
+    if name.trim().is_empty() {
+        bail!("Server name must not be empty");
+    }
+    if !is_server_addr(&addr) {
+        bail!("Invalid server address `{}` (expected `host:port`)", addr);
+    }
+
+    let path = config_file_path(config)?;
+    let mut doc = read_document(&path)?;
+
+    // Comments in a file without tables trail the document; keep them above the first server.
+    let header = match doc.get("servers") {
+        None => doc.trailing().as_str().unwrap_or_default().to_string(),
+        Some(_) => String::new(),
+    };
+    if !header.is_empty() {
+        doc.set_trailing("");
+    }
+
+    let servers = servers_mut(&mut doc)?;
+
+    if servers
+        .iter()
+        .any(|table| server_name(table) == Some(name.as_str()))
+    {
+        bail!("Server `{}` already exists", name);
+    }
+
+    if default {
+        for table in servers.iter_mut() {
+            table.remove("default");
         }
     }
 
-    Ok(builder.build()?)
+    let mut table = Table::new();
+    if !header.is_empty() {
+        table
+            .decor_mut()
+            .set_prefix(format!("{}\n\n", header.trim_end()));
+    }
+    table["name"] = value(&name);
+    table["addr"] = value(&addr);
+    if default {
+        table["default"] = value(true);
+    }
+    if tls {
+        table["tls"] = value(true);
+    }
+    servers.push(table);
+
+    write_document(&path, &doc)?;
+
+    log::info!("Added server `{}` ({}) to `{}`", name, addr, path.display());
+    Ok(())
+}
+
+pub fn remove_server(config: Option<&Path>, name: String) -> Result<()> {
+    let path = config_file_path(config)?;
+    let mut doc = read_document(&path)?;
+    let servers = servers_mut(&mut doc)?;
+
+    let Some(index) = servers
+        .iter()
+        .position(|table| server_name(table) == Some(name.as_str()))
+    else {
+        bail!("Server `{}` not found in `{}`", name, path.display());
+    };
+    servers.remove(index);
+
+    write_document(&path, &doc)?;
+
+    log::info!("Removed server `{}` from `{}`", name, path.display());
+    Ok(())
+}
+
+pub fn set_default_server(config: Option<&Path>, name: String) -> Result<()> {
+    let path = config_file_path(config)?;
+    let mut doc = read_document(&path)?;
+    let servers = servers_mut(&mut doc)?;
+
+    if !servers
+        .iter()
+        .any(|table| server_name(table) == Some(name.as_str()))
+    {
+        bail!("Server `{}` not found in `{}`", name, path.display());
+    }
+
+    for table in servers.iter_mut() {
+        if server_name(table) == Some(name.as_str()) {
+            table["default"] = value(true);
+        } else {
+            table.remove("default");
+        }
+    }
+
+    write_document(&path, &doc)?;
+
+    log::info!("Set `{}` as the default server", name);
+    Ok(())
+}
+
+pub fn list_servers(config: Option<&Path>) -> Result<()> {
+    let settings = load_settings(config)?;
+    utils::pretty_print_servers(&settings.servers);
+    Ok(())
+}
+
+pub fn print_config_path(config: Option<&Path>) -> Result<()> {
+    println!("{}", config_file_path(config)?.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(name: &str, addr: &str, default: bool) -> Server {
+        Server {
+            name: name.to_string(),
+            addr: addr.to_string(),
+            default,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merges_servers_by_name() {
+        let mut settings = Settings {
+            servers: vec![
+                server("home", "10.0.0.1:4899", true),
+                server("lab", "10.0.0.2:4899", false),
+            ],
+            relay: Some("relay.example.com:4900".to_string()),
+        };
+        settings.merge(Settings {
+            servers: vec![
+                server("lab", "10.0.0.3:4899", false),
+                server("ci", "10.0.0.4:4899", false),
+            ],
+            relay: None,
+        });
+
+        let servers: Vec<_> = settings
+            .servers
+            .iter()
+            .map(|s| (s.name.as_str(), s.addr.as_str(), s.default))
+            .collect();
+        assert_eq!(
+            servers,
+            [
+                ("home", "10.0.0.1:4899", true),
+                ("lab", "10.0.0.3:4899", false),
+                ("ci", "10.0.0.4:4899", false),
+            ]
+        );
+        assert_eq!(settings.relay.as_deref(), Some("relay.example.com:4900"));
+    }
+
+    #[test]
+    fn later_default_and_relay_win() {
+        let mut settings = Settings {
+            servers: vec![server("home", "10.0.0.1:4899", true)],
+            relay: Some("old.example.com:4900".to_string()),
+        };
+        settings.merge(Settings {
+            servers: vec![server("lab", "10.0.0.2:4899", true)],
+            relay: Some("new.example.com:4900".to_string()),
+        });
+
+        let defaults: Vec<_> = settings.servers.iter().map(|s| s.default).collect();
+        assert_eq!(defaults, [false, true]);
+        assert_eq!(settings.relay.as_deref(), Some("new.example.com:4900"));
+    }
 }
@@ -0,0 +1,198 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::ErrorKind,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Error, Result, bail};
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::server;
+
+/// Share tokens still valid, kept in the metadata directory.
+const SHARES_FILE: &str = "shares.json";
+
+/// A file anyone holding the token may download.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Share {
+    path: String,
+    created_by: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    max_downloads: Option<u32>,
+    downloads: u32,
+}
+
+impl Share {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Issues and redeems share tokens. Paths are relative to the storage root.
+pub struct ShareStore {
+    root: Utf8PathBuf,
+    shares: tokio::sync::Mutex<BTreeMap<String, Share>>,
+    /// Downloads of each token still running, which count toward its limit.
+    in_flight: Mutex<HashMap<String, u32>>,
+}
+
+/// A download through a share token. Dropping it without calling `complete`
+/// gives the download back.
+pub struct ShareClaim {
+    store: Arc<ShareStore>,
+    token: String,
+    pub path: String,
+}
+
+impl Drop for ShareClaim {
+    fn drop(&mut self) {
+        let mut in_flight = self.store.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.token) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.token);
+            }
+        }
+    }
+}
+
+impl ShareClaim {
+    /// Counts the download, revoking the token once it reaches its limit.
+    pub async fn complete(self) -> Result<()> {
+        let mut shares = self.store.shares.lock().await;
+        let Some(share) = shares.get_mut(&self.token) else {
+            return Ok(());
+        };
+
+        share.downloads += 1;
+        if share
+            .max_downloads
+            .is_some_and(|max| share.downloads >= max)
+        {
+            log::info!("Share token for `{}` used up", share.path);
+            shares.remove(&self.token);
+        }
+        self.store.save(&shares).await
+    }
+}
+
+impl ShareStore {
+    pub async fn load(root: &Utf8Path) -> Result<Self> {
+        let path = shares_path(root);
+        let mut shares: BTreeMap<String, Share> = match fs::read(&path).await {
+            Ok(bytes) => {
+                serde_json::from_slice(&bytes).context(format!("Invalid share index `{}`", path))?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(Error::new(e).context(format!("Failed to read `{}`", path)));
+            }
+        };
+
+        let now = Utc::now();
+        shares.retain(|_, share| !share.is_expired(now));
+
+        Ok(Self {
+            root: root.to_owned(),
+            shares: tokio::sync::Mutex::new(shares),
+            in_flight: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Issues a token for downloading `path`, valid for `expires_in` and
+    /// `max_downloads` when set.
+    pub async fn create(
+        &self,
+        path: &Utf8Path,
+        user: Option<&str>,
+        expires_in: Option<Duration>,
+        max_downloads: Option<u32>,
+    ) -> Result<String> {
+        let now = Utc::now();
+        let expires_at = match expires_in {
+            Some(duration) => {
+                Some(now + chrono::Duration::from_std(duration).context("Expiry is too far away")?)
+            }
+            None => None,
+        };
+
+        let token: String = rand::rng()
+            .random::<[u8; 16]>()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let mut shares = self.shares.lock().await;
+        shares.retain(|_, share| !share.is_expired(now));
+        shares.insert(
+            token.clone(),
+            Share {
+                path: path.to_string(),
+                created_by: user.map(Into::into),
+                expires_at,
+                max_downloads,
+                downloads: 0,
+            },
+        );
+        self.save(&shares).await?;
+
+        Ok(token)
+    }
+
+    /// Starts a download through `token`, or fails if the token is unknown,
+    /// expired or used up.
+    pub async fn claim(self: &Arc<Self>, token: &str) -> Result<ShareClaim> {
+        let mut shares = self.shares.lock().await;
+        let Some(share) = shares.get(token) else {
+            bail!("Invalid or expired share token");
+        };
+
+        if share.is_expired(Utc::now()) {
+            shares.remove(token);
+            self.save(&shares).await?;
+            bail!("Invalid or expired share token");
+        }
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let running = in_flight.get(token).copied().unwrap_or(0);
+        if share
+            .max_downloads
+            .is_some_and(|max| share.downloads + running >= max)
+        {
+            bail!("Share token is already being used");
+        }
+        in_flight.insert(token.to_string(), running + 1);
+
+        Ok(ShareClaim {
+            store: self.clone(),
+            token: token.to_string(),
+            path: share.path.clone(),
+        })
+    }
+
+    async fn save(&self, shares: &BTreeMap<String, Share>) -> Result<()> {
+        let path = shares_path(&self.root);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .context(format!("Failed to create `{}`", parent))?;
+        }
+
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(shares)?)
+            .await
+            .context(format!("Failed to write `{}`", temp))?;
+        fs::rename(&temp, &path)
+            .await
+            .context(format!("Failed to write `{}`", path))
+    }
+}
+
+fn shares_path(root: &Utf8Path) -> Utf8PathBuf {
+    root.join(server::META_DIR).join(SHARES_FILE)
+}
@@ -0,0 +1,88 @@
+use std::net::ToSocketAddrs;
+
+use anyhow::{Context, Result, anyhow};
+use metrics::{Unit, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use tokio::time::Instant;
+
+const DURATION_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+
+/// Serves Prometheus metrics over HTTP on `addr`.
+pub fn install(addr: &str) -> Result<()> {
+    let socket_addr = addr
+        .to_socket_addrs()
+        .context(format!("Invalid metrics address `{}`", addr))?
+        .next()
+        .ok_or_else(|| anyhow!("Invalid metrics address `{}`", addr))?;
+
+    PrometheusBuilder::new()
+        .with_http_listener(socket_addr)
+        .set_buckets_for_metric(
+            Matcher::Full("lud_transfer_duration_seconds".to_string()),
+            DURATION_BUCKETS,
+        )?
+        .install()
+        .context(format!("Failed to start metrics endpoint on {}", addr))?;
+
+    describe_counter!("lud_connections_total", "Connections accepted");
+    describe_counter!(
+        "lud_connections_refused_total",
+        "Connections refused because of connection limits"
+    );
+    describe_gauge!("lud_connections_active", "Connections currently open");
+    describe_counter!("lud_requests_total", "Requests handled by operation");
+    describe_counter!(
+        "lud_errors_total",
+        "Errors by kind: tls, protocol, auth, permission or the failed operation"
+    );
+    describe_counter!(
+        "lud_bytes_received_total",
+        Unit::Bytes,
+        "File data received from clients"
+    );
+    describe_counter!(
+        "lud_bytes_sent_total",
+        Unit::Bytes,
+        "File data sent to clients"
+    );
+    describe_histogram!(
+        "lud_transfer_duration_seconds",
+        Unit::Seconds,
+        "Duration of uploads and downloads"
+    );
+    describe_gauge!("lud_transfers_active", "Uploads and downloads in progress");
+    describe_gauge!(
+        "lud_storage_used_bytes",
+        Unit::Bytes,
+        "Bytes stored below the storage root"
+    );
+
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    Ok(())
+}
+
+/// Counts a transfer as active until dropped, then records its duration.
+pub struct ActiveTransfer {
+    operation: &'static str,
+    started: Instant,
+}
+
+impl ActiveTransfer {
+    pub fn start(operation: &'static str) -> Self {
+        gauge!("lud_transfers_active", "operation" => operation).increment(1.0);
+        Self {
+            operation,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        gauge!("lud_transfers_active", "operation" => self.operation).decrement(1.0);
+        histogram!("lud_transfer_duration_seconds", "operation" => self.operation)
+            .record(self.started.elapsed().as_secs_f64());
+    }
+}
@@ -0,0 +1,88 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, Result, anyhow};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    TlsAcceptor, TlsConnector,
+    client::TlsStream,
+    rustls::{
+        ClientConfig, RootCertStore, ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+    },
+};
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("{}", e))
+        .context(format!(
+            "Failed to read certificates from `{}`",
+            path.display()
+        ))?;
+
+    if certs.is_empty() {
+        return Err(anyhow!("No certificates found in `{}`", path.display()));
+    }
+
+    Ok(certs)
+}
+
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let certs = load_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| anyhow!("{}", e))
+        .context(format!(
+            "Failed to read private key from `{}`",
+            key.display()
+        ))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds a connector trusting the system roots plus `ca_cert`, if given.
+pub fn connector(ca_cert: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+
+    if let Some(ca_cert) = ca_cert {
+        for cert in load_certs(ca_cert)? {
+            roots
+                .add(cert)
+                .context(format!("Invalid CA certificate in `{}`", ca_cert.display()))?;
+        }
+    }
+
+    if roots.is_empty() {
+        return Err(anyhow!("No trusted certificates available (set `ca_cert`)"));
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+pub async fn connect(
+    stream: TcpStream,
+    addr: &str,
+    ca_cert: Option<&Path>,
+) -> Result<TlsStream<TcpStream>> {
+    let host = addr
+        .rsplit_once(':')
+        .map_or(addr, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let server_name = ServerName::try_from(host.to_string())
+        .context(format!("Invalid TLS server name `{}`", host))?;
+
+    connector(ca_cert)?
+        .connect(server_name, stream)
+        .await
+        .context("TLS handshake failed")
+}
@@ -1,8 +1,64 @@
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use anyhow::{Result, anyhow};
 use camino::{Utf8Path, Utf8PathBuf};
+use globset::GlobBuilder;
 use humansize::{BINARY, format_size};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use serde::{Deserialize, Deserializer, de};
 use tabwriter::TabWriter;
+use walkdir::WalkDir;
+
+use crate::{
+    discovery::Discovered,
+    server::{File, Space, Usage},
+    settings::Server,
+};
+
+/// Characters escaped in a segment of a URL path.
+pub const URL_PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/');
+
+/// Percent-encodes a storage path for use in a URL, with a leading slash.
+pub fn url_path(path: &str) -> String {
+    let mut url = String::new();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        url.push('/');
+        url.extend(utf8_percent_encode(segment, URL_PATH_SEGMENT));
+    }
+    if url.is_empty() {
+        url.push('/');
+    }
+    url
+}
 
-use crate::server::File;
+/// The base URL of an HTTP gateway listening at `gateway`, such as
+/// `http://0.0.0.0:8080`. A gateway listening on every address is reached
+/// through the host the server at `server_addr` was reached by.
+pub fn gateway_url(gateway: &str, server_addr: &str) -> String {
+    let Some((scheme, listen)) = gateway.split_once("://") else {
+        return gateway.to_string();
+    };
+    match listen.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_unspecified() => {
+            let host = server_addr
+                .rsplit_once(':')
+                .map_or(server_addr, |(host, _)| host);
+            format!("{}://{}:{}", scheme, host, addr.port())
+        }
+        _ => gateway.to_string(),
+    }
+}
 
 pub fn pretty_print(mut files: Vec<File>) {
     use std::io::{self, Write};
@@ -37,9 +93,108 @@ pub fn pretty_print(mut files: Vec<File>) {
     tw.flush().unwrap();
 }
 
+pub fn pretty_print_servers(servers: &[Server]) {
+    use std::io::{self, Write};
+
+    let mut tw = TabWriter::new(io::stdout()).padding(1).minwidth(16);
+    let is_tty = atty::is(atty::Stream::Stdout);
+
+    if is_tty {
+        writeln!(tw, "\x1b[1mName\tAddress\tDefault\x1b[0m").unwrap();
+    } else {
+        writeln!(tw, "Name\tAddress\tDefault").unwrap();
+    }
+
+    for server in servers {
+        let default = if server.default { "*" } else { "" };
+        writeln!(tw, "{}\t{}\t{}", server.name, server.addr, default).unwrap();
+    }
+
+    tw.flush().unwrap();
+}
+
+pub fn pretty_print_discovered(servers: &[Discovered]) {
+    use std::io::{self, Write};
+
+    let mut tw = TabWriter::new(io::stdout()).padding(1).minwidth(16);
+    let is_tty = atty::is(atty::Stream::Stdout);
+
+    if is_tty {
+        writeln!(tw, "\x1b[1mName\tAddress\tVersion\tTLS\x1b[0m").unwrap();
+    } else {
+        writeln!(tw, "Name\tAddress\tVersion\tTLS").unwrap();
+    }
+
+    for discovered in servers {
+        let tls = if discovered.server.tls { "*" } else { "" };
+        writeln!(
+            tw,
+            "{}\t{}\t{}\t{}",
+            discovered.server.name, discovered.server.addr, discovered.version, tls
+        )
+        .unwrap();
+    }
+
+    tw.flush().unwrap();
+}
+
+/// Prints the storage volume's space, when known, above the usage of each
+/// quota scope, in the columns df(1) uses.
+pub fn pretty_print_usage(space: Option<&Space>, usage: &[Usage]) {
+    use std::io::{self, Write};
+
+    let mut tw = TabWriter::new(io::stdout()).padding(1).minwidth(16);
+    let is_tty = atty::is(atty::Stream::Stdout);
+
+    if is_tty {
+        writeln!(tw, "\x1b[1mScope\tSize\tUsed\tAvail\tUse%\x1b[0m").unwrap();
+    } else {
+        writeln!(tw, "Scope\tSize\tUsed\tAvail\tUse%").unwrap();
+    }
+
+    let rows = space
+        .map(|space| {
+            let used = space.total.saturating_sub(space.available);
+            ("volume".to_string(), used, Some(space.total))
+        })
+        .into_iter()
+        .chain(
+            usage
+                .iter()
+                .map(|entry| (entry.scope.clone(), entry.used, entry.limit)),
+        );
+
+    for (scope, used, size) in rows {
+        let (size, available, percent) = match size {
+            Some(0) => ("0 B".to_string(), "0 B".to_string(), "-".to_string()),
+            Some(size) => (
+                format_size(size, BINARY),
+                format_size(size.saturating_sub(used), BINARY),
+                format!("{:.0}%", used as f64 / size as f64 * 100.0),
+            ),
+            None => ("-".to_string(), "-".to_string(), "-".to_string()),
+        };
+        writeln!(
+            tw,
+            "{}\t{}\t{}\t{}\t{}",
+            scope,
+            size,
+            format_size(used, BINARY),
+            available,
+            percent
+        )
+        .unwrap();
+    }
+
+    tw.flush().unwrap();
+}
+
+/// Largest chunk a file is split into for transfer.
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
 pub fn optimal_chunk_size(file_size: u64) -> usize {
     let min = 16 * 1024;
-    let max = 1024 * 1024;
+    let max = MAX_CHUNK_SIZE;
     let scaled = ((file_size as f64).log2() * 1024.0).clamp(min as f64, max as f64);
     scaled as usize
 }
@@ -80,3 +235,305 @@ pub fn safe_join(base: &Utf8Path, relative: &str) -> Option<Utf8PathBuf> {
         None
     }
 }
+
+/// Whether `path` uses glob syntax, so it names a set of files.
+pub fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '[', '{'])
+}
+
+/// The leading components of `pattern` without glob syntax, which every
+/// match lies below.
+pub fn glob_base(pattern: &str) -> Utf8PathBuf {
+    Utf8Path::new(pattern)
+        .components()
+        .take_while(|component| !is_glob(component.as_str()))
+        .collect()
+}
+
+/// Finds the paths below `root` matching the glob `pattern`, relative to
+/// `root`. `*` stays within one directory while `**` crosses any number, and
+/// only the tree below the pattern's base is walked, no deeper than the
+/// pattern reaches. With `directories`,
+/// matching directories are returned instead of their contents. Entries whose
+/// name `excluded` returns true for are skipped along with their contents.
+pub fn glob(
+    root: &Utf8Path,
+    pattern: &str,
+    directories: bool,
+    excluded: impl Fn(&str) -> bool,
+) -> Result<Vec<File>> {
+    let matcher = GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| anyhow!("Invalid pattern `{}`: {}", pattern, e.kind()))?
+        .compile_matcher();
+
+    let base = glob_base(pattern);
+    let walk_root = match root.join(&base) {
+        path if path.as_str().is_empty() => Utf8PathBuf::from("."),
+        path => path,
+    };
+
+    // Without `**`, no match lies deeper than the pattern's own components.
+    let max_depth = match pattern.contains("**") {
+        true => usize::MAX,
+        false => Utf8Path::new(pattern).components().count() - base.components().count(),
+    };
+
+    let mut files = Vec::new();
+    let mut entries = WalkDir::new(&walk_root)
+        .max_depth(max_depth)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !excluded(&entry.file_name().to_string_lossy())
+        });
+    while let Some(entry) = entries.next() {
+        let Ok(entry) = entry else { continue };
+        let Some(relative) = entry
+            .path()
+            .strip_prefix(&walk_root)
+            .ok()
+            .and_then(|relative| relative.to_str())
+        else {
+            continue;
+        };
+        let path = match relative {
+            "" => base.clone(),
+            relative => base.join(relative),
+        };
+
+        let is_dir = entry.file_type().is_dir();
+        if path.as_str().is_empty() || (is_dir && !directories) || !matcher.is_match(path.as_str())
+        {
+            continue;
+        }
+        if is_dir {
+            entries.skip_current_dir();
+        }
+
+        let size = match entry.metadata() {
+            Ok(metadata) if !is_dir => metadata.len(),
+            _ => 0,
+        };
+        files.push(File {
+            path: path.into_string(),
+            size,
+        });
+    }
+    Ok(files)
+}
+
+/// Parses a byte count such as `512`, `64K`, `10M`, `1.5G` or `2TiB`.
+/// Suffixes are binary multiples.
+pub fn parse_size(input: &str) -> Result<u64> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size `{}`", input))?;
+
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(anyhow!("Invalid size unit in `{}`", input)),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// A byte count that deserializes from either an integer or a string like `10G`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = ByteSize;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a byte count such as 1048576 or \"1M\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<ByteSize, E> {
+                Ok(ByteSize(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<ByteSize, E> {
+                u64::try_from(v)
+                    .map(ByteSize)
+                    .map_err(|_| E::custom("size must not be negative"))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<ByteSize, E> {
+                parse_size(v).map(ByteSize).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&format_size(self.0, BINARY))
+    }
+}
+
+/// Parses a duration such as `500ms`, `30s`, `5m`, `2h`, `7d` or `1w`.
+/// A bare number is taken as seconds.
+pub fn parse_duration(input: &str) -> Result<Duration> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid duration `{}`", input))?;
+
+    let seconds = match unit.trim().to_ascii_lowercase().as_str() {
+        "ms" => 0.001,
+        "" | "s" | "sec" | "secs" => 1.0,
+        "m" | "min" | "mins" => 60.0,
+        "h" | "hr" | "hrs" => 3600.0,
+        "d" | "day" | "days" => 86400.0,
+        "w" | "week" | "weeks" => 604800.0,
+        _ => return Err(anyhow!("Invalid duration unit in `{}`", input)),
+    };
+
+    Duration::try_from_secs_f64(number * seconds)
+        .map_err(|_| anyhow!("Duration `{}` is too long", input))
+}
+
+/// A duration that deserializes from either seconds or a string like `30s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HumanDuration(pub Duration);
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = HumanDuration;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a duration such as 30 or \"30s\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<HumanDuration, E> {
+                Ok(HumanDuration(Duration::from_secs(v)))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<HumanDuration, E> {
+                u64::try_from(v)
+                    .map(|v| HumanDuration(Duration::from_secs(v)))
+                    .map_err(|_| E::custom("duration must not be negative"))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<HumanDuration, E> {
+                parse_duration(v).map(HumanDuration).map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_gateway_urls() {
+        let server = "storage.example.com:4899";
+        assert_eq!(
+            gateway_url("http://0.0.0.0:8080", server),
+            "http://storage.example.com:8080"
+        );
+        assert_eq!(
+            gateway_url("https://[::]:8443", "[::1]:4899"),
+            "https://[::1]:8443"
+        );
+        assert_eq!(
+            gateway_url("http://10.0.0.5:8080", server),
+            "http://10.0.0.5:8080"
+        );
+        assert_eq!(
+            gateway_url("http://files.example.com:80", server),
+            "http://files.example.com:80"
+        );
+    }
+
+    fn glob_paths(root: &Utf8Path, pattern: &str, directories: bool) -> Vec<String> {
+        glob(root, pattern, directories, |name| name == ".lud")
+            .unwrap()
+            .into_iter()
+            .map(|file| file.path)
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_base_of_patterns() {
+        assert_eq!(glob_base("*.gz"), "");
+        assert_eq!(glob_base("logs/2026-*/app.log"), "logs");
+        assert_eq!(glob_base("build/out/*.tar.gz"), "build/out");
+        assert_eq!(glob_base("docs/readme.md"), "docs/readme.md");
+    }
+
+    #[test]
+    fn globs_files_below_a_root() {
+        let root = std::env::temp_dir().join(format!("lud-glob-{}", std::process::id()));
+        let root = Utf8PathBuf::from_path_buf(root).unwrap();
+        for dir in ["sub/deep", ".lud", "v[1]/empty"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in ["a.gz", "b.txt", "sub/b.gz", "sub/deep/c.gz", ".lud/d.gz"] {
+            std::fs::write(root.join(file), file).unwrap();
+        }
+
+        assert_eq!(glob_paths(&root, "*.gz", false), ["a.gz"]);
+        assert_eq!(glob_paths(&root, "sub/*.gz", false), ["sub/b.gz"]);
+        assert_eq!(glob_paths(&root, "*/*.gz", false), ["sub/b.gz"]);
+        assert_eq!(
+            glob_paths(&root, "{a,sub/deep/c}.gz", false),
+            ["a.gz", "sub/deep/c.gz"]
+        );
+        assert_eq!(
+            glob_paths(&root, "**/*.gz", false),
+            ["a.gz", "sub/b.gz", "sub/deep/c.gz"]
+        );
+        assert_eq!(glob_paths(&root, "s*", true), ["sub"]);
+        // Escaped names list one level, empty directories included.
+        let pattern = format!("{}/*", globset::escape("v[1]"));
+        assert_eq!(glob_paths(&root, &pattern, true), ["v[1]/empty"]);
+        assert_eq!(glob_paths(&root, "s*", false), Vec::<String>::new());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("1.5h").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse_duration("2 fortnights").is_err());
+    }
+
+    #[test]
+    fn rejects_durations_too_long_to_represent() {
+        assert!(parse_duration("99999999999999999999999w").is_err());
+        // Parses to infinity.
+        assert!(parse_duration(&"9".repeat(400)).is_err());
+    }
+}